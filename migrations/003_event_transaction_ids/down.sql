@@ -0,0 +1,2 @@
+ALTER TABLE events DROP COLUMN access_token_id;
+ALTER TABLE events DROP COLUMN transaction_id;
@@ -0,0 +1,2 @@
+ALTER TABLE events ADD COLUMN transaction_id TEXT;
+ALTER TABLE events ADD COLUMN access_token_id BIGINT;
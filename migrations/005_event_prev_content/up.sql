@@ -0,0 +1 @@
+ALTER TABLE events ADD COLUMN prev_content TEXT;
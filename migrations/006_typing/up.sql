@@ -0,0 +1,7 @@
+CREATE TABLE typing (
+    room_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (room_id, user_id)
+);
@@ -1,35 +1,53 @@
 //! Endpoints for accounts.
 use bodyparser;
 use diesel::prelude::*;
+use diesel::result::Error as DieselError;
 use iron::status::Status;
 use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
+use ruma_identifiers::UserId;
+use serde_json::{from_str, Value};
 
+use crate::authentication::{AuthType, Flow, InteractiveAuth};
+use crate::config::Config;
 use crate::crypto::hash_password;
 use crate::db::DB;
 use crate::error::ApiError;
 use crate::middleware::{
-    AccessTokenAuth, DataTypeParam, JsonRequest, MiddlewareChain, RoomIdParam, UserIdParam,
+    AccessTokenAuth, DataTypeParam, JsonRequest, MiddlewareChain, RoomIdParam, UIAuth, UserIdParam,
 };
 use crate::models::access_token::AccessToken;
 use crate::models::account_data::{
     AccountData, NewAccountData, NewRoomAccountData, RoomAccountData,
 };
+use crate::models::event::Event;
+use crate::models::profile::Profile;
 use crate::models::room_membership::RoomMembership;
 use crate::models::user::User;
-use crate::modifier::EmptyResponse;
+use crate::modifier::{empty_response, SerializableResponse};
 
 /// The `/account/password` endpoint.
 #[derive(Clone, Copy, Debug)]
 pub struct AccountPassword;
 
 /// The body of the request for this API.
+///
+/// The `auth` parameter is handled by `UIAuth`.
 #[derive(Clone, Debug, Deserialize)]
 struct AccountPasswordRequest {
     /// The new password for the account.
     pub new_password: String,
 }
 
-middleware_chain!(AccountPassword, [AccessTokenAuth]);
+middleware_chain!(
+    AccountPassword,
+    [
+        JsonRequest,
+        AccessTokenAuth,
+        UIAuth::new(InteractiveAuth::new(vec![Flow::new(vec![
+            AuthType::Password
+        ])]))
+    ]
+);
 
 impl Handler for AccountPassword {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
@@ -39,20 +57,30 @@ impl Handler for AccountPassword {
                 Ok(None) | Err(_) => Err(ApiError::not_json(None))?,
             };
 
-        let mut user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let mut user = User::from_request(request)?;
+
+        let access_token = AccessToken::from_request(request)?;
 
-        user.password_hash = hash_password(&account_password_request.new_password)?;
+        let config = Config::from_request(request)?;
+
+        user.password_hash = hash_password(
+            &account_password_request.new_password,
+            config.password_hash_cost,
+        )?;
 
         let connection = DB::from_request(request)?;
 
-        user.save_changes::<User>(&*connection)
-            .map_err(|_| ApiError::unauthorized(None))?;
+        connection
+            .transaction::<(), ApiError, _>(|| {
+                user.save_changes::<User>(&*connection)
+                    .map_err(|_| ApiError::unauthorized(None))?;
+
+                // Other sessions must log in again with the new password.
+                AccessToken::revoke_all_for_user(&connection, &user.id, Some(&access_token))
+            })
+            .map_err(ApiError::from)?;
 
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        Ok(empty_response())
     }
 }
 
@@ -60,25 +88,32 @@ impl Handler for AccountPassword {
 #[derive(Clone, Copy, Debug)]
 pub struct DeactivateAccount;
 
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct DeactivateAccountRequest {
+    /// Whether to also erase the user's profile and the content of the events they sent.
+    #[serde(default)]
+    erase: bool,
+}
+
 middleware_chain!(DeactivateAccount, [AccessTokenAuth]);
 
 impl Handler for DeactivateAccount {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let connection = DB::from_request(request)?;
+        // The request body is optional.
+        let erase = match request.get::<bodyparser::Struct<DeactivateAccountRequest>>() {
+            Ok(Some(deactivate_request)) => deactivate_request.erase,
+            Ok(None) => false,
+            Err(_) => Err(ApiError::bad_json(None))?,
+        };
 
-        {
-            let token = request
-                .extensions
-                .get_mut::<AccessToken>()
-                .expect("AccessTokenAuth should ensure an access token");
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
 
-            token.revoke(&connection)?;
-        }
+        let mut token = AccessToken::from_request(request)?;
+        token.revoke(&connection)?;
 
-        let user = request
-            .extensions
-            .get_mut::<User>()
-            .expect("AccessTokenAuth should ensure a user");
+        let mut user = User::from_request(request)?;
 
         user.deactivate(&connection)?;
 
@@ -86,7 +121,51 @@ impl Handler for DeactivateAccount {
         AccountData::delete_by_uid(&connection, &user.id)?;
         RoomAccountData::delete_by_uid(&connection, &user.id)?;
 
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        if erase {
+            Event::erase_by_sender(&connection, &user.id)?;
+            Profile::erase(
+                &connection,
+                &config.domain,
+                config.presence_enabled,
+                user.id.clone(),
+            )?;
+        }
+
+        Ok(empty_response())
+    }
+}
+
+/// The `/account/whoami` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct Whoami;
+
+/// The response of this API.
+#[derive(Clone, Debug, Serialize)]
+struct WhoamiResponse {
+    /// The user that owns the access token.
+    user_id: UserId,
+    /// The device the access token is linked to, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_id: Option<String>,
+    /// The time the account was created, in milliseconds since the Unix epoch.
+    created_at: i64,
+}
+
+middleware_chain!(Whoami, [AccessTokenAuth]);
+
+impl Handler for Whoami {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = User::from_request(request)?;
+
+        let access_token = AccessToken::from_request(request)?;
+
+        let response = WhoamiResponse {
+            user_id: user.id.clone(),
+            device_id: access_token.device_id.clone(),
+            created_at: user.created_at_millis(),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
     }
 }
 
@@ -101,17 +180,9 @@ middleware_chain!(
 
 impl Handler for PutAccountData {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
-
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
+        let user = User::from_request(request)?;
+
+        let user_id = UserIdParam::from_request(request)?;
 
         if user_id != user.id {
             let error = ApiError::unauthorized(
@@ -121,11 +192,7 @@ impl Handler for PutAccountData {
             return Err(IronError::from(error));
         }
 
-        let data_type = request
-            .extensions
-            .get::<DataTypeParam>()
-            .expect("DataTypeParam should ensure a data type")
-            .clone();
+        let data_type = DataTypeParam::from_request(request)?;
 
         let content = match request.get::<bodyparser::Json>() {
             Ok(Some(content)) => content.to_string().clone(),
@@ -142,7 +209,7 @@ impl Handler for PutAccountData {
 
         AccountData::upsert(&connection, &new_data)?;
 
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        Ok(empty_response())
     }
 }
 
@@ -163,17 +230,9 @@ middleware_chain!(
 
 impl Handler for PutRoomAccountData {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
-
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
+        let user = User::from_request(request)?;
+
+        let user_id = UserIdParam::from_request(request)?;
 
         if user_id != user.id {
             let error = ApiError::unauthorized(
@@ -183,11 +242,7 @@ impl Handler for PutRoomAccountData {
             return Err(IronError::from(error));
         }
 
-        let room_id = request
-            .extensions
-            .get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a RoomId")
-            .clone();
+        let room_id = RoomIdParam::from_request(request)?;
 
         let connection = DB::from_request(request)?;
 
@@ -206,11 +261,7 @@ impl Handler for PutRoomAccountData {
             return Err(IronError::from(error));
         }
 
-        let data_type = request
-            .extensions
-            .get::<DataTypeParam>()
-            .expect("DataTypeParam should ensure a data type")
-            .clone();
+        let data_type = DataTypeParam::from_request(request)?;
 
         let content = match request.get::<bodyparser::Json>() {
             Ok(Some(content)) => content.to_string().clone(),
@@ -226,13 +277,100 @@ impl Handler for PutRoomAccountData {
 
         RoomAccountData::upsert(&connection, &new_data)?;
 
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        Ok(empty_response())
+    }
+}
+
+/// The GET `/user/:user_id/account_data/:type` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetAccountData;
+
+middleware_chain!(
+    GetAccountData,
+    [UserIdParam, DataTypeParam, AccessTokenAuth]
+);
+
+impl Handler for GetAccountData {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = User::from_request(request)?;
+
+        let user_id = UserIdParam::from_request(request)?;
+
+        if user_id != user.id {
+            let error = ApiError::unauthorized(
+                "The given user_id does not correspond to the authenticated user".to_string(),
+            );
+
+            return Err(IronError::from(error));
+        }
+
+        let data_type = DataTypeParam::from_request(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        let account_data =
+            match AccountData::find_by_uid_and_type(&connection, &user.id, &data_type) {
+                Ok(account_data) => account_data,
+                Err(DieselError::NotFound) => Err(ApiError::not_found(
+                    "No account data of this type was found.".to_string(),
+                ))?,
+                Err(err) => Err(ApiError::from(err))?,
+            };
+
+        let content: Value = from_str(&account_data.content).map_err(ApiError::from)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(content))))
+    }
+}
+
+/// The GET `/user/:user_id/rooms/:room_id/account_data/:type` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetRoomAccountData;
+
+middleware_chain!(
+    GetRoomAccountData,
+    [UserIdParam, RoomIdParam, DataTypeParam, AccessTokenAuth]
+);
+
+impl Handler for GetRoomAccountData {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = User::from_request(request)?;
+
+        let user_id = UserIdParam::from_request(request)?;
+
+        if user_id != user.id {
+            let error = ApiError::unauthorized(
+                "The given user_id does not correspond to the authenticated user".to_string(),
+            );
+
+            return Err(IronError::from(error));
+        }
+
+        let room_id = RoomIdParam::from_request(request)?;
+
+        let data_type = DataTypeParam::from_request(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        let account_data = match RoomAccountData::find(&connection, &user.id, &room_id, &data_type)
+        {
+            Ok(account_data) => account_data,
+            Err(DieselError::NotFound) => Err(ApiError::not_found(
+                "No account data of this type was found for this room.".to_string(),
+            ))?,
+            Err(err) => Err(ApiError::from(err))?,
+        };
+
+        let content: Value = from_str(&account_data.content).map_err(ApiError::from)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(content))))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::test::Test;
+    use crate::query::SyncOptions;
+    use crate::test::{assert_recent, Test};
     use iron::status::Status;
 
     #[test]
@@ -240,26 +378,105 @@ mod tests {
         let test = Test::new();
         let user = test.create_user();
 
+        let login = |password: &str| {
+            test.post(
+                "/_matrix/client/r0/login",
+                &format!(
+                    r#"{{"type": "m.login.password", "user": "{}", "password": "{}"}}"#,
+                    user.name, password
+                ),
+            )
+        };
+
+        let response = login("secret");
+        assert_eq!(response.status, Status::Ok);
+        let other_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let body = format!(
+            r#"{{"auth": {{"type": "m.login.password", "user": "{}", "password": "secret"}}, "new_password": "hidden"}}"#,
+            user.id
+        );
         let response = test.post(
             &format!(
                 "/_matrix/client/r0/account/password?access_token={}",
                 user.token
             ),
-            r#"{"new_password": "hidden"}"#,
+            &body,
         );
         test.check_empty_response(response);
 
+        assert_eq!(login("hidden").status, Status::Ok);
+        assert_eq!(login("secret").status, Status::Forbidden);
+
+        // Only the access token used to change the password stays valid.
+        let whoami = |access_token: &str| {
+            test.get(&format!(
+                "/_matrix/client/r0/account/whoami?access_token={}",
+                access_token
+            ))
+        };
+        assert_eq!(whoami(&user.token).status, Status::Ok);
+        assert_eq!(whoami(&other_token).status, Status::Unauthorized);
+    }
+
+    #[test]
+    fn change_password_with_wrong_password() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let body = format!(
+            r#"{{"auth": {{"type": "m.login.password", "user": "{}", "password": "wrong"}}, "new_password": "hidden"}}"#,
+            user.id
+        );
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/account/password?access_token={}",
+                user.token
+            ),
+            &body,
+        );
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+
         let response = test.post(
             "/_matrix/client/r0/login",
-            format!(
-                r#"{{"type": "m.login.password", "user": "{}", "password": "hidden"}}"#,
+            &format!(
+                r#"{{"type": "m.login.password", "user": "{}", "password": "secret"}}"#,
                 user.name
-            )
-            .as_str(),
+            ),
         );
         assert_eq!(response.status, Status::Ok);
     }
 
+    #[test]
+    fn change_password_with_credentials_of_other_user() {
+        let test = Test::new();
+        let user = test.create_user();
+        let other_user = test.create_user();
+
+        let body = format!(
+            r#"{{"auth": {{"type": "m.login.password", "user": "{}", "password": "secret"}}, "new_password": "hidden"}}"#,
+            other_user.id
+        );
+        let response = test.post(
+            &format!(
+                "/_matrix/client/r0/account/password?access_token={}",
+                user.token
+            ),
+            &body,
+        );
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
     #[test]
     fn deactivate_account() {
         let test = Test::new();
@@ -284,7 +501,66 @@ mod tests {
             Status::Forbidden
         );
 
-        assert_eq!(test.post(&deactivate, r#"{}"#).status, Status::Forbidden);
+        assert_eq!(test.post(&deactivate, r#"{}"#).status, Status::Unauthorized);
+    }
+
+    #[test]
+    fn deactivate_account_with_erase() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let mark = test.create_user();
+
+        let displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            mark.id, mark.token
+        );
+        assert_eq!(
+            test.put(&displayname_path, r#"{"displayname": "Mark"}"#)
+                .status,
+            Status::Ok
+        );
+        assert_eq!(test.join_room(&mark.token, &room_id).status, Status::Ok);
+        assert_eq!(
+            test.send_message(&mark.token, &room_id, "Secret", 1).status,
+            Status::Ok
+        );
+
+        let deactivate = format!(
+            "/_matrix/client/r0/account/deactivate?access_token={}",
+            mark.token
+        );
+        test.check_empty_response(test.post(&deactivate, r#"{"erase": true}"#));
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/profile/{}/displayname",
+            mark.id
+        ));
+        assert_eq!(response.status, Status::NotFound);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+        let events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+
+        let message = events
+            .iter()
+            .find(|event| event.get("type").unwrap().as_str().unwrap() == "m.room.message")
+            .unwrap();
+        assert!(message.get("content").unwrap().get("body").is_none());
+        assert!(events
+            .iter()
+            .filter(|event| event.get("sender").unwrap().as_str().unwrap() == mark.id)
+            .all(|event| event.pointer("/content/displayname").is_none()));
     }
 
     #[test]
@@ -373,6 +649,100 @@ mod tests {
         test.check_empty_response(response);
     }
 
+    #[test]
+    fn get_account_data() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let data_type = "org.matrix.personal.config";
+        let account_data_path = format!(
+            "/_matrix/client/r0/user/{}/account_data/{}?access_token={}",
+            user.id, data_type, user.token
+        );
+
+        assert_eq!(test.get(&account_data_path).status, Status::NotFound);
+
+        let response = test.put(&account_data_path, r#"{"email": "user@email.com"}"#);
+        test.check_empty_response(response);
+
+        let response = test.get(&account_data_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("email").unwrap().as_str().unwrap(),
+            "user@email.com"
+        );
+
+        let response = test.put(&account_data_path, r#"{"email": "user@email.org"}"#);
+        test.check_empty_response(response);
+
+        let response = test.get(&account_data_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("email").unwrap().as_str().unwrap(),
+            "user@email.org"
+        );
+    }
+
+    #[test]
+    fn get_account_data_of_other_user() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let data_type = "org.matrix.personal.config";
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/user/{}/account_data/{}?access_token={}",
+                bob.id, data_type, bob.token
+            ),
+            r#"{"email": "bob@email.com"}"#,
+        );
+        test.check_empty_response(response);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/user/{}/account_data/{}?access_token={}",
+            bob.id, data_type, alice.token
+        ));
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn get_room_account_data() {
+        let test = Test::new();
+        let user = test.create_user();
+        let room_id = test.create_room(&user.token);
+        let other_room_id = test.create_room(&user.token);
+
+        let data_type = "org.matrix.room.config";
+        let path = format!(
+            "/_matrix/client/r0/user/{}/rooms/{}/account_data/{}?access_token={}",
+            user.id, room_id, data_type, user.token
+        );
+
+        let response = test.put(&path, r#"{"ui_color": "yellow"}"#);
+        test.check_empty_response(response);
+
+        let response = test.get(&path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("ui_color").unwrap().as_str().unwrap(),
+            "yellow"
+        );
+
+        // Room account data is scoped to its room and does not leak into the global one.
+        let other_room_path = format!(
+            "/_matrix/client/r0/user/{}/rooms/{}/account_data/{}?access_token={}",
+            user.id, other_room_id, data_type, user.token
+        );
+        assert_eq!(test.get(&other_room_path).status, Status::NotFound);
+
+        let global_path = format!(
+            "/_matrix/client/r0/user/{}/account_data/{}?access_token={}",
+            user.id, data_type, user.token
+        );
+        assert_eq!(test.get(&global_path).status, Status::NotFound);
+    }
+
     #[test]
     fn update_room_account_data_with_invalid_user() {
         let test = Test::new();
@@ -457,4 +827,76 @@ mod tests {
             "No membership entry was found."
         );
     }
+
+    #[test]
+    fn whoami() {
+        let test = Test::new();
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        let access_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/account/whoami?access_token={}",
+            access_token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("user_id").unwrap().as_str().unwrap(),
+            "@carl:ruma.test"
+        );
+        assert!(response.json().get("device_id").is_none());
+
+        assert_recent(response.json().get("created_at").unwrap().as_i64().unwrap());
+    }
+
+    #[test]
+    fn whoami_with_device() {
+        let test = Test::new();
+        test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl", "password": "secret", "device_id": "PHONE"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("device_id").unwrap().as_str().unwrap(),
+            "PHONE"
+        );
+        let access_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/account/whoami?access_token={}",
+            access_token
+        ));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("device_id").unwrap().as_str().unwrap(),
+            "PHONE"
+        );
+    }
+
+    #[test]
+    fn whoami_with_unknown_token() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/account/whoami?access_token=bogus");
+
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNKNOWN_TOKEN"
+        );
+    }
 }
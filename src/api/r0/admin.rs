@@ -0,0 +1,384 @@
+//! Endpoints for server administrators.
+
+use std::error::Error;
+
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+use ruma_identifiers::{RoomId, UserId};
+use url::Url;
+
+use crate::api::r0::pagination::parse_limit;
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{AccessTokenAuth, AdminOnly, MiddlewareChain, UserIdParam};
+use crate::models::room_membership::RoomMembership;
+use crate::models::user::User;
+use crate::modifier::SerializableResponse;
+
+/// The number of rooms returned per page if no `limit` is given.
+const USER_ROOMS_LIMIT: u64 = 100;
+
+/// The maximum number of rooms returned per page.
+const USER_ROOMS_MAX_LIMIT: u64 = 1000;
+
+/// The `/admin/users/:user_id/rooms` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct GetUserRooms;
+
+/// A room the user has a membership in.
+#[derive(Debug, Serialize)]
+struct UserRoom {
+    /// The room's ID.
+    room_id: RoomId,
+    /// The user's membership state in the room, e.g. "join".
+    membership: String,
+}
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct GetUserRoomsResponse {
+    /// The time the user's account was created, in milliseconds since the Unix epoch.
+    created_at: i64,
+    /// The rooms the user has a membership in, ordered by room ID.
+    rooms: Vec<UserRoom>,
+    /// The number of rooms the user has joined, across all pages.
+    joined_rooms: i64,
+    /// A token to pass as `from` to get the next page of rooms, if there are more.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
+}
+
+middleware_chain!(GetUserRooms, [UserIdParam, AccessTokenAuth, AdminOnly]);
+
+impl Handler for GetUserRooms {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user_id = UserIdParam::from_request(request)?;
+
+        let url: Url = request.url.clone().into();
+
+        let mut from = 0;
+        let mut limit = None;
+        for (key, value) in url.query_pairs().into_owned() {
+            match key.as_ref() {
+                "from" => {
+                    from = value
+                        .parse::<usize>()
+                        .map_err(|err| ApiError::invalid_param("from", err.description()))?;
+                }
+                "limit" => {
+                    limit = Some(value);
+                }
+                _ => (),
+            }
+        }
+
+        let limit = parse_limit(
+            limit.as_ref().map(String::as_str),
+            USER_ROOMS_LIMIT,
+            USER_ROOMS_MAX_LIMIT,
+        )? as usize;
+
+        let connection = DB::from_request(request)?;
+
+        let user = match User::find_registered_user(&connection, &user_id)? {
+            Some(user) => user,
+            None => Err(ApiError::not_found(format!(
+                "No user found for {}",
+                user_id
+            )))?,
+        };
+
+        let joined_rooms = RoomMembership::count_by_uid_and_state(&connection, &user_id, "join")?;
+        let mut memberships = RoomMembership::find_by_uid(&connection, user_id)?;
+        memberships.sort_by_key(|membership| membership.room_id.to_string());
+
+        let next_batch = if memberships.len() > from + limit {
+            Some((from + limit).to_string())
+        } else {
+            None
+        };
+
+        let rooms = memberships
+            .into_iter()
+            .skip(from)
+            .take(limit)
+            .map(|membership| UserRoom {
+                room_id: membership.room_id,
+                membership: membership.membership,
+            })
+            .collect();
+
+        let response = GetUserRoomsResponse {
+            created_at: user.created_at_millis(),
+            rooms,
+            joined_rooms,
+            next_batch,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The `/admin/whois/:user_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct WhoIs;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct WhoIsResponse {
+    /// The user's ID.
+    user_id: UserId,
+    /// The time the account was created, in milliseconds since the Unix epoch.
+    created_at: i64,
+    /// The number of rooms the user has joined.
+    joined_rooms: i64,
+}
+
+middleware_chain!(WhoIs, [UserIdParam, AccessTokenAuth, AdminOnly]);
+
+impl Handler for WhoIs {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user_id = UserIdParam::from_request(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        let user = match User::find_registered_user(&connection, &user_id)? {
+            Some(user) => user,
+            None => Err(ApiError::not_found(format!(
+                "No user found for {}",
+                user_id
+            )))?,
+        };
+
+        let joined_rooms = RoomMembership::count_by_uid_and_state(&connection, &user.id, "join")?;
+
+        let response = WhoIsResponse {
+            created_at: user.created_at_millis(),
+            joined_rooms,
+            user_id: user.id,
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use iron::status::Status;
+    use ruma_identifiers::UserId;
+
+    use crate::test::{assert_recent, Test};
+
+    /// Set up a server with `@admin:ruma.test` as administrator and return the admin's token.
+    fn setup_admin() -> (Test, String) {
+        let test = Test::with_config(|config| {
+            config.admins = vec![UserId::try_from("@admin:ruma.test").unwrap()]
+        });
+
+        let response = test.register_user(r#"{"username": "admin", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+        let admin_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        (test, admin_token)
+    }
+
+    #[test]
+    fn list_user_rooms() {
+        let (test, admin_token) = setup_admin();
+
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let joined_room_id = test.create_room(&alice.token);
+        let room_options = format!(r#"{{"invite": ["{}"]}}"#, alice.id);
+        let invited_room_id = test.create_room_with_params(&bob.token, &room_options);
+
+        let rooms_path = format!(
+            "/_matrix/client/r0/admin/users/{}/rooms?access_token={}",
+            alice.id, admin_token
+        );
+
+        let response = test.get(&rooms_path);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("next_batch").is_none());
+
+        assert_recent(response.json().get("created_at").unwrap().as_i64().unwrap());
+
+        let rooms = response.json().get("rooms").unwrap().as_array().unwrap();
+        assert_eq!(rooms.len(), 2);
+
+        let membership_in = |room_id: &str| {
+            rooms
+                .iter()
+                .find(|room| room.get("room_id").unwrap().as_str().unwrap() == room_id)
+                .unwrap()
+                .get("membership")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(membership_in(&joined_room_id), "join");
+        assert_eq!(membership_in(&invited_room_id), "invite");
+
+        let response = test.get(&format!("{}&limit=1", rooms_path));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .get("rooms")
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+        let next_batch = response
+            .json()
+            .get("next_batch")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let response = test.get(&format!("{}&limit=1&from={}", rooms_path, next_batch));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .get("rooms")
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(response.json().get("next_batch").is_none());
+    }
+
+    #[test]
+    fn list_user_rooms_forbidden_for_non_admins() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let rooms_path = format!(
+            "/_matrix/client/r0/admin/users/{}/rooms?access_token={}",
+            alice.id, bob.token
+        );
+
+        let response = test.get(&rooms_path);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+    }
+
+    #[test]
+    fn list_rooms_of_unknown_user() {
+        let (test, admin_token) = setup_admin();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/users/@nobody:ruma.test/rooms?access_token={}",
+            admin_token
+        ));
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_FOUND"
+        );
+    }
+
+    #[test]
+    fn whois_created_at() {
+        let (test, admin_token) = setup_admin();
+        let alice = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/whois/{}?access_token={}",
+            alice.id, admin_token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("user_id").unwrap().as_str().unwrap(),
+            alice.id
+        );
+
+        assert_recent(response.json().get("created_at").unwrap().as_i64().unwrap());
+    }
+
+    #[test]
+    fn whois_forbidden_for_non_admins() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/whois/{}?access_token={}",
+            alice.id, alice.token
+        ));
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn joined_rooms_count() {
+        let (test, admin_token) = setup_admin();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let first_room_id = test.create_public_room(&bob.token);
+        let second_room_id = test.create_public_room(&bob.token);
+        test.create_room(&alice.token);
+
+        assert_eq!(
+            test.join_room(&alice.token, &first_room_id).status,
+            Status::Ok
+        );
+        assert_eq!(
+            test.join_room(&alice.token, &second_room_id).status,
+            Status::Ok
+        );
+        assert_eq!(
+            test.leave_room(&alice.token, &second_room_id).status,
+            Status::Ok
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/whois/{}?access_token={}",
+            alice.id, admin_token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .get("joined_rooms")
+                .unwrap()
+                .as_i64()
+                .unwrap(),
+            2
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/admin/users/{}/rooms?limit=1&access_token={}",
+            alice.id, admin_token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .get("joined_rooms")
+                .unwrap()
+                .as_i64()
+                .unwrap(),
+            2
+        );
+    }
+}
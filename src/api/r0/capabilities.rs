@@ -0,0 +1,113 @@
+//! Endpoints for information about the capabilities of the homeserver.
+
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Request, Response};
+
+use crate::config::Config;
+use crate::middleware::{AccessTokenAuth, MiddlewareChain};
+use crate::modifier::SerializableResponse;
+
+/// The `/capabilities` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct Capabilities;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct CapabilitiesResponse {
+    /// The capabilities the homeserver supports.
+    capabilities: CapabilitiesContent,
+}
+
+/// The capabilities of the homeserver.
+#[derive(Debug, Serialize)]
+struct CapabilitiesContent {
+    /// Whether the presence of users is tracked.
+    #[serde(rename = "io.ruma.presence")]
+    presence: BooleanCapability,
+    /// Whether users can change their own avatar URL.
+    #[serde(rename = "m.set_avatar_url")]
+    set_avatar_url: BooleanCapability,
+    /// Whether users can change their own display name.
+    #[serde(rename = "m.set_displayname")]
+    set_displayname: BooleanCapability,
+}
+
+/// A capability that can only be enabled or disabled.
+#[derive(Debug, Serialize)]
+struct BooleanCapability {
+    /// Whether the capability is enabled.
+    enabled: bool,
+}
+
+middleware_chain!(Capabilities, [AccessTokenAuth]);
+
+impl Handler for Capabilities {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+
+        let response = CapabilitiesResponse {
+            capabilities: CapabilitiesContent {
+                presence: BooleanCapability {
+                    enabled: config.presence_enabled,
+                },
+                set_avatar_url: BooleanCapability {
+                    enabled: config.allow_avatar_change,
+                },
+                set_displayname: BooleanCapability {
+                    enabled: config.allow_displayname_change,
+                },
+            },
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test::Test;
+    use iron::status::Status;
+
+    #[test]
+    fn capabilities_reflect_config() {
+        let test = Test::with_config(|config| config.allow_displayname_change = false);
+        let carl = test.create_user();
+
+        let capabilities_path = format!(
+            "/_matrix/client/r0/capabilities?access_token={}",
+            carl.token
+        );
+        let response = test.get(&capabilities_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        let set_displayname = json
+            .pointer("/capabilities/m.set_displayname/enabled")
+            .unwrap();
+        let set_avatar_url = json
+            .pointer("/capabilities/m.set_avatar_url/enabled")
+            .unwrap();
+
+        assert!(!set_displayname.as_bool().unwrap());
+        assert!(set_avatar_url.as_bool().unwrap());
+    }
+
+    #[test]
+    fn capabilities_reflect_disabled_presence() {
+        let test = Test::with_config(|config| config.presence_enabled = false);
+        let carl = test.create_user();
+
+        let capabilities_path = format!(
+            "/_matrix/client/r0/capabilities?access_token={}",
+            carl.token
+        );
+        let response = test.get(&capabilities_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let presence = response
+            .json()
+            .pointer("/capabilities/io.ruma.presence/enabled")
+            .unwrap();
+        assert!(!presence.as_bool().unwrap());
+    }
+}
@@ -11,7 +11,7 @@ use crate::error::ApiError;
 use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomAliasIdParam};
 use crate::models::room_alias::{NewRoomAlias, RoomAlias};
 use crate::models::user::User;
-use crate::modifier::{EmptyResponse, SerializableResponse};
+use crate::modifier::{empty_response, SerializableResponse};
 
 /// The GET `/directory/room/:room_alias` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -30,11 +30,7 @@ middleware_chain!(GetRoomAlias, [RoomAliasIdParam]);
 
 impl Handler for GetRoomAlias {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let room_alias_id = request
-            .extensions
-            .get::<RoomAliasIdParam>()
-            .expect("RoomAliasIdParam should ensure a RoomAliasId")
-            .clone();
+        let room_alias_id = RoomAliasIdParam::from_request(request)?;
 
         let connection = DB::from_request(request)?;
 
@@ -57,24 +53,16 @@ middleware_chain!(DeleteRoomAlias, [RoomAliasIdParam, AccessTokenAuth]);
 
 impl Handler for DeleteRoomAlias {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let room_alias_id = request
-            .extensions
-            .get::<RoomAliasIdParam>()
-            .expect("RoomAliasIdParam should ensure a RoomAliasId")
-            .clone();
-
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let room_alias_id = RoomAliasIdParam::from_request(request)?;
+
+        let user = User::from_request(request)?;
 
         let connection = DB::from_request(request)?;
 
         let affected_rows = RoomAlias::delete(&connection, &room_alias_id, &user.id)?;
 
         if affected_rows > 0 {
-            Ok(Response::with(EmptyResponse(Status::Ok)))
+            Ok(empty_response())
         } else {
             Err(ApiError::not_found(
                 "Provided room alias did not exist or you do not have access to delete it."
@@ -104,22 +92,14 @@ impl Handler for PutRoomAlias {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
         let config = Config::from_request(request)?;
 
-        let room_alias_id = request
-            .extensions
-            .get::<RoomAliasIdParam>()
-            .expect("RoomAliasIdParam should ensure a RoomAliasId")
-            .clone();
+        let room_alias_id = RoomAliasIdParam::from_request(request)?;
 
         let room_id = match request.get::<bodyparser::Struct<PutRoomAliasRequest>>() {
             Ok(Some(req)) => req.room_id,
             Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
         };
 
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = User::from_request(request)?;
 
         let connection = DB::from_request(request)?;
 
@@ -132,7 +112,7 @@ impl Handler for PutRoomAlias {
 
         RoomAlias::create(&connection, &config.domain.to_string(), &new_room_alias)?;
 
-        Ok(Response::with(Status::Ok))
+        Ok(empty_response())
     }
 }
 
@@ -159,6 +139,32 @@ mod tests {
         assert!(response.json().get("servers").unwrap().is_array());
     }
 
+    #[test]
+    fn get_room_alias_by_full_alias() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let room_id =
+            test.create_room_with_params(&user.token, r#"{"room_alias_name": "my_room"}"#);
+
+        let response = test.get("/_matrix/client/r0/directory/room/%23my_room%3Aruma.test");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("room_id").unwrap().as_str().unwrap(),
+            room_id
+        );
+    }
+
+    #[test]
+    fn get_invalid_room_alias() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/r0/directory/room/%23my_room");
+
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
     #[test]
     fn get_unknown_room_alias() {
         let test = Test::new();
@@ -232,7 +238,7 @@ mod tests {
         let put_room_alias_body = format!(r#"{{"room_id": "{}"}}"#, room_id);
         let response = test.put(&put_room_alias_path, &put_room_alias_body);
 
-        assert_eq!(response.status, Status::Ok);
+        test.check_empty_response(response);
 
         let response = test.get("/_matrix/client/r0/directory/room/my_room");
 
@@ -1,6 +1,6 @@
 //! Endpoints for creating events.
 
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 
 use bodyparser;
 use diesel::pg::PgConnection;
@@ -19,10 +19,11 @@ use ruma_events::room::join_rules::JoinRulesEvent;
 use ruma_events::room::message::MessageEvent;
 use ruma_events::room::name::NameEvent;
 use ruma_events::room::power_levels::PowerLevelsEvent;
+use ruma_events::room::redaction::{RedactionEvent, RedactionEventContent};
 use ruma_events::room::third_party_invite::ThirdPartyInviteEvent;
 use ruma_events::room::topic::TopicEvent;
 use ruma_events::{CustomRoomEvent, CustomStateEvent, EventType};
-use ruma_identifiers::{EventId, RoomId};
+use ruma_identifiers::{EventId, RoomAliasId, RoomId};
 use serde::Deserialize;
 use serde_json::{from_str, from_value, to_string, Value};
 
@@ -30,11 +31,13 @@ use crate::config::Config;
 use crate::db::DB;
 use crate::error::{ApiError, MapApiError};
 use crate::middleware::{
-    AccessTokenAuth, EventTypeParam, JsonRequest, MiddlewareChain, RoomIdParam, TransactionIdParam,
+    AccessTokenAuth, EventIdParam, EventTypeParam, JsonRequest, MiddlewareChain, RoomIdParam,
+    TransactionIdParam,
 };
 use crate::models::access_token::AccessToken;
-use crate::models::event::NewEvent;
+use crate::models::event::{Event, NewEvent};
 use crate::models::room::Room;
+use crate::models::room_alias::RoomAlias;
 use crate::models::room_membership::RoomMembership;
 use crate::models::transaction::Transaction;
 use crate::models::user::User;
@@ -114,28 +117,13 @@ middleware_chain!(
 
 impl Handler for SendMessageEvent {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let room_id = request
-            .extensions
-            .get::<RoomIdParam>()
-            .expect("Should have been required by RoomIdParam.")
-            .clone();
+        let room_id = RoomIdParam::from_request(request)?;
 
-        let event_type = request
-            .extensions
-            .get::<EventTypeParam>()
-            .expect("EventTypeParam should ensure an EventType")
-            .clone();
+        let event_type = EventTypeParam::from_request(request)?;
 
-        request
-            .extensions
-            .get::<TransactionIdParam>()
-            .expect("TransactionIdParam should ensure a TransactionId");
+        let transaction_id = TransactionIdParam::from_request(request)?;
 
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = User::from_request(request)?;
 
         let event_content = request
             .get::<bodyparser::Json>()
@@ -211,11 +199,7 @@ impl Handler for SendMessageEvent {
         let connection = DB::from_request(request)?;
 
         let path = request.url.path().join("/").to_string();
-        let token = (*request
-            .extensions
-            .get::<AccessToken>()
-            .expect("AccessTokenAuth should ensure an access token"))
-        .clone();
+        let token = AccessToken::from_request(request)?;
 
         if let Some(transaction) = Transaction::find(&connection, &path, &token.value)? {
             let response: EventResponse =
@@ -223,13 +207,19 @@ impl Handler for SendMessageEvent {
             return Ok(Response::with((status::Ok, SerializableResponse(response))));
         }
 
+        let room_event = NewEvent {
+            transaction_id: Some(transaction_id),
+            access_token_id: Some(token.id),
+            ..room_event
+        };
+
         let response = EventResponse {
             event_id: event_id.opaque_id().to_string(),
         };
 
         connection
             .transaction(|| {
-                verify_permissions(&connection, &room_id, &user, &event_type)?;
+                verify_permissions(&connection, &room_id, &user, &event_type, false)?;
 
                 diesel::insert_into(events::table)
                     .values(&room_event)
@@ -269,25 +259,13 @@ impl Handler for StateMessageEvent {
             .expect("Params object is missing")
             .clone();
 
-        let room_id = request
-            .extensions
-            .get::<RoomIdParam>()
-            .expect("Should have been required by RoomIdParam.")
-            .clone();
+        let room_id = RoomIdParam::from_request(request)?;
 
-        let event_type = request
-            .extensions
-            .get::<EventTypeParam>()
-            .expect("EventTypeParam should ensure an EventType")
-            .clone();
+        let event_type = EventTypeParam::from_request(request)?;
 
         let state_key = params.find("state_key").unwrap_or("");
 
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = User::from_request(request)?;
 
         let event_content = request
             .get::<bodyparser::Json>()
@@ -298,6 +276,8 @@ impl Handler for StateMessageEvent {
             ApiError::unknown("Failed to generated event ID for the new event.".to_string())
         })?;
 
+        let connection = DB::from_request(request)?;
+
         let state_event: NewEvent = match event_type {
             EventType::RoomAvatar => {
                 ensure_empty_state_key(state_key, &event_type)?;
@@ -314,6 +294,7 @@ impl Handler for StateMessageEvent {
             }
             EventType::RoomCanonicalAlias => {
                 ensure_empty_state_key(state_key, &event_type)?;
+                ensure_alias_points_to_room(&connection, &event_content, &room_id)?;
 
                 state_event!(
                     CanonicalAliasEvent,
@@ -401,6 +382,7 @@ impl Handler for StateMessageEvent {
             ),
             EventType::RoomTopic => {
                 ensure_empty_state_key(state_key, &event_type)?;
+                ensure_valid_topic(&event_content, config.max_topic_length)?;
 
                 state_event!(
                     TopicEvent,
@@ -435,11 +417,9 @@ impl Handler for StateMessageEvent {
             }
         };
 
-        let connection = DB::from_request(request)?;
-
         connection
             .transaction(|| {
-                verify_permissions(&connection, &room_id, &user, &event_type)?;
+                verify_permissions(&connection, &room_id, &user, &event_type, true)?;
 
                 diesel::insert_into(events::table)
                     .values(&state_event)
@@ -456,12 +436,117 @@ impl Handler for StateMessageEvent {
     }
 }
 
+/// The body of the request for the redaction API.
+#[derive(Clone, Debug, Deserialize)]
+struct RedactEventRequest {
+    /// The reason for the event being redacted.
+    reason: Option<String>,
+}
+
+/// The `/rooms/:room_id/redact/:event_id/:transaction_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct RedactEvent;
+
+middleware_chain!(
+    RedactEvent,
+    [
+        JsonRequest,
+        RoomIdParam,
+        EventIdParam,
+        TransactionIdParam,
+        AccessTokenAuth
+    ]
+);
+
+impl Handler for RedactEvent {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let room_id = RoomIdParam::from_request(request)?;
+
+        let redacts = EventIdParam::from_request(request)?;
+
+        TransactionIdParam::from_request(request)?;
+
+        let user = User::from_request(request)?;
+
+        let reason = match request.get::<bodyparser::Struct<RedactEventRequest>>() {
+            Ok(Some(redact_event_request)) => redact_event_request.reason,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let config = Config::from_request(request)?;
+        let event_id = EventId::new(&config.domain).map_api_err(|_| {
+            ApiError::unknown("Failed to generated event ID for the new event.".to_string())
+        })?;
+
+        let redaction_event: NewEvent = RedactionEvent {
+            content: RedactionEventContent { reason },
+            event_id: event_id.clone(),
+            event_type: EventType::RoomRedaction,
+            origin_server_ts: 0,
+            redacts: redacts.clone(),
+            room_id: Some(room_id.clone()),
+            sender: user.id.clone(),
+            unsigned: None,
+        }
+        .try_into()
+        .map_err(ApiError::from)?;
+
+        let connection = DB::from_request(request)?;
+
+        let path = request.url.path().join("/").to_string();
+        let token = AccessToken::from_request(request)?;
+
+        if let Some(transaction) = Transaction::find(&connection, &path, &token.value)? {
+            let response: EventResponse =
+                from_str(&transaction.response).map_err(ApiError::from)?;
+            return Ok(Response::with((status::Ok, SerializableResponse(response))));
+        }
+
+        let response = EventResponse {
+            event_id: event_id.opaque_id().to_string(),
+        };
+
+        connection
+            .transaction(|| {
+                verify_permissions(
+                    &connection,
+                    &room_id,
+                    &user,
+                    &EventType::RoomRedaction,
+                    false,
+                )?;
+                verify_redaction_permissions(&connection, &room_id, &user, &redacts)?;
+
+                diesel::insert_into(events::table)
+                    .values(&redaction_event)
+                    .execute(&*connection)
+                    .map_err(ApiError::from)?;
+
+                let serialized_response = to_string(&response).map_err(ApiError::from)?;
+
+                Transaction::create(
+                    &connection,
+                    path.clone(),
+                    token.value.clone(),
+                    serialized_response,
+                )
+            })
+            .map_err(ApiError::from)?;
+
+        Ok(Response::with((status::Ok, SerializableResponse(response))))
+    }
+}
+
 /// Check if a `User` has permission to create an event in a given `Room`.
+///
+/// Event types without a power level of their own require the room's `state_default` power level
+/// for state events and `events_default` for all other events.
 fn verify_permissions(
     connection: &PgConnection,
     room_id: &RoomId,
     user: &User,
     event_type: &EventType,
+    is_state_event: bool,
 ) -> Result<(), ApiError> {
     let room = match Room::find(connection, room_id)? {
         Some(room) => room,
@@ -470,34 +555,73 @@ fn verify_permissions(
         ))?,
     };
 
-    match RoomMembership::find(connection, room_id, &user.id)? {
-        Some(membership) => {
-            if membership.membership != "join" {
-                Err(ApiError::unauthorized(format!(
-                    "The user {} has not joined the room",
-                    user.id
-                )))?
-            }
-        }
-        None => Err(ApiError::unauthorized(format!(
-            "The user {} is not a member of the room",
-            user.id
-        )))?,
-    }
+    RoomMembership::require_membership(connection, room_id, &user.id, &["join"])?;
 
     let power_levels = room.current_power_levels(&*connection)?;
     let user_power_level = power_levels
         .users
         .get(&user.id)
         .unwrap_or(&power_levels.users_default);
+    let default_power_level = if is_state_event {
+        &power_levels.state_default
+    } else {
+        &power_levels.events_default
+    };
     let required_power_level = power_levels
         .events
         .get(event_type)
-        .unwrap_or(&power_levels.events_default);
+        .unwrap_or(default_power_level);
 
     if required_power_level > user_power_level {
+        let message = if is_state_event {
+            format!(
+                "Insufficient power level to set the {} state of this room: {} is required.",
+                event_type, required_power_level
+            )
+        } else {
+            "Insufficient power level to create this event.".to_string()
+        };
+
+        return Err(ApiError::unauthorized(message));
+    }
+
+    Ok(())
+}
+
+/// Check if a `User` may redact an event in a given `Room`.
+///
+/// Users may always redact their own events. Redacting the events of other users requires the
+/// room's `redact` power level.
+fn verify_redaction_permissions(
+    connection: &PgConnection,
+    room_id: &RoomId,
+    user: &User,
+    event_id: &EventId,
+) -> Result<(), ApiError> {
+    let event = Event::find(connection, event_id)?
+        .filter(|event| event.room_id.as_ref() == Some(room_id))
+        .ok_or_else(|| ApiError::not_found("The event was not found in the room.".to_string()))?;
+
+    if event.sender == user.id {
+        return Ok(());
+    }
+
+    let room = match Room::find(connection, room_id)? {
+        Some(room) => room,
+        None => Err(ApiError::unauthorized(
+            "The room was not found on this server".to_string(),
+        ))?,
+    };
+
+    let power_levels = room.current_power_levels(&*connection)?;
+    let user_power_level = power_levels
+        .users
+        .get(&user.id)
+        .unwrap_or(&power_levels.users_default);
+
+    if &power_levels.redact > user_power_level {
         return Err(ApiError::unauthorized(
-            "Insufficient power level to create this event.".to_string(),
+            "Insufficient power level to redact this event.".to_string(),
         ));
     }
 
@@ -516,6 +640,48 @@ fn ensure_empty_state_key(state_key: &str, event_type: &EventType) -> Result<(),
     }
 }
 
+/// Enforces that the alias in the content of an `m.room.canonical_alias` event, if any, is mapped
+/// to the given room.
+fn ensure_alias_points_to_room(
+    connection: &PgConnection,
+    event_content: &Value,
+    room_id: &RoomId,
+) -> Result<(), IronError> {
+    let alias = match event_content.get("alias").and_then(Value::as_str) {
+        Some(alias) => RoomAliasId::try_from(alias)
+            .map_api_err(|_| ApiError::bad_event("Invalid room alias.".to_string()))?,
+        None => return Ok(()),
+    };
+
+    match RoomAlias::find(connection, &alias)? {
+        Some(ref room_alias) if room_alias.room_id == *room_id => Ok(()),
+        _ => Err(ApiError::bad_alias(format!(
+            "The alias {} does not point to this room.",
+            alias
+        )))?,
+    }
+}
+
+/// Enforces that the content of an `m.room.topic` event contains a string topic that is not
+/// longer than the given number of characters.
+fn ensure_valid_topic(event_content: &Value, max_topic_length: usize) -> Result<(), IronError> {
+    let topic = match event_content.get("topic").and_then(Value::as_str) {
+        Some(topic) => topic,
+        None => Err(ApiError::bad_json(
+            "The topic must be a string.".to_string(),
+        ))?,
+    };
+
+    if topic.chars().count() > max_topic_length {
+        Err(ApiError::bad_json(format!(
+            "The topic must not be longer than {} characters.",
+            max_topic_length
+        )))?;
+    }
+
+    Ok(())
+}
+
 /// Convert the JSON from the request into the correct type for the event's `content` field.
 fn extract_event_content<T>(event_content: Value, event_type: &EventType) -> Result<T, ApiError>
 where
@@ -642,6 +808,10 @@ mod tests {
         let response = test.send_message(&bob.token, &room_id, "Hello", 1);
 
         assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
         assert_eq!(
             response.json().get("error").unwrap().as_str().unwrap(),
             format!("The user {} is not a member of the room", bob.id)
@@ -665,6 +835,57 @@ mod tests {
         );
     }
 
+    #[test]
+    fn redact_own_event() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.send_message(&carl.token, &room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let redact_path = format!(
+            "/_matrix/client/r0/rooms/{}/redact/{}/1?access_token={}",
+            room_id, event_id, carl.token
+        );
+        let response = test.put(&redact_path, "{}");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("event_id").unwrap().is_string());
+    }
+
+    #[test]
+    fn redact_event_of_other_user_without_power_level() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let alice = test.create_user();
+        assert_eq!(test.join_room(&alice.token, &room_id).status, Status::Ok);
+
+        let response = test.send_message(&carl.token, &room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let redact_path = format!(
+            "/_matrix/client/r0/rooms/{}/redact/{}/1?access_token={}",
+            room_id, event_id, alice.token
+        );
+        let response = test.put(&redact_path, "{}");
+
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn redact_unknown_event() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let redact_path = format!(
+            "/_matrix/client/r0/rooms/{}/redact/unknown/1?access_token={}",
+            room_id, carl.token
+        );
+        let response = test.put(&redact_path, "{}");
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
     #[test]
     fn overwrite_state_event() {
         let test = Test::new();
@@ -728,6 +949,103 @@ mod tests {
         assert_eq!(response.status, Status::Ok);
     }
 
+    #[test]
+    fn state_events_require_state_default_power_level() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_id = test.create_public_room(&alice.token);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.topic",
+            r#"{"topic": "Alice's topic"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.send_state_event(
+            &bob.token,
+            &room_id,
+            "m.room.topic",
+            r#"{"topic": "Bob's topic"}"#,
+        );
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            "Insufficient power level to set the m.room.topic state of this room: 50 is required."
+        );
+
+        // Message events only need the lower events_default power level.
+        let response = test.send_message(&bob.token, &room_id, "Hi", 1);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn topic_too_long() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures("{}");
+
+        let event_content = format!(r#"{{"topic": "{}"}}"#, "a".repeat(1025));
+        let response =
+            test.send_state_event(&alice.token, &room_id, "m.room.topic", &event_content);
+        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_BAD_JSON"
+        );
+
+        let response =
+            test.send_state_event(&alice.token, &room_id, "m.room.topic", r#"{"topic": 42}"#);
+        assert_eq!(response.status, Status::UnprocessableEntity);
+
+        let event_content = format!(r#"{{"topic": "{}"}}"#, "a".repeat(1024));
+        let response =
+            test.send_state_event(&alice.token, &room_id, "m.room.topic", &event_content);
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn canonical_alias_must_point_to_room() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"room_alias_name": "my_room"}"#);
+        test.create_room_with_params(&alice.token, r#"{"room_alias_name": "other_room"}"#);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.canonical_alias",
+            r##"{"alias": "#other_room:ruma.test"}"##,
+        );
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_BAD_ALIAS"
+        );
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.canonical_alias",
+            r##"{"alias": "#no_room:ruma.test"}"##,
+        );
+        assert_eq!(response.status, Status::BadRequest);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.canonical_alias",
+            r##"{"alias": "#my_room:ruma.test"}"##,
+        );
+        assert_eq!(response.status, Status::Ok);
+    }
+
     #[test]
     fn create_events_with_transactions() {
         let test = Test::new();
@@ -745,5 +1063,12 @@ mod tests {
         let response = test.send_message(&alice.token, &room_id, "Hi once again", 2);
         let third_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
         assert_ne!(third_event_id, second_event_id);
+
+        // Using the same transaction ID in another room.
+        let other_room_id = test.create_room(&alice.token);
+        let response = test.send_message(&alice.token, &other_room_id, "Hi there", 1);
+        assert_eq!(response.status, Status::Ok);
+        let fourth_event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+        assert_ne!(fourth_event_id, first_event_id);
     }
 }
@@ -4,6 +4,7 @@ use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
 use serde_json::{from_str, to_value};
 
+use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
 use crate::middleware::{
@@ -11,7 +12,7 @@ use crate::middleware::{
 };
 use crate::models::filter::{ContentFilter, Filter};
 use crate::models::user::User;
-use crate::modifier::SerializableResponse;
+use crate::modifier::{empty_response, SerializableResponse};
 
 /// The GET `/user/:user_id/filter/:filter_id` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -21,16 +22,17 @@ middleware_chain!(GetFilter, [AccessTokenAuth, FilterIdParam, UserIdParam]);
 
 impl Handler for GetFilter {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
 
-        let filter_id = *request
-            .extensions
-            .get::<FilterIdParam>()
-            .expect("FilterIdParam should ensure a FilterIdParam");
+        let user = User::from_request(request)?;
+
+        let filter_id = FilterIdParam::from_request(request)?;
+
+        // Filters of other users are reported as missing, with the same error as filters that
+        // don't exist, so that their existence isn't leaked.
+        if user_id != user.id {
+            Err(Filter::not_found())?;
+        }
 
         let connection = DB::from_request(request)?;
         let filter = Filter::find(&connection, user_id, filter_id)?;
@@ -39,6 +41,74 @@ impl Handler for GetFilter {
     }
 }
 
+/// The DELETE `/user/:user_id/filter/:filter_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct DeleteFilter;
+
+middleware_chain!(DeleteFilter, [AccessTokenAuth, FilterIdParam, UserIdParam]);
+
+impl Handler for DeleteFilter {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user_id = UserIdParam::from_request(request)?;
+
+        let user = User::from_request(request)?;
+
+        let filter_id = FilterIdParam::from_request(request)?;
+
+        if user_id != user.id {
+            Err(Filter::not_found())?;
+        }
+
+        let connection = DB::from_request(request)?;
+        Filter::delete(&connection, user_id, filter_id)?;
+
+        Ok(empty_response())
+    }
+}
+
+/// The GET `/user/:user_id/filters` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct ListFilters;
+
+/// A single filter in the response of `ListFilters`.
+#[derive(Debug, Serialize)]
+struct ListedFilter {
+    /// The ID of the filter.
+    filter_id: String,
+    /// The filter definition.
+    content: ContentFilter,
+}
+
+middleware_chain!(ListFilters, [AccessTokenAuth, UserIdParam]);
+
+impl Handler for ListFilters {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user_id = UserIdParam::from_request(request)?;
+
+        let user = User::from_request(request)?;
+
+        if user_id != user.id {
+            Err(ApiError::unauthorized(
+                "The given user_id does not correspond to the authenticated user".to_string(),
+            ))?;
+        }
+
+        let connection = DB::from_request(request)?;
+
+        let response = Filter::list_for_user(&connection, &user_id)?
+            .into_iter()
+            .map(|filter| {
+                Ok(ListedFilter {
+                    filter_id: filter.id.to_string(),
+                    content: from_str(&filter.content).map_err(ApiError::from)?,
+                })
+            })
+            .collect::<Result<Vec<ListedFilter>, ApiError>>()?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
 /// The POST `/user/:user_id/filter` endpoint.
 #[derive(Clone, Copy, Debug)]
 pub struct PostFilter;
@@ -54,17 +124,9 @@ middleware_chain!(PostFilter, [JsonRequest, AccessTokenAuth, UserIdParam]);
 
 impl Handler for PostFilter {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
-
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
+
+        let user = User::from_request(request)?;
 
         if user_id != user.id {
             Err(ApiError::unauthorized(
@@ -77,13 +139,25 @@ impl Handler for PostFilter {
             Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
         };
 
+        filter.validate()?;
+
+        let deduplicate_filters = Config::from_request(request)?.deduplicate_filters;
         let connection = DB::from_request(request)?;
 
-        let id = Filter::create(
-            &connection,
-            user_id,
-            to_value(&filter).map_err(ApiError::from)?.to_string(),
-        )?;
+        // Serializing the parsed filter yields the same string for equivalent filters, regardless
+        // of key order or whitespace in the request body.
+        let content = to_value(&filter).map_err(ApiError::from)?.to_string();
+
+        let existing_id = if deduplicate_filters {
+            Filter::find_id_by_content(&connection, user_id.clone(), &content)?
+        } else {
+            None
+        };
+
+        let id = match existing_id {
+            Some(id) => id,
+            None => Filter::create(&connection, user_id, content)?,
+        };
 
         let response = PostFilterResponse {
             filter_id: id.to_string(),
@@ -119,6 +193,24 @@ mod tests {
         assert_eq!(response.body, r#"{"room":{"timeline":{"limit":10}}}"#);
     }
 
+    #[test]
+    fn top_level_sections_round_trip() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let filter = r#"{"presence":{"limit":5,"types":["m.presence"]},"account_data":{"not_types":["m.tag"]},"event_format":"client","event_fields":["content.body"]}"#;
+
+        let filter_id = test.create_filter(&carl.token, carl.id.as_str(), filter);
+
+        let get_filter_path = format!(
+            "/_matrix/client/r0/user/{}/filter/{}?access_token={}",
+            carl.id, filter_id, carl.token
+        );
+
+        let response = test.get(&get_filter_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body, filter);
+    }
+
     #[test]
     fn invalid_user() {
         let test = Test::new();
@@ -133,6 +225,232 @@ mod tests {
         assert_eq!(response.status, Status::Forbidden);
     }
 
+    #[test]
+    fn fractional_limit() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let filter_path = format!(
+            "/_matrix/client/r0/user/{}/filter?access_token={}",
+            carl.id, carl.token
+        );
+
+        let response = test.post(&filter_path, r#"{"room":{"timeline":{"limit":10.5}}}"#);
+        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_BAD_JSON"
+        );
+
+        let response = test.post(&filter_path, r#"{"presence":{"limit":1.0}}"#);
+        assert_eq!(response.status, Status::UnprocessableEntity);
+    }
+
+    #[test]
+    fn negative_limit() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let filter_path = format!(
+            "/_matrix/client/r0/user/{}/filter?access_token={}",
+            carl.id, carl.token
+        );
+
+        let response = test.post(&filter_path, r#"{"room":{"timeline":{"limit":-1}}}"#);
+        assert_eq!(response.status, Status::UnprocessableEntity);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_BAD_JSON"
+        );
+    }
+
+    #[test]
+    fn invalid_event_fields() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let filter_path = format!(
+            "/_matrix/client/r0/user/{}/filter?access_token={}",
+            carl.id, carl.token
+        );
+
+        for event_fields in &[
+            r#"[""]"#,
+            r#"["content..body"]"#,
+            r#"["content."]"#,
+            r#"["\\n"]"#,
+        ] {
+            let body = format!(r#"{{"event_fields":{}}}"#, event_fields);
+            let response = test.post(&filter_path, &body);
+            assert_eq!(response.status, Status::UnprocessableEntity);
+            assert_eq!(
+                response.json().get("errcode").unwrap().as_str().unwrap(),
+                "M_BAD_JSON"
+            );
+        }
+
+        let response = test.post(
+            &filter_path,
+            r#"{"event_fields":["type","content.body","content.m\\.relates_to"]}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn duplicate_filter_reuses_id() {
+        let test = Test::with_config(|config| config.deduplicate_filters = true);
+        let carl = test.create_user();
+        let alice = test.create_user();
+
+        let filter_id = test.create_filter(
+            &carl.token,
+            carl.id.as_str(),
+            r#"{"room":{"timeline":{"limit":10},"rooms":["!a:ruma.test"]}}"#,
+        );
+        let same_filter_id = test.create_filter(
+            &carl.token,
+            carl.id.as_str(),
+            r#"{ "room": { "rooms": ["!a:ruma.test"], "timeline": { "limit": 10 } } }"#,
+        );
+        let other_filter_id = test.create_filter(
+            &carl.token,
+            carl.id.as_str(),
+            r#"{"room":{"timeline":{"limit":20}}}"#,
+        );
+        let alice_filter_id = test.create_filter(
+            &alice.token,
+            alice.id.as_str(),
+            r#"{"room":{"timeline":{"limit":10},"rooms":["!a:ruma.test"]}}"#,
+        );
+
+        assert_eq!(filter_id, same_filter_id);
+        assert_ne!(filter_id, other_filter_id);
+        assert_ne!(filter_id, alice_filter_id);
+    }
+
+    #[test]
+    fn duplicate_filter_without_deduplication() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let content = r#"{"room":{"timeline":{"limit":10}}}"#;
+
+        let filter_id = test.create_filter(&carl.token, carl.id.as_str(), content);
+        let second_filter_id = test.create_filter(&carl.token, carl.id.as_str(), content);
+
+        assert_ne!(filter_id, second_filter_id);
+    }
+
+    #[test]
+    fn delete_filter() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let filter_id = test.create_filter(
+            &carl.token,
+            carl.id.as_str(),
+            r#"{"room":{"timeline":{"limit":10}}}"#,
+        );
+
+        let filter_path = format!(
+            "/_matrix/client/r0/user/{}/filter/{}?access_token={}",
+            carl.id, filter_id, carl.token
+        );
+
+        test.check_empty_response(test.delete(&filter_path));
+
+        assert_eq!(test.get(&filter_path).status, Status::NotFound);
+        assert_eq!(test.delete(&filter_path).status, Status::NotFound);
+    }
+
+    #[test]
+    fn delete_filter_of_other_user() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let alice = test.create_user();
+
+        let filter_id = test.create_filter(
+            &carl.token,
+            carl.id.as_str(),
+            r#"{"room":{"timeline":{"limit":10}}}"#,
+        );
+
+        let filter_path = format!(
+            "/_matrix/client/r0/user/{}/filter/{}?access_token={}",
+            carl.id, filter_id, alice.token
+        );
+        assert_eq!(test.delete(&filter_path).status, Status::NotFound);
+
+        let filter_path = format!(
+            "/_matrix/client/r0/user/{}/filter/{}?access_token={}",
+            carl.id, filter_id, carl.token
+        );
+        assert_eq!(test.get(&filter_path).status, Status::Ok);
+    }
+
+    #[test]
+    fn list_filters() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let alice = test.create_user();
+
+        let first_id = test.create_filter(
+            &carl.token,
+            carl.id.as_str(),
+            r#"{"room":{"timeline":{"limit":10}}}"#,
+        );
+        let second_id =
+            test.create_filter(&carl.token, carl.id.as_str(), r#"{"presence":{"limit":5}}"#);
+        test.create_filter(
+            &alice.token,
+            alice.id.as_str(),
+            r#"{"room":{"timeline":{"limit":1}}}"#,
+        );
+
+        let filters_path = format!(
+            "/_matrix/client/r0/user/{}/filters?access_token={}",
+            carl.id, carl.token
+        );
+        let response = test.get(&filters_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let filters = response.json().as_array().unwrap();
+        assert_eq!(filters.len(), 2);
+        assert_eq!(
+            filters[0].get("filter_id").unwrap().as_str().unwrap(),
+            first_id
+        );
+        assert_eq!(
+            filters[0]
+                .pointer("/content/room/timeline/limit")
+                .unwrap()
+                .as_u64()
+                .unwrap(),
+            10
+        );
+        assert_eq!(
+            filters[1].get("filter_id").unwrap().as_str().unwrap(),
+            second_id
+        );
+        assert_eq!(
+            filters[1]
+                .pointer("/content/presence/limit")
+                .unwrap()
+                .as_u64()
+                .unwrap(),
+            5
+        );
+    }
+
+    #[test]
+    fn list_filters_of_other_user() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let alice = test.create_user();
+
+        let filters_path = format!(
+            "/_matrix/client/r0/user/{}/filters?access_token={}",
+            carl.id, alice.token
+        );
+        assert_eq!(test.get(&filters_path).status, Status::Forbidden);
+    }
+
     #[test]
     fn get_not_found() {
         let test = Test::new();
@@ -146,4 +464,40 @@ mod tests {
         let response = test.get(&get_filter_path);
         assert_eq!(response.status, Status::NotFound);
     }
+
+    #[test]
+    fn get_filter_of_other_user_looks_missing() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let alice = test.create_user();
+
+        let filter_id = test.create_filter(
+            &carl.token,
+            carl.id.as_str(),
+            r#"{"room":{"timeline":{"limit":10}}}"#,
+        );
+
+        let other_user_response = test.get(&format!(
+            "/_matrix/client/r0/user/{}/filter/{}?access_token={}",
+            carl.id, filter_id, alice.token
+        ));
+        assert_eq!(other_user_response.status, Status::NotFound);
+
+        let missing_response = test.get(&format!(
+            "/_matrix/client/r0/user/{}/filter/{}?access_token={}",
+            alice.id, filter_id, alice.token
+        ));
+        assert_eq!(missing_response.status, Status::NotFound);
+
+        assert_eq!(
+            missing_response
+                .json()
+                .get("errcode")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "M_NOT_FOUND"
+        );
+        assert_eq!(other_user_response.body, missing_response.body);
+    }
 }
@@ -19,7 +19,7 @@ use crate::models::room::Room;
 use crate::models::room_alias::RoomAlias;
 use crate::models::room_membership::{RoomMembership, RoomMembershipOptions};
 use crate::models::user::User;
-use crate::modifier::{EmptyResponse, SerializableResponse};
+use crate::modifier::{empty_response, SerializableResponse};
 
 /// The `/rooms/:room_id/join` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -36,20 +36,12 @@ middleware_chain!(JoinRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
 
 impl Handler for JoinRoom {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = User::from_request(request)?;
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
 
-        let room_id = request
-            .extensions
-            .get::<RoomIdParam>()
-            .expect("Should have been required by RoomIdParam.")
-            .clone();
+        let room_id = RoomIdParam::from_request(request)?;
 
         join_room(room_id, user, &connection, &config)
     }
@@ -66,20 +58,12 @@ middleware_chain!(
 
 impl Handler for JoinRoomWithIdOrAlias {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = User::from_request(request)?;
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
 
-        let room_id_or_alias = request
-            .extensions
-            .get::<RoomIdOrAliasParam>()
-            .expect("Should have been required by RoomIdOrAliasParam.")
-            .clone();
+        let room_id_or_alias = RoomIdOrAliasParam::from_request(request)?;
 
         let room_id = match room_id_or_alias {
             RoomIdOrAliasId::RoomId(id) => id,
@@ -100,15 +84,7 @@ fn join_room(
     connection: &PgConnection,
     config: &Config,
 ) -> IronResult<Response> {
-    let room_membership_options = RoomMembershipOptions {
-        room_id: room_id.clone(),
-        user_id: user.id.clone(),
-        sender: user.id,
-        membership: "join".to_string(),
-    };
-
-    let room_membership =
-        RoomMembership::upsert(connection, &config.domain, room_membership_options)?;
+    let room_membership = RoomMembership::join(connection, &config.domain, room_id, &user.id)?;
 
     let response = JoinRoomResponse {
         room_id: room_membership.room_id,
@@ -125,26 +101,19 @@ middleware_chain!(LeaveRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
 
 impl Handler for LeaveRoom {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = User::from_request(request)?;
 
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
 
-        let room_id = request
-            .extensions
-            .get::<RoomIdParam>()
-            .expect("Should have been required by RoomIdParam.")
-            .clone();
+        let room_id = RoomIdParam::from_request(request)?;
 
         let room_membership_options = RoomMembershipOptions {
             room_id: room_id.clone(),
             user_id: user.id.clone(),
             sender: user.id.clone(),
             membership: "leave".to_string(),
+            is_direct: None,
         };
 
         if Room::find(&connection, &room_id)?.is_none() {
@@ -155,10 +124,10 @@ impl Handler for LeaveRoom {
 
         match RoomMembership::find(&connection, &room_id, &user.id)? {
             Some(mut room_membership) => match room_membership.membership.as_str() {
-                "leave" => Ok(Response::with(Status::Ok)),
+                "leave" => Ok(empty_response()),
                 "join" | "invite" => {
                     room_membership.update(&connection, &config.domain, room_membership_options)?;
-                    Ok(Response::with(EmptyResponse(Status::Ok)))
+                    Ok(empty_response())
                 }
                 "ban" => Err(ApiError::unauthorized(
                     "User is banned from the room".to_string(),
@@ -191,17 +160,9 @@ middleware_chain!(KickFromRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
 
 impl Handler for KickFromRoom {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let room_id = request
-            .extensions
-            .get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a room_id")
-            .clone();
-
-        let kicker = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let room_id = RoomIdParam::from_request(request)?;
+
+        let kicker = User::from_request(request)?;
 
         let kickee_id = match request.get::<bodyparser::Struct<KickFromRoomRequest>>() {
             Ok(Some(req)) => req.user_id,
@@ -250,11 +211,12 @@ impl Handler for KickFromRoom {
             user_id: kickee_id,
             sender: kicker.id,
             membership: "leave".to_string(),
+            is_direct: None,
         };
 
         kickee_membership.update(&connection, &config.domain, room_membership_options)?;
 
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        Ok(empty_response())
     }
 }
 
@@ -273,17 +235,9 @@ middleware_chain!(InviteToRoom, [JsonRequest, RoomIdParam, AccessTokenAuth]);
 
 impl Handler for InviteToRoom {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let room_id = request
-            .extensions
-            .get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a room_id")
-            .clone();
-
-        let inviter = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let room_id = RoomIdParam::from_request(request)?;
+
+        let inviter = User::from_request(request)?;
 
         let invitee_id = match request.get::<bodyparser::Struct<InviteToRoomRequest>>() {
             Ok(Some(req)) => req.user_id,
@@ -334,6 +288,7 @@ impl Handler for InviteToRoom {
             user_id: invitee_id,
             sender: inviter.id,
             membership: "invite".to_string(),
+            is_direct: None,
         };
 
         if let Some(mut entry) = invitee_membership {
@@ -353,12 +308,14 @@ impl Handler for InviteToRoom {
             RoomMembership::create(&connection, &config.domain, new_membership_options)?;
         }
 
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        Ok(empty_response())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::str::from_utf8;
+
     use crate::test::Test;
     use iron::status::Status;
 
@@ -416,6 +373,47 @@ mod tests {
         );
     }
 
+    #[test]
+    fn join_other_public_room_via_join_endpoint_alias() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let mark = test.create_user();
+        let room_id = test.create_room_with_params(
+            &carl.token,
+            r#"{"room_alias_name":"thepub", "visibility": "public"}"#,
+        );
+
+        let room_join_path = format!(
+            "/_matrix/client/r0/join/%23thepub:ruma.test?access_token={}",
+            mark.token
+        );
+
+        let response = test.post(&room_join_path, r"{}");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("room_id").unwrap().as_str().unwrap(),
+            room_id
+        );
+    }
+
+    #[test]
+    fn join_unknown_alias() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let room_join_path = format!(
+            "/_matrix/client/r0/join/%23nothere:ruma.test?access_token={}",
+            carl.token
+        );
+
+        let response = test.post(&room_join_path, r"{}");
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_FOUND"
+        );
+    }
+
     #[test]
     fn join_own_public_room() {
         let test = Test::new();
@@ -501,6 +499,23 @@ mod tests {
         assert_eq!(response.status, Status::Forbidden);
     }
 
+    #[test]
+    fn rejoin_other_private_room_after_leaving() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let mark = test.create_user();
+
+        let body = format!(r#"{{"visibility": "private", "invite": ["{}"]}}"#, mark.id);
+        let room_id = test.create_room_with_params(&carl.token, &body);
+
+        assert_eq!(test.join_room(&mark.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&mark.token, &room_id).status, Status::Ok);
+
+        // The invite was used up by the first join.
+        let response = test.join_room(&mark.token, &room_id);
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
     #[test]
     fn invite_to_room() {
         let test = Test::new();
@@ -514,6 +529,31 @@ mod tests {
         assert!(test.join_room(&alice.token, &room_id).status.is_success());
     }
 
+    #[test]
+    fn invite_is_sent_by_inviter() {
+        let test = Test::new();
+        let (bob, room_id) = test.initial_fixtures(r#"{"visibility": "private"}"#);
+        let alice = test.create_user();
+
+        let response = test.invite(&bob.token, &room_id, &alice.id);
+        assert_eq!(response.status, Status::Ok);
+
+        let room_members_path = format!(
+            "/_matrix/client/r0/rooms/{}/members?membership=invite&access_token={}",
+            room_id, bob.token
+        );
+        let response = test.get(&room_members_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let chunk = response.json().get("chunk").unwrap().as_array().unwrap();
+        assert_eq!(chunk.len(), 1);
+        assert_eq!(
+            chunk[0].get("state_key").unwrap().as_str().unwrap(),
+            alice.id
+        );
+        assert_eq!(chunk[0].get("sender").unwrap().as_str().unwrap(), bob.id);
+    }
+
     #[test]
     fn invite_before_joining() {
         let test = Test::new();
@@ -651,6 +691,90 @@ mod tests {
         );
     }
 
+    #[test]
+    fn leave_room_response() {
+        let test = Test::new();
+        let bob = test.create_user();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let leave_room_path = format!(
+            "/_matrix/client/r0/rooms/{}/leave?access_token={}",
+            room_id, bob.token,
+        );
+
+        let response = test.post(&leave_room_path, r#"{}"#);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+
+        let leave_room_path = format!(
+            "/_matrix/client/r0/rooms/{}/leave?access_token={}",
+            room_id, alice.token,
+        );
+
+        test.check_empty_response(test.post(&leave_room_path, r#"{}"#));
+    }
+
+    #[test]
+    fn leave_event_has_prev_content() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let mark = test.create_user();
+
+        let displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            mark.id, mark.token
+        );
+        assert_eq!(
+            test.put(&displayname_path, r#"{"displayname": "Mark"}"#)
+                .status,
+            Status::Ok
+        );
+
+        assert_eq!(test.join_room(&mark.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&mark.token, &room_id).status, Status::Ok);
+
+        let members_path = format!(
+            "/_matrix/client/r0/rooms/{}/members?access_token={}",
+            room_id, carl.token
+        );
+        let response = test.get(&members_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let chunk = response.json().get("chunk").unwrap().as_array().unwrap();
+        let leave_event = chunk
+            .iter()
+            .find(|event| event.get("state_key").unwrap().as_str().unwrap() == mark.id)
+            .unwrap();
+
+        assert_eq!(
+            leave_event
+                .pointer("/content/membership")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "leave"
+        );
+        assert_eq!(
+            leave_event
+                .pointer("/unsigned/prev_content/membership")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "join"
+        );
+        assert_eq!(
+            leave_event
+                .pointer("/unsigned/prev_content/displayname")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Mark"
+        );
+    }
+
     #[test]
     fn leave_nonexistent_room() {
         let test = Test::new();
@@ -858,4 +982,71 @@ mod tests {
             "The kickee is not currently in the room"
         );
     }
+
+    #[test]
+    fn rate_limited_join_has_retry_after() {
+        let test = Test::with_config(|config| config.room_join_rate_limit = 1);
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        let other_room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.join_room(&bob.token, &other_room_id);
+        assert_eq!(response.status, Status::TooManyRequests);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_LIMIT_EXCEEDED"
+        );
+
+        let retry_after_ms = response
+            .json()
+            .get("retry_after_ms")
+            .unwrap()
+            .as_u64()
+            .unwrap();
+        let retry_after: u64 = response
+            .headers
+            .get_raw("Retry-After")
+            .and_then(|values| values.first())
+            .and_then(|value| from_utf8(value).ok())
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(retry_after, (retry_after_ms + 999) / 1000);
+    }
+
+    #[test]
+    fn join_endpoints_share_rate_limit() {
+        let test = Test::with_config(|config| config.room_join_rate_limit = 2);
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_ids: Vec<String> = (0..3)
+            .map(|_| test.create_public_room(&alice.token))
+            .collect();
+
+        let join_with_id_path = |room_id: &str| {
+            format!(
+                "/_matrix/client/r0/join/{}?access_token={}",
+                room_id, bob.token
+            )
+        };
+
+        assert_eq!(test.join_room(&bob.token, &room_ids[0]).status, Status::Ok);
+        assert_eq!(
+            test.post(&join_with_id_path(&room_ids[1]), "{}").status,
+            Status::Ok
+        );
+
+        // Each endpoint was only used once, but together they reached the limit.
+        assert_eq!(
+            test.join_room(&bob.token, &room_ids[2]).status,
+            Status::TooManyRequests
+        );
+        assert_eq!(
+            test.post(&join_with_id_path(&room_ids[2]), "{}").status,
+            Status::TooManyRequests
+        );
+    }
 }
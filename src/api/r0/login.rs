@@ -15,6 +15,7 @@ use crate::db::DB;
 use crate::error::ApiError;
 use crate::middleware::{JsonRequest, MiddlewareChain};
 use crate::models::access_token::AccessToken;
+use crate::models::user::{local_user_id, User};
 use crate::modifier::SerializableResponse;
 
 /// The `/login` endpoint.
@@ -70,6 +71,8 @@ struct LoginRequest {
     pub user: String,
     /// The user's password.
     pub password: String,
+    /// The ID of the client device logging in.
+    pub device_id: Option<String>,
 }
 
 /// The body of the response for this API.
@@ -81,6 +84,9 @@ struct LoginResponse {
     pub home_server: String,
     /// The fully-qualified Matrix ID that has been registered.
     pub user_id: UserId,
+    /// The ID of the device the access token is linked to, if the request specified one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
 }
 
 middleware_chain!(Login, [JsonRequest]);
@@ -105,25 +111,23 @@ impl Handler for Login {
 
                 user_id
             }
-            Err(_) => {
-                UserId::try_from(format!("@{}:{}", login_request.user, &config.domain).as_ref())
-                    .map_err(ApiError::from)?
-            }
+            Err(_) => local_user_id(&login_request.user, &config.domain)?,
         };
 
+        let connection = DB::from_request(request)?;
+        let user_id = User::find_login_id(&connection, &user_id)?;
+
         let auth_params = AuthParams::Password(PasswordAuthParams {
             password: login_request.password,
             user_id,
         });
 
-        let connection = DB::from_request(request)?;
-        let registered_user = auth_params
-            .authenticate(&connection)
-            .map_err(|_| ApiError::unauthorized("Invalid credentials".to_string()))?;
+        let registered_user = auth_params.authenticate(&connection)?;
 
         let access_token = AccessToken::create(
             &connection,
             &registered_user.id,
+            login_request.device_id,
             &config.macaroon_secret_key,
         )?;
 
@@ -131,6 +135,7 @@ impl Handler for Login {
             access_token: access_token.value,
             home_server: config.domain.clone(),
             user_id: registered_user.id,
+            device_id: access_token.device_id,
         };
 
         Ok(Response::with((status::Ok, SerializableResponse(response))))
@@ -139,8 +144,13 @@ impl Handler for Login {
 
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
+
+    use crate::crypto::hash_password;
+    use crate::models::user::{NewUser, User};
     use crate::test::Test;
     use iron::status::Status;
+    use ruma_identifiers::UserId;
 
     #[test]
     fn valid_credentials() {
@@ -213,4 +223,58 @@ mod tests {
 
         assert_eq!(response.status, Status::Forbidden);
     }
+
+    #[test]
+    fn malformed_password_hash() {
+        let test = Test::new();
+
+        test.with_connection(|connection| {
+            let new_user = NewUser {
+                id: UserId::try_from("@carl:ruma.test").unwrap(),
+                password_hash: "not a hash".to_string(),
+            };
+            User::create(connection, &new_user, &[0; 32]).unwrap();
+        });
+
+        let response = test.post(
+            "/_matrix/client/r0/login",
+            r#"{"type": "m.login.password", "user": "carl", "password": "secret"}"#,
+        );
+
+        assert_eq!(response.status, Status::InternalServerError);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNKNOWN"
+        );
+    }
+
+    #[test]
+    fn mixed_case_username_can_log_in() {
+        let test = Test::new();
+
+        // Such localparts can no longer be registered, but existing accounts may have them.
+        test.with_connection(|connection| {
+            let new_user = NewUser {
+                id: UserId::try_from("@Carl:ruma.test").unwrap(),
+                password_hash: hash_password("secret", 3).unwrap(),
+            };
+            User::create(connection, &new_user, &[0; 32]).unwrap();
+        });
+
+        for user in &["Carl", "carl", "@carl:ruma.test"] {
+            let response = test.post(
+                "/_matrix/client/r0/login",
+                &format!(
+                    r#"{{"type": "m.login.password", "user": "{}", "password": "secret"}}"#,
+                    user
+                ),
+            );
+
+            assert_eq!(response.status, Status::Ok);
+            assert_eq!(
+                response.json().get("user_id").unwrap().as_str().unwrap(),
+                "@Carl:ruma.test"
+            );
+        }
+    }
 }
@@ -1,12 +1,12 @@
 //! Endpoints for logging out users.
 
-use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Request, Response};
 
 use crate::db::DB;
 use crate::middleware::{AccessTokenAuth, MiddlewareChain};
 use crate::models::access_token::AccessToken;
-use crate::modifier::EmptyResponse;
+use crate::models::user::User;
+use crate::modifier::empty_response;
 
 /// The `/logout` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -18,14 +18,29 @@ impl Handler for Logout {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
         let connection = DB::from_request(request)?;
 
-        let access_token = request
-            .extensions
-            .get_mut::<AccessToken>()
-            .expect("AccessTokenAuth should ensure an access token");
+        let mut access_token = AccessToken::from_request(request)?;
 
         access_token.revoke(&connection)?;
 
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        Ok(empty_response())
+    }
+}
+
+/// The `/logout/all` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct LogoutAll;
+
+middleware_chain!(LogoutAll, [AccessTokenAuth]);
+
+impl Handler for LogoutAll {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = User::from_request(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        AccessToken::revoke_all_for_user(&connection, &user.id, None)?;
+
+        Ok(empty_response())
     }
 }
 
@@ -43,6 +58,44 @@ mod tests {
         let login_path = format!("/_matrix/client/r0/logout?access_token={}", user.token);
 
         assert!(test.post(&login_path, "{}").status.is_success());
-        assert_eq!(test.post(&login_path, "{}").status, Status::Forbidden);
+
+        let response = test.post(&login_path, "{}");
+        assert_eq!(response.status, Status::Unauthorized);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNKNOWN_TOKEN"
+        );
+    }
+
+    #[test]
+    fn logout_all_revokes_every_access_token() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let login_body = format!(
+            r#"{{"type": "m.login.password", "user": "{}", "password": "secret"}}"#,
+            user.id
+        );
+        let response = test.post("/_matrix/client/r0/login", &login_body);
+        assert_eq!(response.status, Status::Ok);
+        let second_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert_ne!(second_token, user.token);
+
+        let logout_all_path = format!("/_matrix/client/r0/logout/all?access_token={}", user.token);
+        test.check_empty_response(test.post(&logout_all_path, "{}"));
+
+        for token in &[&user.token, &second_token] {
+            let response = test.post(
+                &format!("/_matrix/client/r0/logout?access_token={}", token),
+                "{}",
+            );
+            assert_eq!(response.status, Status::Unauthorized);
+        }
     }
 }
@@ -1,14 +1,32 @@
 //! Endpoints for room members.
 
+use std::cmp;
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+
 use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Request, Response};
 use ruma_events::room::member::MemberEvent;
+use ruma_identifiers::UserId;
+use url::Url;
 
+use crate::api::r0::pagination::parse_limit;
 use crate::db::DB;
+use crate::error::ApiError;
 use crate::middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
-use crate::models::room_membership::RoomMembership;
+use crate::models::event::Event;
+use crate::models::profile::Profile;
+use crate::models::room_membership::{MemberEventsOptions, RoomMembership};
 use crate::models::user::User;
 use crate::modifier::SerializableResponse;
+use crate::query::Batch;
+
+/// The number of members returned per page if no `limit` is given.
+const MEMBERS_DEFAULT_LIMIT: u64 = 100;
+
+/// The maximum number of members returned per page.
+const MEMBERS_MAX_LIMIT: u64 = 1000;
 
 /// The `/rooms/:room_id/members` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -19,28 +37,186 @@ pub struct Members;
 struct MembersResponse {
     /// A list of `MemberEvent`s for the room.
     chunk: Vec<MemberEvent>,
+    /// A token to pass as `from` to get the next page of members, if there may be more.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    next_batch: Option<String>,
 }
 
 middleware_chain!(Members, [RoomIdParam, AccessTokenAuth]);
 
 impl Handler for Members {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user");
+        let user = User::from_request(request)?;
+
+        let connection = DB::from_request(request)?;
+
+        let room_id = RoomIdParam::from_request(request)?;
+
+        let url: Url = request.url.clone().into();
+        let query_pairs = url.query_pairs().into_owned();
+
+        let mut options = MemberEventsOptions::default();
+        let mut limit = None;
+        for (key, value) in query_pairs {
+            match key.as_ref() {
+                "at" => {
+                    let batch = Batch::from_str(&value)
+                        .map_err(|err| ApiError::invalid_param("at", &err))?;
+                    options.at = Some(batch.room_key);
+                }
+                "from" => {
+                    let from = i64::from_str_radix(&value, 10)
+                        .map_err(|err| ApiError::invalid_param("from", err.description()))?;
+                    options.from = Some(from);
+                }
+                "limit" => {
+                    limit = Some(value);
+                }
+                "membership" => {
+                    options.membership = Some(value);
+                }
+                _ => (),
+            }
+        }
+
+        let limit = parse_limit(
+            limit.as_ref().map(String::as_str),
+            MEMBERS_DEFAULT_LIMIT,
+            MEMBERS_MAX_LIMIT,
+        )?;
+        options.limit = Some(limit as i64);
+
+        let membership = RoomMembership::require_membership(
+            &connection,
+            &room_id,
+            &user.id,
+            &["join", "leave"],
+        )?;
+
+        // Users who left the room can only see its members from before they left.
+        if membership.membership == "leave" {
+            let leave_event = Event::find(&connection, &membership.event_id)?
+                .expect("A room membership should be associated with an event");
+
+            options.at = Some(options.at.map_or(leave_event.ordering, |at| {
+                cmp::min(at, leave_event.ordering)
+            }));
+        }
+
+        let (next_from, events) =
+            RoomMembership::get_events_by_room(&connection, room_id, &options)?;
+
+        let response = MembersResponse {
+            chunk: events,
+            next_batch: next_from.map(|from| from.to_string()),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The `/rooms/:room_id/joined_members` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct JoinedMembers;
+
+/// The body of the response for this API.
+#[derive(Debug, Serialize)]
+struct JoinedMembersResponse {
+    /// The profiles of the joined members, keyed by their user ID.
+    joined: HashMap<UserId, RoomMember>,
+    /// The number of joined members, if `include_counts` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    joined_count: Option<i64>,
+    /// The number of invited users, if `include_counts` was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    invited_count: Option<i64>,
+}
+
+/// The profile of a joined member.
+#[derive(Debug, Serialize)]
+struct RoomMember {
+    /// The member's avatar URL, if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+    /// The member's display name, if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    display_name: Option<String>,
+}
+
+middleware_chain!(JoinedMembers, [RoomIdParam, AccessTokenAuth]);
+
+impl Handler for JoinedMembers {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = User::from_request(request)?;
+
+        let room_id = RoomIdParam::from_request(request)?;
+
+        let url: Url = request.url.clone().into();
+        let mut include_counts = false;
+        for (key, value) in url.query_pairs() {
+            match (key.as_ref(), value.as_ref()) {
+                ("include_counts", "true") => include_counts = true,
+                ("include_counts", "false") => include_counts = false,
+                ("include_counts", _) => {
+                    Err(ApiError::invalid_param(
+                        "include_counts",
+                        "Must be true or false.",
+                    ))?;
+                }
+                _ => (),
+            }
+        }
 
         let connection = DB::from_request(request)?;
 
-        let room_id = request
-            .extensions
-            .get::<RoomIdParam>()
-            .expect("Should have been required by RoomIdParam.")
-            .clone();
+        RoomMembership::require_membership(&connection, &room_id, &user.id, &["join"])?;
+
+        let user_ids =
+            RoomMembership::find_user_ids_by_room_and_state(&connection, &room_id, "join")?;
+
+        let mut joined: HashMap<UserId, RoomMember> =
+            Profile::get_profiles(&connection, &user_ids)?
+                .into_iter()
+                .map(|profile| {
+                    let member = RoomMember {
+                        avatar_url: profile.avatar_url,
+                        display_name: profile.displayname,
+                    };
+
+                    (profile.id, member)
+                })
+                .collect();
 
-        let events = RoomMembership::get_events_by_room(&connection, room_id)?;
+        // Members who never set up a profile are listed without one.
+        for user_id in user_ids {
+            joined.entry(user_id).or_insert(RoomMember {
+                avatar_url: None,
+                display_name: None,
+            });
+        }
 
-        let response = MembersResponse { chunk: events };
+        let (joined_count, invited_count) = if include_counts {
+            (
+                Some(RoomMembership::count_by_room_and_state(
+                    &connection,
+                    &room_id,
+                    "join",
+                )?),
+                Some(RoomMembership::count_by_room_and_state(
+                    &connection,
+                    &room_id,
+                    "invite",
+                )?),
+            )
+        } else {
+            (None, None)
+        };
+
+        let response = JoinedMembersResponse {
+            joined,
+            joined_count,
+            invited_count,
+        };
 
         Ok(Response::with((Status::Ok, SerializableResponse(response))))
     }
@@ -48,6 +224,7 @@ impl Handler for Members {
 
 #[cfg(test)]
 mod tests {
+    use super::MEMBERS_DEFAULT_LIMIT;
     use crate::test::Test;
     use iron::status::Status;
 
@@ -74,4 +251,245 @@ mod tests {
         let chunk = chunk.as_array().unwrap();
         assert_eq!(chunk.len(), 1);
     }
+
+    #[test]
+    fn room_members_paginated() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        for _ in 0..3 {
+            let user = test.create_user();
+            assert_eq!(test.join_room(&user.token, &room_id).status, Status::Ok);
+        }
+
+        let dan = test.create_user();
+        assert_eq!(test.join_room(&dan.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&dan.token, &room_id).status, Status::Ok);
+
+        let mut members = Vec::new();
+        let mut from = None;
+
+        loop {
+            let mut room_members_path = format!(
+                "/_matrix/client/r0/rooms/{}/members?membership=join&limit=3&access_token={}",
+                room_id, carl.token
+            );
+            if let Some(from) = from {
+                room_members_path.push_str(&format!("&from={}", from));
+            }
+
+            let response = test.get(&room_members_path);
+            assert_eq!(response.status, Status::Ok);
+
+            let json = response.json();
+            let chunk = json.get("chunk").unwrap().as_array().unwrap();
+            assert!(chunk.len() <= 3);
+
+            for event in chunk {
+                let membership = event.pointer("/content/membership").unwrap();
+                assert_eq!(membership.as_str().unwrap(), "join");
+                members.push(event.get("sender").unwrap().as_str().unwrap().to_string());
+            }
+
+            from = match json.get("next_batch") {
+                Some(next_batch) => Some(next_batch.as_str().unwrap().to_string()),
+                None => break,
+            };
+        }
+
+        members.sort();
+        members.dedup();
+        assert_eq!(members.len(), 4);
+        assert!(!members.contains(&dan.id));
+    }
+
+    #[test]
+    fn room_members_default_limit() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        for _ in 0..MEMBERS_DEFAULT_LIMIT {
+            let user = test.create_user();
+            assert_eq!(test.join_room(&user.token, &room_id).status, Status::Ok);
+        }
+
+        let room_members_path = format!(
+            "/_matrix/client/r0/rooms/{}/members?access_token={}",
+            room_id, carl.token
+        );
+
+        let response = test.get(&room_members_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        let chunk = json.get("chunk").unwrap().as_array().unwrap();
+        assert_eq!(chunk.len() as u64, MEMBERS_DEFAULT_LIMIT);
+        assert!(json.get("next_batch").is_some());
+
+        let response = test.get(&format!("{}&limit={}", room_members_path, 5));
+        assert_eq!(response.status, Status::Ok);
+
+        let json = response.json();
+        let chunk = json.get("chunk").unwrap().as_array().unwrap();
+        assert_eq!(chunk.len(), 5);
+        assert!(json.get("next_batch").is_some());
+    }
+
+    #[test]
+    fn room_members_in_stable_order() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let mut expected = vec![carl.id.clone()];
+        for _ in 0..3 {
+            let user = test.create_user();
+            assert_eq!(test.join_room(&user.token, &room_id).status, Status::Ok);
+            expected.push(user.id);
+        }
+
+        let room_members_path = format!(
+            "/_matrix/client/r0/rooms/{}/members?access_token={}",
+            room_id, carl.token
+        );
+
+        for _ in 0..2 {
+            let response = test.get(&room_members_path);
+            assert_eq!(response.status, Status::Ok);
+
+            let members: Vec<&str> = response
+                .json()
+                .get("chunk")
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|event| event.get("state_key").unwrap().as_str().unwrap())
+                .collect();
+            assert_eq!(members, expected);
+        }
+    }
+
+    #[test]
+    fn room_members_forbidden_for_non_members() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/members?access_token={}",
+            room_id, bob.token
+        ));
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+    }
+
+    #[test]
+    fn room_members_after_leaving() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let carl = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+        assert_eq!(test.join_room(&carl.token, &room_id).status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/members?access_token={}",
+            room_id, bob.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let mut members: Vec<&str> = response
+            .json()
+            .get("chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event.get("state_key").unwrap().as_str().unwrap())
+            .collect();
+        members.sort();
+
+        // Carl joined after Bob left, so Bob cannot see him.
+        let mut expected = vec![alice.id.as_str(), bob.id.as_str()];
+        expected.sort();
+        assert_eq!(members, expected);
+    }
+
+    #[test]
+    fn joined_members() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let dan = test.create_user();
+        assert_eq!(test.join_room(&dan.token, &room_id).status, Status::Ok);
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+                dan.id, dan.token
+            ),
+            r#"{"displayname": "Dan"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let eve = test.create_user();
+        assert_eq!(
+            test.invite(&carl.token, &room_id, &eve.id).status,
+            Status::Ok
+        );
+
+        let frank = test.create_user();
+        assert_eq!(test.join_room(&frank.token, &room_id).status, Status::Ok);
+        assert_eq!(test.leave_room(&frank.token, &room_id).status, Status::Ok);
+
+        let joined_members_path = format!(
+            "/_matrix/client/r0/rooms/{}/joined_members?access_token={}",
+            room_id, carl.token
+        );
+
+        let response = test.get(&joined_members_path);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().get("joined_count").is_none());
+        assert!(response.json().get("invited_count").is_none());
+
+        let joined = response.json().get("joined").unwrap().as_object().unwrap();
+        assert_eq!(joined.len(), 2);
+        assert!(joined.get(&carl.id).is_some());
+        assert_eq!(
+            joined
+                .get(&dan.id)
+                .unwrap()
+                .get("display_name")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Dan"
+        );
+
+        let response = test.get(&format!("{}&include_counts=true", joined_members_path));
+        assert_eq!(response.status, Status::Ok);
+        let json = response.json();
+        assert_eq!(json.get("joined_count").unwrap().as_i64().unwrap(), 2);
+        assert_eq!(json.get("invited_count").unwrap().as_i64().unwrap(), 1);
+    }
+
+    #[test]
+    fn joined_members_without_membership() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let dan = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/joined_members?access_token={}",
+            room_id, dan.token
+        ));
+        assert_eq!(response.status, Status::Forbidden);
+    }
 }
@@ -1,24 +1,33 @@
 //! API endpoints for the 0.x.x version of the Matrix spec.
 
-pub use self::account::{AccountPassword, DeactivateAccount, PutAccountData, PutRoomAccountData};
+pub use self::account::{
+    AccountPassword, DeactivateAccount, GetAccountData, GetRoomAccountData, PutAccountData,
+    PutRoomAccountData, Whoami,
+};
+pub use self::admin::{GetUserRooms, WhoIs};
+pub use self::capabilities::Capabilities;
 pub use self::directory::{DeleteRoomAlias, GetRoomAlias, PutRoomAlias};
-pub use self::event_creation::{SendMessageEvent, StateMessageEvent};
-pub use self::filter::{GetFilter, PostFilter};
+pub use self::event_creation::{RedactEvent, SendMessageEvent, StateMessageEvent};
+pub use self::filter::{DeleteFilter, GetFilter, ListFilters, PostFilter};
 pub use self::join::{InviteToRoom, JoinRoom, JoinRoomWithIdOrAlias, KickFromRoom, LeaveRoom};
 pub use self::login::Login;
-pub use self::logout::Logout;
-pub use self::members::Members;
+pub use self::logout::{Logout, LogoutAll};
+pub use self::members::{JoinedMembers, Members};
 pub use self::presence::{GetPresenceList, GetPresenceStatus, PostPresenceList, PutPresenceStatus};
 pub use self::profile::{GetAvatarUrl, GetDisplayName, Profile, PutAvatarUrl, PutDisplayName};
 pub use self::pushers::{GetPushers, SetPushers};
+pub use self::receipts::PostReceipt;
 pub use self::registration::Register;
 pub use self::room_creation::CreateRoom;
-pub use self::room_info::RoomState;
+pub use self::room_info::{GetStateEvent, RoomState};
 pub use self::sync::Sync;
 pub use self::tags::{DeleteTag, GetTags, PutTag};
+pub use self::typing::PutTyping;
 pub use self::versions::Versions;
 
 mod account;
+mod admin;
+mod capabilities;
 mod directory;
 mod event_creation;
 mod filter;
@@ -26,12 +35,15 @@ mod join;
 mod login;
 mod logout;
 mod members;
+mod pagination;
 mod presence;
 mod profile;
 mod pushers;
+mod receipts;
 mod registration;
 mod room_creation;
 mod room_info;
 mod sync;
 mod tags;
+mod typing;
 mod versions;
@@ -0,0 +1,47 @@
+//! Helpers for paginated endpoints.
+
+use std::cmp;
+
+use crate::error::ApiError;
+
+/// Parse the value of a `limit` query parameter.
+///
+/// Returns `default` if the parameter was not given and clamps larger values to `max`. Values
+/// that are not non-negative integers are rejected with `M_INVALID_PARAM`.
+pub fn parse_limit(value: Option<&str>, default: u64, max: u64) -> Result<u64, ApiError> {
+    let limit = match value {
+        Some(value) => value
+            .parse::<u64>()
+            .map_err(|_| ApiError::invalid_param("limit", "Must be a non-negative integer."))?,
+        None => default,
+    };
+
+    Ok(cmp::min(limit, max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_limit;
+
+    #[test]
+    fn default_when_missing() {
+        assert_eq!(parse_limit(None, 10, 100).unwrap(), 10);
+    }
+
+    #[test]
+    fn given_value() {
+        assert_eq!(parse_limit(Some("42"), 10, 100).unwrap(), 42);
+    }
+
+    #[test]
+    fn clamped_to_max() {
+        assert_eq!(parse_limit(Some("1000"), 10, 100).unwrap(), 100);
+    }
+
+    #[test]
+    fn invalid_values() {
+        assert!(parse_limit(Some("-1"), 10, 100).is_err());
+        assert!(parse_limit(Some("ten"), 10, 100).is_err());
+        assert!(parse_limit(Some("1.5"), 10, 100).is_err());
+    }
+}
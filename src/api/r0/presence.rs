@@ -11,10 +11,10 @@ use crate::db::DB;
 use crate::error::ApiError;
 use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, UserIdParam};
 use crate::models::presence_list::PresenceList;
-use crate::models::presence_status::{get_now, PresenceStatus};
+use crate::models::presence_status::PresenceStatus;
 use crate::models::room_membership::RoomMembership;
 use crate::models::user::User;
-use crate::modifier::{EmptyResponse, SerializableResponse};
+use crate::modifier::{empty_response, SerializableResponse};
 
 /// The PUT `/presence/:user_id/status` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -26,7 +26,7 @@ struct PutPresenceStatusRequest {
     /// The status message to attach to this state.
     status_msg: Option<String>,
     /// The new presence state. One of: ["online", "offline", "unavailable"]
-    presence: PresenceState,
+    presence: String,
 }
 
 middleware_chain!(
@@ -36,17 +36,9 @@ middleware_chain!(
 
 impl Handler for PutPresenceStatus {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
-
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
+
+        let user = User::from_request(request)?;
 
         let put_presence_status_request =
             match request.get::<bodyparser::Struct<PutPresenceStatusRequest>>() {
@@ -64,15 +56,40 @@ impl Handler for PutPresenceStatus {
             return Err(IronError::from(error));
         }
 
+        if let Some(ref status_msg) = put_presence_status_request.status_msg {
+            if status_msg.chars().count() > config.max_status_msg_length {
+                Err(ApiError::invalid_param(
+                    "status_msg",
+                    &format!(
+                        "Must not be longer than {} characters.",
+                        config.max_status_msg_length
+                    ),
+                ))?;
+            }
+        }
+
+        let presence: PresenceState =
+            put_presence_status_request.presence.parse().map_err(|_| {
+                ApiError::invalid_param(
+                    "presence",
+                    "Must be one of \"online\", \"offline\" or \"unavailable\".",
+                )
+            })?;
+
+        // The request is still validated, so clients notice mistakes regardless of the setting.
+        if !config.presence_enabled {
+            return Ok(empty_response());
+        }
+
         PresenceStatus::upsert(
             &connection,
             &config.domain,
             &user_id,
-            Some(put_presence_status_request.presence),
+            Some(presence),
             put_presence_status_request.status_msg,
         )?;
 
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        Ok(empty_response())
     }
 }
 
@@ -89,28 +106,23 @@ struct GetPresenceStatusResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     status_msg: Option<String>,
     /// Whether the user is currently active.
-    currently_active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currently_active: Option<bool>,
     /// The length of time in milliseconds since an action was performed by this user.
-    last_active_ago: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_active_ago: Option<u64>,
     /// This user's presence. One of: ["online", "offline", "unavailable"]
     presence: PresenceState,
 }
 
 impl Handler for GetPresenceStatus {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
-
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
+
+        let user = User::from_request(request)?;
 
         let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
 
         if user.id != user_id {
             let rooms = RoomMembership::find_common_rooms(&connection, &user.id, &user_id, "join")?;
@@ -122,26 +134,34 @@ impl Handler for GetPresenceStatus {
             }
         }
 
-        let status = match PresenceStatus::find_by_uid(&connection, &user_id)? {
-            Some(status) => status,
-            None => Err(ApiError::not_found(
-                "The given user_id does not correspond to an presence status".to_string(),
-            ))?,
+        let status = if config.presence_enabled {
+            PresenceStatus::find_by_uid(&connection, &user_id)?
+        } else {
+            None
         };
 
-        let presence_state: PresenceState = status
-            .presence
-            .parse()
-            .expect("Database insert should ensure a PresenceState");
-
-        let now = get_now();
-        let last_active_ago = now - status.updated_at.0;
-
-        let response = GetPresenceStatusResponse {
-            status_msg: status.status_msg,
-            currently_active: PresenceState::Online == presence_state,
-            last_active_ago,
-            presence: presence_state,
+        let response = match status {
+            Some(status) => {
+                let presence_state: PresenceState = status
+                    .presence
+                    .parse()
+                    .expect("Database insert should ensure a PresenceState");
+
+                GetPresenceStatusResponse {
+                    last_active_ago: Some(status.last_active_ago()),
+                    status_msg: status.status_msg,
+                    currently_active: Some(PresenceState::Online == presence_state),
+                    presence: presence_state,
+                }
+            }
+            // Users that never set their presence are offline, as are all users if presence is
+            // disabled.
+            None => GetPresenceStatusResponse {
+                status_msg: None,
+                currently_active: None,
+                last_active_ago: None,
+                presence: PresenceState::Offline,
+            },
         };
 
         Ok(Response::with((Status::Ok, SerializableResponse(response))))
@@ -173,17 +193,9 @@ impl Handler for PostPresenceList {
                 Ok(Some(request)) => request,
                 Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
             };
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
-
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
+
+        let user = User::from_request(request)?;
 
         let connection = DB::from_request(request)?;
 
@@ -202,7 +214,7 @@ impl Handler for PostPresenceList {
             put_presence_list_request.drop,
         )?;
 
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        Ok(empty_response())
     }
 }
 
@@ -214,15 +226,27 @@ middleware_chain!(GetPresenceList, [UserIdParam, AccessTokenAuth]);
 
 impl Handler for GetPresenceList {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
+
+        let user = User::from_request(request)?;
+
+        if user_id != user.id {
+            let error = ApiError::unauthorized(
+                "The given user_id does not correspond to the authenticated user".to_string(),
+            );
+
+            return Err(IronError::from(error));
+        }
 
         let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
 
-        let (_, events) = PresenceList::find_events_by_uid(&connection, &user_id, None)?;
+        // Like in sync, no presence events are returned if presence is disabled.
+        let events = if config.presence_enabled {
+            PresenceList::find_events_by_uid(&connection, &user_id, None)?.1
+        } else {
+            Vec::new()
+        };
 
         Ok(Response::with((Status::Ok, SerializableResponse(events))))
     }
@@ -258,6 +282,49 @@ mod tests {
         assert_eq!(json.get("presence").unwrap().as_str().unwrap(), "online");
     }
 
+    #[test]
+    fn all_presence_states() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let presence_status_path = format!(
+            "/_matrix/client/r0/presence/{}/status?access_token={}",
+            alice.id, alice.token
+        );
+
+        for presence in &["online", "offline", "unavailable"] {
+            test.update_presence(
+                &alice.token,
+                &alice.id,
+                &format!(r#"{{"presence":"{}"}}"#, presence),
+            );
+
+            let response = test.get(&presence_status_path);
+            assert_eq!(response.status, Status::Ok);
+            assert_eq!(
+                response.json().get("presence").unwrap().as_str().unwrap(),
+                *presence
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_presence_state() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let presence_status_path = format!(
+            "/_matrix/client/r0/presence/{}/status?access_token={}",
+            alice.id, alice.token
+        );
+        let response = test.put(&presence_status_path, r#"{"presence":"busy"}"#);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+
     #[test]
     fn presence_status_message() {
         let test = Test::new();
@@ -289,6 +356,34 @@ mod tests {
         assert_eq!(json.get("status_msg").unwrap().as_str().unwrap(), "Oscar!");
     }
 
+    #[test]
+    fn presence_status_message_too_long() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let presence_status_path = format!(
+            "/_matrix/client/r0/presence/{}/status?access_token={}",
+            alice.id, alice.token
+        );
+        let body = format!(
+            r#"{{"presence":"online", "status_msg": "{}"}}"#,
+            "a".repeat(257)
+        );
+        let response = test.put(&presence_status_path, &body);
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+
+        let body = format!(
+            r#"{{"presence":"online", "status_msg": "{}"}}"#,
+            "a".repeat(256)
+        );
+        let response = test.put(&presence_status_path, &body);
+        assert_eq!(response.status, Status::Ok);
+    }
+
     #[test]
     fn forbidden_get_presence_status_no_shared_room() {
         let test = Test::new();
@@ -306,7 +401,7 @@ mod tests {
     }
 
     #[test]
-    fn not_found_presence_status() {
+    fn default_offline_presence_status() {
         let test = Test::new();
         let alice = test.create_user();
 
@@ -315,7 +410,51 @@ mod tests {
             alice.id, alice.token
         );
         let response = test.get(&presence_status_path);
-        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body, r#"{"presence":"offline"}"#);
+    }
+
+    #[test]
+    fn presence_disabled() {
+        let test = Test::with_config(|config| config.presence_enabled = false);
+        let alice = test.create_user();
+
+        let response = test.update_presence(&alice.token, &alice.id, r#"{"presence":"online"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let presence_status_path = format!(
+            "/_matrix/client/r0/presence/{}/status?access_token={}",
+            alice.id, alice.token
+        );
+        let response = test.get(&presence_status_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body, r#"{"presence":"offline"}"#);
+    }
+
+    #[test]
+    fn presence_list_with_presence_disabled() {
+        let test = Test::with_config(|config| config.presence_enabled = false);
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let response = test.join_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Ok);
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id, alice.token
+        );
+        let response = test.post(
+            &presence_list_path,
+            &format!(r#"{{"invite":["{}"], "drop": []}}"#, bob.id),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.update_presence(&bob.token, &bob.id, r#"{"presence":"online"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&presence_list_path);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().as_array().unwrap().is_empty());
     }
 
     #[test]
@@ -419,6 +558,33 @@ mod tests {
         assert_eq!(response.status, Status::Forbidden);
     }
 
+    #[test]
+    fn forbidden_get_presence_list_of_other_user() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        let response = test.join_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Ok);
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id, alice.token
+        );
+        let response = test.post(
+            &presence_list_path,
+            &format!(r#"{{"invite":["{}"], "drop": []}}"#, bob.id),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id, bob.token
+        );
+        let response = test.get(&presence_list_path);
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
     #[test]
     fn invitee_does_not_exist_presence_list() {
         let test = Test::new();
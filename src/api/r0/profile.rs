@@ -7,10 +7,10 @@ use iron::{Chain, Handler, IronError, IronResult, Plugin, Request, Response};
 use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
-use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, UserIdParam};
+use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RateLimit, UserIdParam};
 use crate::models::profile::Profile as DataProfile;
 use crate::models::user::User;
-use crate::modifier::{EmptyResponse, SerializableResponse};
+use crate::modifier::{empty_response, SerializableResponse};
 
 /// The `/profile/:user_id` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -20,33 +20,29 @@ pub struct Profile;
 #[derive(Clone, Debug, Serialize)]
 struct ProfileResponse {
     /// The user's avatar URL if they have set one, otherwise not present.
+    #[serde(skip_serializing_if = "Option::is_none")]
     avatar_url: Option<String>,
     /// The user's display name if they have set one, otherwise not present.
+    #[serde(skip_serializing_if = "Option::is_none")]
     displayname: Option<String>,
 }
 
-middleware_chain!(Profile, [UserIdParam, AccessTokenAuth]);
+middleware_chain!(Profile, [UserIdParam]);
 
 impl Handler for Profile {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user");
-
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
 
         let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
 
         let profile = DataProfile::find_by_uid(&connection, &user_id)?;
 
         let response = match profile {
             Some(profile) => ProfileResponse {
-                avatar_url: profile.avatar_url,
+                avatar_url: profile
+                    .avatar_url
+                    .or_else(|| config.default_avatar_url.clone()),
                 displayname: profile.displayname,
             },
             None => Err(ApiError::not_found(format!(
@@ -70,27 +66,22 @@ struct GetAvatarUrlResponse {
     avatar_url: String,
 }
 
-middleware_chain!(GetAvatarUrl, [UserIdParam, AccessTokenAuth]);
+middleware_chain!(GetAvatarUrl, [UserIdParam]);
 
 impl Handler for GetAvatarUrl {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user");
-
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
 
         let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
 
         let profile = DataProfile::find_by_uid(&connection, &user_id)?;
 
         let response = match profile {
-            Some(profile) => match profile.avatar_url {
+            Some(profile) => match profile
+                .avatar_url
+                .or_else(|| config.default_avatar_url.clone())
+            {
                 Some(avatar_url) => GetAvatarUrlResponse { avatar_url },
                 None => Err(ApiError::not_found(format!(
                     "No avatar_url found for {}",
@@ -118,7 +109,15 @@ struct PutAvatarUrlRequest {
     avatar_url: Option<String>,
 }
 
-middleware_chain!(PutAvatarUrl, [JsonRequest, UserIdParam, AccessTokenAuth]);
+middleware_chain!(
+    PutAvatarUrl,
+    [
+        JsonRequest,
+        UserIdParam,
+        AccessTokenAuth,
+        RateLimit::per_hour(|config| config.profile_change_rate_limit).exempt_admins()
+    ]
+);
 
 impl Handler for PutAvatarUrl {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
@@ -130,17 +129,9 @@ impl Handler for PutAvatarUrl {
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
 
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = User::from_request(request)?;
 
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
 
         if user_id != user.id {
             let error = ApiError::unauthorized(
@@ -150,16 +141,23 @@ impl Handler for PutAvatarUrl {
             return Err(IronError::from(error));
         }
 
+        if !config.allow_avatar_change {
+            let error = ApiError::unauthorized(
+                "Changing the avatar URL is disabled on this homeserver".to_string(),
+            );
+
+            return Err(IronError::from(error));
+        }
+
         DataProfile::update_avatar_url(
             &connection,
             &config.domain,
-            user_id.clone(),
+            config.presence_enabled,
+            user_id,
             avatar_url_request.avatar_url,
         )?;
 
-        DataProfile::update_memberships(&connection, &config.domain, user_id.clone())?;
-
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        Ok(empty_response())
     }
 }
 
@@ -174,20 +172,11 @@ struct GetDisplayNameResponse {
     displayname: String,
 }
 
-middleware_chain!(GetDisplayName, [UserIdParam, AccessTokenAuth]);
+middleware_chain!(GetDisplayName, [UserIdParam]);
 
 impl Handler for GetDisplayName {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user");
-
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
 
         let connection = DB::from_request(request)?;
 
@@ -222,7 +211,15 @@ struct PutDisplayNameRequest {
     displayname: Option<String>,
 }
 
-middleware_chain!(PutDisplayName, [JsonRequest, UserIdParam, AccessTokenAuth]);
+middleware_chain!(
+    PutDisplayName,
+    [
+        JsonRequest,
+        UserIdParam,
+        AccessTokenAuth,
+        RateLimit::per_hour(|config| config.profile_change_rate_limit).exempt_admins()
+    ]
+);
 
 impl Handler for PutDisplayName {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
@@ -234,17 +231,9 @@ impl Handler for PutDisplayName {
         let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
 
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = User::from_request(request)?;
 
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
 
         if user_id != user.id {
             let error = ApiError::unauthorized(
@@ -254,24 +243,41 @@ impl Handler for PutDisplayName {
             return Err(IronError::from(error));
         }
 
+        if !config.allow_displayname_change {
+            let error = ApiError::unauthorized(
+                "Changing the display name is disabled on this homeserver".to_string(),
+            );
+
+            return Err(IronError::from(error));
+        }
+
         DataProfile::update_displayname(
             &connection,
             &config.domain,
-            user_id.clone(),
+            config.presence_enabled,
+            user_id,
             displayname_request.displayname,
         )?;
 
-        DataProfile::update_memberships(&connection, &config.domain, user_id.clone())?;
-
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        Ok(empty_response())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
+
+    use crate::models::filter::ContentFilter;
     use crate::query::SyncOptions;
     use crate::test::Test;
     use iron::status::Status;
+    use ruma_identifiers::UserId;
+    use serde_json::from_str;
+
+    /// A filter that includes the user's own presence, which sync leaves out by default.
+    fn own_presence_filter(user_id: &str) -> ContentFilter {
+        from_str(&format!(r#"{{"presence":{{"senders":["{}"]}}}}"#, user_id)).unwrap()
+    }
 
     #[test]
     fn get_new_user_profile() {
@@ -326,6 +332,145 @@ mod tests {
         );
     }
 
+    #[test]
+    fn get_unset_displayname() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/profile/{}/displayname",
+            carl.id
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_FOUND"
+        );
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            format!("No displayname found for {}", carl.id)
+        );
+    }
+
+    #[test]
+    fn get_unset_avatar_url() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/profile/{}/avatar_url",
+            carl.id
+        ));
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_FOUND"
+        );
+        assert_eq!(
+            response.json().get("error").unwrap().as_str().unwrap(),
+            format!("No avatar_url found for {}", carl.id)
+        );
+    }
+
+    #[test]
+    fn get_default_avatar_url() {
+        let test = Test::with_config(|config| {
+            config.default_avatar_url = Some("mxc://ruma.test/default".to_string())
+        });
+        let carl = test.create_user();
+        let alice = test.create_user();
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/profile/{}/avatar_url",
+            carl.id
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("avatar_url").unwrap().as_str().unwrap(),
+            "mxc://ruma.test/default"
+        );
+
+        let response = test.get(&format!("/_matrix/client/r0/profile/{}", carl.id));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("avatar_url").unwrap().as_str().unwrap(),
+            "mxc://ruma.test/default"
+        );
+
+        let avatar_url_path = format!(
+            "/_matrix/client/r0/profile/{}/avatar_url?access_token={}",
+            alice.id, alice.token
+        );
+        assert_eq!(
+            test.put(
+                &avatar_url_path,
+                r#"{"avatar_url": "mxc://ruma.test/alice"}"#
+            )
+            .status,
+            Status::Ok
+        );
+
+        let response = test.get(&format!("/_matrix/client/r0/profile/{}", alice.id));
+        assert_eq!(
+            response.json().get("avatar_url").unwrap().as_str().unwrap(),
+            "mxc://ruma.test/alice"
+        );
+    }
+
+    #[test]
+    fn get_profile_fields_without_access_token() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let avatar_url_path = format!(
+            "/_matrix/client/r0/profile/{}/avatar_url?access_token={}",
+            carl.id, carl.token
+        );
+        assert!(test
+            .put(
+                &avatar_url_path,
+                r#"{"avatar_url": "mxc://ruma.test/avatar"}"#
+            )
+            .status
+            .is_success());
+
+        let displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            carl.id, carl.token
+        );
+        assert!(test
+            .put(&displayname_path, r#"{"displayname": "Carl"}"#)
+            .status
+            .is_success());
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/profile/{}/avatar_url",
+            carl.id
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("avatar_url").unwrap().as_str().unwrap(),
+            "mxc://ruma.test/avatar"
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/profile/{}/displayname",
+            carl.id
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .get("displayname")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Carl"
+        );
+    }
+
     #[test]
     fn put_avatar_url() {
         let test = Test::new();
@@ -384,6 +529,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn put_displayname_disabled() {
+        let test = Test::with_config(|config| config.allow_displayname_change = false);
+        let carl = test.create_user();
+
+        let put_displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            carl.id, carl.token
+        );
+        let response = test.put(&put_displayname_path, r#"{"displayname": "Bogus"}"#);
+
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+    }
+
     #[test]
     fn put_displayname_unauthorized() {
         let test = Test::new();
@@ -477,6 +640,37 @@ mod tests {
         );
     }
 
+    #[test]
+    fn get_profile_with_only_displayname() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            carl.id, carl.token
+        );
+
+        assert!(test
+            .put(&displayname_path, r#"{"displayname": "Carl"}"#)
+            .status
+            .is_success());
+
+        // Profiles are public, so no access token is required.
+        let response = test.get(&format!("/_matrix/client/r0/profile/{}", carl.id));
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .get("displayname")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Carl"
+        );
+        assert!(response.json().get("avatar_url").is_none());
+    }
+
     #[test]
     fn get_profile_non_existent_user() {
         let test = Test::new();
@@ -525,7 +719,7 @@ mod tests {
         test.update_presence(&carl.token, &carl.id, r#"{"presence":"online"}"#);
 
         let options = SyncOptions {
-            filter: None,
+            filter: Some(own_presence_filter(&carl.id)),
             since: None,
             full_state: false,
             set_presence: None,
@@ -566,7 +760,7 @@ mod tests {
             .is_success());
 
         let options = SyncOptions {
-            filter: None,
+            filter: Some(own_presence_filter(&carl.id)),
             since: Some(next_batch),
             full_state: false,
             set_presence: None,
@@ -596,6 +790,158 @@ mod tests {
         );
     }
 
+    #[test]
+    fn member_events_after_changed_displayname() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let first_room_id = test.create_public_room(&alice.token);
+        let second_room_id = test.create_public_room(&alice.token);
+        assert_eq!(
+            test.join_room(&bob.token, &first_room_id).status,
+            Status::Ok
+        );
+        assert_eq!(
+            test.join_room(&bob.token, &second_room_id).status,
+            Status::Ok
+        );
+
+        let room_options = format!(r#"{{"invite": ["{}"]}}"#, bob.id);
+        let invited_room_id = test.create_room_with_params(&alice.token, &room_options);
+
+        let put_displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            bob.id, bob.token
+        );
+        assert!(test
+            .put(&put_displayname_path, r#"{"displayname": "Bob"}"#)
+            .status
+            .is_success());
+
+        let bob_member_event = |room_id: &str| {
+            let members_path = format!(
+                "/_matrix/client/r0/rooms/{}/members?access_token={}",
+                room_id, alice.token
+            );
+            let response = test.get(&members_path);
+            assert_eq!(response.status, Status::Ok);
+
+            response
+                .json()
+                .get("chunk")
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|event| event.get("state_key").unwrap().as_str().unwrap() == bob.id)
+                .unwrap()
+                .clone()
+        };
+
+        for room_id in &[&first_room_id, &second_room_id] {
+            let event = bob_member_event(room_id);
+            assert_eq!(
+                event
+                    .pointer("/content/membership")
+                    .unwrap()
+                    .as_str()
+                    .unwrap(),
+                "join"
+            );
+            assert_eq!(
+                event
+                    .pointer("/content/displayname")
+                    .unwrap()
+                    .as_str()
+                    .unwrap(),
+                "Bob"
+            );
+        }
+
+        let event = bob_member_event(&invited_room_id);
+        assert_eq!(
+            event
+                .pointer("/content/membership")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "invite"
+        );
+    }
+
+    #[test]
+    fn changed_displayname_in_many_rooms_is_synced() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_ids: Vec<String> = (0..3).map(|_| test.create_room(&carl.token)).collect();
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            carl.id, carl.token
+        );
+        let response = test.post(
+            &presence_list_path,
+            &format!(r#"{{"invite":["{}"], "drop": []}}"#, carl.id),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: Some(own_presence_filter(&carl.id)),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+        let next_batch = Test::get_next_batch(&response);
+
+        let put_displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            carl.id, carl.token
+        );
+        assert!(test
+            .put(&put_displayname_path, r#"{"displayname": "Carl"}"#)
+            .status
+            .is_success());
+
+        let options = SyncOptions {
+            filter: Some(own_presence_filter(&carl.id)),
+            since: Some(next_batch),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+
+        for room_id in &room_ids {
+            let events = response
+                .json()
+                .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+                .unwrap()
+                .as_array()
+                .unwrap();
+            assert_eq!(events.len(), 1);
+            assert_eq!(
+                events[0]
+                    .pointer("/content/displayname")
+                    .unwrap()
+                    .as_str()
+                    .unwrap(),
+                "Carl"
+            );
+        }
+
+        let events = response
+            .json()
+            .pointer("/presence/events")
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].get("sender").unwrap().as_str().unwrap(), carl.id);
+    }
+
     #[test]
     fn update_presence_after_changed_displayname() {
         let test = Test::new();
@@ -623,7 +969,7 @@ mod tests {
         test.update_presence(&carl.token, &carl.id, r#"{"presence":"online"}"#);
 
         let options = SyncOptions {
-            filter: None,
+            filter: Some(own_presence_filter(&carl.id)),
             since: None,
             full_state: false,
             set_presence: None,
@@ -664,7 +1010,7 @@ mod tests {
             .is_success());
 
         let options = SyncOptions {
-            filter: None,
+            filter: Some(own_presence_filter(&carl.id)),
             since: Some(next_batch),
             full_state: false,
             set_presence: None,
@@ -693,4 +1039,58 @@ mod tests {
             "Bogus"
         );
     }
+
+    #[test]
+    fn profile_changes_rate_limited() {
+        let test = Test::with_config(|config| config.profile_change_rate_limit = 2);
+        let carl = test.create_user();
+
+        let displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            carl.id, carl.token
+        );
+
+        assert_eq!(
+            test.put(&displayname_path, r#"{"displayname": "Carl"}"#)
+                .status,
+            Status::Ok
+        );
+        assert_eq!(
+            test.put(&displayname_path, r#"{"displayname": "Carlos"}"#)
+                .status,
+            Status::Ok
+        );
+
+        let response = test.put(&displayname_path, r#"{"displayname": "Charles"}"#);
+        assert_eq!(response.status, Status::TooManyRequests);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_LIMIT_EXCEEDED"
+        );
+    }
+
+    #[test]
+    fn profile_changes_of_admins_not_rate_limited() {
+        let test = Test::with_config(|config| {
+            config.admins = vec![UserId::try_from("@admin:ruma.test").unwrap()];
+            config.profile_change_rate_limit = 1;
+        });
+        let response = test.register_user(r#"{"username": "admin", "password": "secret"}"#);
+        let access_token = response
+            .json()
+            .get("access_token")
+            .unwrap()
+            .as_str()
+            .unwrap();
+
+        let displayname_path = format!(
+            "/_matrix/client/r0/profile/@admin:ruma.test/displayname?access_token={}",
+            access_token
+        );
+
+        for displayname in &["Admin", "Administrator"] {
+            let body = format!(r#"{{"displayname": "{}"}}"#, displayname);
+            assert_eq!(test.put(&displayname_path, &body).status, Status::Ok);
+        }
+    }
 }
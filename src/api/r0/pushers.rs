@@ -9,7 +9,7 @@ use crate::error::{ApiError, MapApiError};
 use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
 use crate::models::pusher::{Pusher, PusherOptions};
 use crate::models::user::User;
-use crate::modifier::{EmptyResponse, SerializableResponse};
+use crate::modifier::{empty_response, SerializableResponse};
 
 /// The GET `/pushers` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -26,11 +26,7 @@ middleware_chain!(GetPushers, [AccessTokenAuth]);
 
 impl Handler for GetPushers {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = User::from_request(request)?;
 
         let connection = DB::from_request(request)?;
 
@@ -52,11 +48,7 @@ middleware_chain!(SetPushers, [JsonRequest, AccessTokenAuth]);
 
 impl Handler for SetPushers {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = User::from_request(request)?;
 
         let value: Value = match request.get::<bodyparser::Struct<Value>>() {
             Ok(Some(request)) => request,
@@ -83,7 +75,7 @@ impl Handler for SetPushers {
             Pusher::upsert(&connection, &user.id, &pusher_options)?;
         }
 
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        Ok(empty_response())
     }
 }
 
@@ -0,0 +1,204 @@
+//! Endpoints for receipts.
+
+use iron::{Chain, Handler, IronResult, Request, Response};
+use router::Router;
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{AccessTokenAuth, EventIdParam, MiddlewareChain, RoomIdParam};
+use crate::models::event::Event;
+use crate::models::receipt::Receipt;
+use crate::models::room_membership::RoomMembership;
+use crate::models::user::User;
+use crate::modifier::empty_response;
+
+/// The POST `/rooms/:room_id/receipt/:receipt_type/:event_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct PostReceipt;
+
+middleware_chain!(PostReceipt, [RoomIdParam, EventIdParam, AccessTokenAuth]);
+
+impl Handler for PostReceipt {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = User::from_request(request)?;
+
+        let room_id = RoomIdParam::from_request(request)?;
+
+        let event_id = EventIdParam::from_request(request)?;
+
+        let receipt_type = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .find("receipt_type")
+            .unwrap_or("")
+            .to_string();
+
+        if receipt_type != "m.read" {
+            Err(ApiError::invalid_param(
+                "receipt_type",
+                "Only m.read receipts are supported.",
+            ))?;
+        }
+
+        let connection = DB::from_request(request)?;
+
+        RoomMembership::require_membership(&connection, &room_id, &user.id, &["join"])?;
+
+        match Event::find(&connection, &event_id)? {
+            Some(ref event) if event.room_id.as_ref() == Some(&room_id) => (),
+            _ => Err(ApiError::not_found(
+                "The event was not found in this room.".to_string(),
+            ))?,
+        }
+
+        Receipt::upsert(&connection, &room_id, &user.id, &receipt_type, &event_id)?;
+
+        Ok(empty_response())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use crate::query::{Batch, SyncOptions};
+    use crate::test::Test;
+
+    /// Sync and return the content of the `m.receipt` event of the room, if included, and the
+    /// next batch.
+    fn receipts_since(
+        test: &Test,
+        access_token: &str,
+        room_id: &str,
+        since: Option<Batch>,
+    ) -> (Option<Value>, Batch) {
+        let options = SyncOptions {
+            filter: None,
+            since,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(access_token, options);
+
+        let content = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/ephemeral/events", room_id))
+            .and_then(|events| {
+                events
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|event| event.get("type").unwrap().as_str().unwrap() == "m.receipt")
+            })
+            .map(|event| event.get("content").unwrap().clone());
+
+        (content, Test::get_next_batch(&response))
+    }
+
+    #[test]
+    fn read_receipt() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let mut event_ids = Vec::new();
+        for txn_id in 1..=2 {
+            let response = test.send_message(&alice.token, &room_id, "Hi", txn_id);
+            let event_id = response
+                .json()
+                .get("event_id")
+                .unwrap()
+                .as_str()
+                .unwrap()
+                .to_string();
+
+            let receipt_path = format!(
+                "/_matrix/client/r0/rooms/{}/receipt/m.read/{}?access_token={}",
+                room_id, event_id, alice.token
+            );
+            let response = test.post(&receipt_path, "{}");
+            assert_eq!(response.status, Status::Ok);
+
+            event_ids.push(event_id);
+        }
+
+        // Bob sees only Alice's latest receipt, which replaced the first one.
+        let (content, next_batch) = receipts_since(&test, &bob.token, &room_id, None);
+        let content = content.unwrap();
+        assert!(content.get(&event_ids[0]).is_none());
+        assert!(content
+            .pointer(&format!("/{}/m.read/{}/ts", event_ids[1], alice.id))
+            .unwrap()
+            .is_i64());
+
+        // Receipts are only sent again once they change.
+        let (content, _) = receipts_since(&test, &bob.token, &room_id, Some(next_batch));
+        assert!(content.is_none());
+    }
+
+    #[test]
+    fn read_receipt_without_membership() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let receipt_path = format!(
+            "/_matrix/client/r0/rooms/{}/receipt/m.read/{}?access_token={}",
+            room_id, event_id, bob.token
+        );
+        let response = test.post(&receipt_path, "{}");
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_FORBIDDEN"
+        );
+    }
+
+    #[test]
+    fn read_receipt_for_event_of_other_room() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+        let other_room_id = test.create_room(&alice.token);
+
+        let response = test.send_message(&alice.token, &other_room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let receipt_path = format!(
+            "/_matrix/client/r0/rooms/{}/receipt/m.read/{}?access_token={}",
+            room_id, event_id, alice.token
+        );
+        let response = test.post(&receipt_path, "{}");
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_FOUND"
+        );
+    }
+
+    #[test]
+    fn unsupported_receipt_type() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_room(&alice.token);
+
+        let response = test.send_message(&alice.token, &room_id, "Hi", 1);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let receipt_path = format!(
+            "/_matrix/client/r0/rooms/{}/receipt/m.unknown/{}?access_token={}",
+            room_id, event_id, alice.token
+        );
+        let response = test.post(&receipt_path, "{}");
+        assert_eq!(response.status, Status::BadRequest);
+    }
+}
@@ -1,11 +1,11 @@
 //! Endpoints for user account registration.
 
-use std::convert::TryFrom;
 use std::fmt::{Formatter, Result as FmtResult};
 
 use bodyparser;
+use diesel::pg::PgConnection;
 use iron::{status, Chain, Handler, IronError, IronResult, Plugin, Request, Response};
-use ruma_identifiers::UserId;
+use ruma_identifiers::{RoomIdOrAliasId, UserId};
 use serde::de::{Deserialize, Deserializer, Error as SerdeError, Visitor};
 
 use crate::config::Config;
@@ -14,7 +14,9 @@ use crate::db::DB;
 use crate::error::ApiError;
 use crate::middleware::{JsonRequest, MiddlewareChain};
 use crate::models::profile::Profile;
-use crate::models::user::{NewUser, User};
+use crate::models::room_alias::RoomAlias;
+use crate::models::room_membership::RoomMembership;
+use crate::models::user::{make_user_id, NewUser, User};
 use crate::modifier::SerializableResponse;
 
 /// The `/register` endpoint.
@@ -107,13 +109,13 @@ impl Handler for Register {
 
         let new_user = NewUser {
             id: match registration_request.username {
-                Some(username) => {
-                    UserId::try_from(format!("@{}:{}", username, &config.domain).as_ref())
-                        .map_err(ApiError::from)?
-                }
+                Some(username) => make_user_id(&username, &config.domain)?,
                 None => UserId::new(&config.domain).map_err(ApiError::from)?,
             },
-            password_hash: hash_password(&registration_request.password)?,
+            password_hash: hash_password(
+                &registration_request.password,
+                config.password_hash_cost,
+            )?,
         };
 
         let connection = DB::from_request(request)?;
@@ -127,14 +129,32 @@ impl Handler for Register {
         let (user, access_token) =
             User::create(&connection, &new_user, &config.macaroon_secret_key)?;
 
+        let displayname = if config.default_displayname_from_localpart {
+            Some(user.id.localpart().to_string())
+        } else {
+            None
+        };
+
         let new_profile = Profile {
             id: user.id.clone(),
             avatar_url: None,
-            displayname: None,
+            displayname,
         };
 
         Profile::create(&connection, &new_profile)?;
 
+        // A misconfigured room must not prevent users from registering.
+        for room_id_or_alias in &config.auto_join_rooms {
+            if let Err(error) =
+                auto_join_room(&connection, &config.domain, &user.id, room_id_or_alias)
+            {
+                warn!(
+                    "Failed to automatically join {} to {}: {}",
+                    user.id, room_id_or_alias, error
+                );
+            }
+        }
+
         let response = RegistrationResponse {
             access_token: access_token.value,
             home_server: config.domain.clone(),
@@ -145,10 +165,30 @@ impl Handler for Register {
     }
 }
 
+/// Join a newly registered user to one of the rooms from the `auto_join_rooms` configuration.
+fn auto_join_room(
+    connection: &PgConnection,
+    domain: &str,
+    user_id: &UserId,
+    room_id_or_alias: &RoomIdOrAliasId,
+) -> Result<(), ApiError> {
+    let room_id = match room_id_or_alias {
+        RoomIdOrAliasId::RoomId(id) => id.clone(),
+        RoomIdOrAliasId::RoomAliasId(alias) => RoomAlias::find_by_alias(connection, alias)?.room_id,
+    };
+
+    RoomMembership::join(connection, domain, room_id, user_id)?;
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
+
     use crate::test::Test;
     use iron::status::Status;
+    use ruma_identifiers::RoomIdOrAliasId;
 
     #[test]
     fn minimum_input_parameters() {
@@ -207,6 +247,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn invalid_username() {
+        let test = Test::new();
+
+        for username in &["Carl", "car l", "carl:ruma.test"] {
+            let response = test.register_user(&format!(
+                r#"{{"username": "{}", "password": "secret"}}"#,
+                username
+            ));
+
+            assert_eq!(response.status, Status::BadRequest);
+            assert_eq!(
+                response.json().get("errcode").unwrap().as_str().unwrap(),
+                "M_INVALID_USERNAME"
+            );
+        }
+    }
+
     #[test]
     fn user_already_registered() {
         let test = Test::new();
@@ -227,4 +285,101 @@ mod tests {
             "This user_id already exists"
         );
     }
+
+    #[test]
+    fn default_displayname_from_localpart() {
+        let test = Test::with_config(|config| config.default_displayname_from_localpart = true);
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get("/_matrix/client/r0/profile/@carl:ruma.test/displayname");
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .get("displayname")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "carl"
+        );
+    }
+
+    #[test]
+    fn no_default_displayname() {
+        let test = Test::new();
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get("/_matrix/client/r0/profile/@carl:ruma.test/displayname");
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn auto_join_rooms() {
+        let test = Test::with_config(|config| {
+            config.auto_join_rooms = vec![
+                RoomIdOrAliasId::try_from("#welcome:ruma.test").unwrap(),
+                RoomIdOrAliasId::try_from("#missing:ruma.test").unwrap(),
+            ]
+        });
+        let alice = test.create_user();
+        let room_id = test.create_room_with_params(
+            &alice.token,
+            r#"{"visibility": "public", "room_alias_name": "welcome"}"#,
+        );
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let members_path = format!(
+            "/_matrix/client/r0/rooms/{}/members?membership=join&access_token={}",
+            room_id, alice.token
+        );
+        let response = test.get(&members_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let members: Vec<&str> = response
+            .json()
+            .get("chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event.get("state_key").unwrap().as_str().unwrap())
+            .collect();
+        assert!(members.contains(&"@carl:ruma.test"));
+    }
+
+    #[test]
+    fn auto_join_rooms_skips_private_rooms() {
+        let test = Test::with_config(|config| {
+            config.auto_join_rooms = vec![RoomIdOrAliasId::try_from("#staff:ruma.test").unwrap()]
+        });
+        let alice = test.create_user();
+        let room_id = test.create_room_with_params(
+            &alice.token,
+            r#"{"visibility": "private", "room_alias_name": "staff"}"#,
+        );
+
+        let response = test.register_user(r#"{"username": "carl", "password": "secret"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let members_path = format!(
+            "/_matrix/client/r0/rooms/{}/members?access_token={}",
+            room_id, alice.token
+        );
+        let response = test.get(&members_path);
+        assert_eq!(response.status, Status::Ok);
+        assert!(response
+            .json()
+            .get("chunk")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|event| event.get("state_key").unwrap().as_str().unwrap() != "@carl:ruma.test"));
+    }
 }
@@ -8,13 +8,15 @@ use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
 use ruma_events::stripped::StrippedState;
 use ruma_identifiers::{RoomId, UserId};
-use serde_json::{from_value, Value};
+use serde_json::{from_value, Map, Value};
 
 use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
-use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
-use crate::models::room::{CreationOptions, NewRoom, Room, RoomPreset, RoomVisibility};
+use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RateLimit};
+use crate::models::room::{
+    make_room_id, CreationOptions, NewRoom, Room, RoomPreset, RoomVisibility,
+};
 use crate::models::room_membership::{RoomMembership, RoomMembershipOptions};
 use crate::models::user::User;
 use crate::modifier::SerializableResponse;
@@ -33,6 +35,8 @@ struct CreateRoomRequest {
     pub initial_state: Option<Vec<StrippedState>>,
     /// A list of user IDs to invite to the room.
     pub invite: Option<Vec<UserId>>,
+    /// Whether the invites are for a direct chat with the creator.
+    pub is_direct: Option<bool>,
     /// Indicates the room's name.
     pub name: Option<String>,
     /// Convenience parameter for setting various default state events based on a preset.
@@ -51,24 +55,36 @@ struct CreationContent {
     /// Whether or not this room should participate in federation with other homeservers.
     #[serde(rename = "m.federate")]
     pub federate: Option<bool>,
+    /// Any other keys, e.g. `type` for spaces.
+    #[serde(flatten)]
+    pub other: Map<String, Value>,
 }
 
+/// Keys of the m.room.create content that are set by the server and can't be overridden.
+const RESERVED_CREATION_CONTENT_KEYS: &[&str] = &["creator", "room_version"];
+
 /// The body of the response for this API.
 #[derive(Debug, Serialize)]
 struct CreateRoomResponse {
     /// The fully qualified ID of the room that was created.
     room_id: RoomId,
+    /// The users from the `invite` list that could not be invited to the room.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failed_invites: Vec<UserId>,
 }
 
-middleware_chain!(CreateRoom, [JsonRequest, AccessTokenAuth]);
+middleware_chain!(
+    CreateRoom,
+    [
+        JsonRequest,
+        AccessTokenAuth,
+        RateLimit::new(|config| config.room_creation_rate_limit)
+    ]
+);
 
 impl Handler for CreateRoom {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = User::from_request(request)?;
 
         let create_room_request = extract_create_room_request(request, &user.id)?;
 
@@ -76,16 +92,31 @@ impl Handler for CreateRoom {
         let config = Config::from_request(request)?;
 
         let new_room = NewRoom {
-            id: RoomId::new(&config.domain).map_err(ApiError::from)?,
+            id: make_room_id(&config.domain)?,
             user_id: user.id,
             public: create_room_request
                 .visibility
                 .map_or(false, |v| v == RoomVisibility::Public),
         };
 
-        let federate = match create_room_request.creation_content {
-            Some(creation_content) => creation_content.federate.unwrap_or(true),
-            None => true,
+        let (federate, creation_content) = match create_room_request.creation_content {
+            Some(creation_content) => {
+                if let Some(key) = RESERVED_CREATION_CONTENT_KEYS
+                    .iter()
+                    .find(|key| creation_content.other.contains_key(**key))
+                {
+                    Err(ApiError::invalid_param(
+                        "creation_content",
+                        &format!("'{}' can't be overridden", key),
+                    ))?;
+                }
+
+                (
+                    creation_content.federate.unwrap_or(true),
+                    creation_content.other,
+                )
+            }
+            None => (true, Map::new()),
         };
 
         let preset = match create_room_request.preset {
@@ -101,32 +132,39 @@ impl Handler for CreateRoom {
 
         let creation_options = CreationOptions {
             alias: create_room_request.room_alias_name,
+            creation_content,
             federate: Some(federate),
             initial_state: create_room_request.initial_state,
             invite_list: create_room_request.invite,
+            is_direct: create_room_request.is_direct,
             name: create_room_request.name,
             preset,
             topic: create_room_request.topic,
         };
 
-        let room: Room = connection
-            .transaction::<Room, ApiError, _>(|| {
-                let room = Room::create(&connection, &new_room, &config.domain, &creation_options)?;
+        let (room, failed_invites) = connection
+            .transaction::<(Room, Vec<UserId>), ApiError, _>(|| {
+                let (room, failed_invites) =
+                    Room::create(&connection, &new_room, &config.domain, &creation_options)?;
 
                 let options = RoomMembershipOptions {
                     room_id: room.id.clone(),
                     user_id: room.user_id.clone(),
                     sender: room.user_id.clone(),
                     membership: "join".to_string(),
+                    is_direct: None,
                 };
 
                 RoomMembership::create(&connection, &config.domain, options)?;
 
-                Ok(room)
+                Ok((room, failed_invites))
             })
             .map_err(ApiError::from)?;
 
-        let response = CreateRoomResponse { room_id: room.id };
+        let response = CreateRoomResponse {
+            room_id: room.id,
+            failed_invites,
+        };
 
         Ok(Response::with((Status::Ok, SerializableResponse(response))))
     }
@@ -167,6 +205,8 @@ fn extract_create_room_request(
 
 #[cfg(test)]
 mod tests {
+    use std::str::from_utf8;
+
     use crate::test::Test;
     use iron::status::Status;
 
@@ -182,6 +222,111 @@ mod tests {
         assert!(response.json().get("room_id").unwrap().as_str().is_some());
     }
 
+    #[test]
+    fn rate_limited() {
+        let test = Test::with_config(|config| config.room_creation_rate_limit = 2);
+        let user = test.create_user();
+
+        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}", user.token);
+
+        assert_eq!(test.post(&create_room_path, "{}").status, Status::Ok);
+        assert_eq!(test.post(&create_room_path, "{}").status, Status::Ok);
+
+        let response = test.post(&create_room_path, "{}");
+        assert_eq!(response.status, Status::TooManyRequests);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_LIMIT_EXCEEDED"
+        );
+
+        let other_user = test.create_user();
+        let create_room_path = format!(
+            "/_matrix/client/r0/createRoom?access_token={}",
+            other_user.token
+        );
+        assert_eq!(test.post(&create_room_path, "{}").status, Status::Ok);
+    }
+
+    #[test]
+    fn rate_limited_with_retry_after() {
+        let test = Test::with_config(|config| config.room_creation_rate_limit = 1);
+        let user = test.create_user();
+
+        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}", user.token);
+
+        assert_eq!(test.post(&create_room_path, "{}").status, Status::Ok);
+
+        let response = test.post(&create_room_path, "{}");
+        assert_eq!(response.status, Status::TooManyRequests);
+
+        let retry_after_ms = response
+            .json()
+            .get("retry_after_ms")
+            .unwrap()
+            .as_u64()
+            .unwrap();
+        assert!(retry_after_ms > 0 && retry_after_ms <= 60_000);
+
+        let retry_after: u64 = response
+            .headers
+            .get_raw("Retry-After")
+            .and_then(|values| values.first())
+            .and_then(|value| from_utf8(value).ok())
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert_eq!(retry_after, (retry_after_ms + 999) / 1000);
+    }
+
+    #[test]
+    fn with_creation_content() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let room_id = test.create_room_with_params(
+            &user.token,
+            r#"{"creation_content": {"type": "m.space", "m.federate": false}}"#,
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id, user.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+
+        let create_event = response
+            .json()
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event.get("type").unwrap().as_str().unwrap() == "m.room.create")
+            .unwrap()
+            .clone();
+        let content = create_event.get("content").unwrap();
+        assert!(!content.get("m.federate").unwrap().as_bool().unwrap());
+        assert_eq!(content.get("creator").unwrap().as_str().unwrap(), user.id);
+    }
+
+    #[test]
+    fn with_reserved_creation_content_keys() {
+        let test = Test::new();
+        let user = test.create_user();
+        let other_user = test.create_user();
+
+        let create_room_path = format!("/_matrix/client/r0/createRoom?access_token={}", user.token);
+
+        let body = format!(
+            r#"{{"creation_content": {{"creator": "{}"}}}}"#,
+            other_user.id
+        );
+        let response = test.post(&create_room_path, &body);
+        assert_eq!(response.status, Status::BadRequest);
+
+        let body = r#"{"creation_content": {"room_version": "5"}}"#;
+        let response = test.post(&create_room_path, body);
+        assert_eq!(response.status, Status::BadRequest);
+    }
+
     #[test]
     fn with_room_alias() {
         let test = Test::new();
@@ -246,6 +391,67 @@ mod tests {
         );
     }
 
+    #[test]
+    fn public_visibility_is_joinable() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_id = test.create_room_with_params(&alice.token, r#"{"visibility": "public"}"#);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+    }
+
+    #[test]
+    fn public_chat_preset_is_joinable() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_id = test.create_room_with_params(&alice.token, r#"{"preset": "public_chat"}"#);
+
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+    }
+
+    #[test]
+    fn private_chat_preset_requires_invite() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        // The preset takes precedence over the visibility.
+        let room_id = test.create_room_with_params(
+            &alice.token,
+            r#"{"preset": "private_chat", "visibility": "public"}"#,
+        );
+
+        assert_eq!(
+            test.join_room(&bob.token, &room_id).status,
+            Status::Forbidden
+        );
+
+        assert_eq!(
+            test.invite(&alice.token, &room_id, &bob.id).status,
+            Status::Ok
+        );
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+    }
+
+    #[test]
+    fn trusted_private_chat_preset_requires_invite() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_id =
+            test.create_room_with_params(&alice.token, r#"{"preset": "trusted_private_chat"}"#);
+
+        assert_eq!(
+            test.join_room(&bob.token, &room_id).status,
+            Status::Forbidden
+        );
+    }
+
     #[test]
     fn with_invited_users() {
         let test = Test::new();
@@ -272,37 +478,39 @@ mod tests {
     #[test]
     fn with_unknown_invited_users() {
         let test = Test::new();
-        let _bob = test.create_user();
+        let bob = test.create_user();
         let alice = test.create_user();
 
-        let room_options = r#"{"visibility": "private",
-                               "invite": [
-                                   "@bob:ruma.test",
-                                   "@carl:ruma.test",
-                                   "@dan:ruma.test"
-                               ]}"#;
+        let room_options = format!(
+            r#"{{"visibility": "private",
+                 "invite": [
+                     "{}",
+                     "@carl:ruma.test",
+                     "@dan:ruma.test"
+                 ]}}"#,
+            bob.id
+        );
 
         let response = test.post(
             &format!("/_matrix/client/r0/createRoom?access_token={}", alice.token),
-            room_options,
-        );
-
-        assert_eq!(
-            response.json().get("errcode").unwrap().as_str().unwrap(),
-            "M_BAD_JSON"
+            &room_options,
         );
+        assert_eq!(response.status, Status::Ok);
 
-        let error = response
-            .json()
-            .get("error")
+        let json = response.json();
+        let room_id = json.get("room_id").unwrap().as_str().unwrap();
+        let mut failed_invites: Vec<&str> = json
+            .get("failed_invites")
             .unwrap()
-            .as_str()
+            .as_array()
             .unwrap()
-            .to_string();
+            .iter()
+            .map(|user_id| user_id.as_str().unwrap())
+            .collect();
+        failed_invites.sort();
 
-        assert!(error.starts_with("Unknown users in invite list:"));
-        assert!(error.contains("@carl:ruma.test"));
-        assert!(error.contains("@dan:ruma.test"));
+        assert_eq!(failed_invites, vec!["@carl:ruma.test", "@dan:ruma.test"]);
+        assert!(test.join_room(&bob.token, room_id).status.is_success());
     }
 
     #[test]
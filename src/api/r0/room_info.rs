@@ -1,14 +1,15 @@
-//! Endpoint for retrieving the state of a room.
+//! Endpoints for retrieving the state of a room.
 
-use std::convert::TryInto;
+use std::slice;
 
 use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Request, Response};
-use ruma_events::collections::all::StateEvent;
+use router::Router;
+use serde_json::{from_str, Value};
 
 use crate::db::DB;
 use crate::error::ApiError;
-use crate::middleware::{AccessTokenAuth, MiddlewareChain, RoomIdParam};
+use crate::middleware::{AccessTokenAuth, EventTypeParam, MiddlewareChain, RoomIdParam};
 use crate::models::event::Event;
 use crate::models::room::Room;
 use crate::models::room_membership::RoomMembership;
@@ -23,17 +24,9 @@ middleware_chain!(RoomState, [RoomIdParam, AccessTokenAuth]);
 
 impl Handler for RoomState {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = User::from_request(request)?;
 
-        let room_id = request
-            .extensions
-            .get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a room_id")
-            .clone();
+        let room_id = RoomIdParam::from_request(request)?;
 
         let connection = DB::from_request(request)?;
 
@@ -44,23 +37,21 @@ impl Handler for RoomState {
             ))?,
         };
 
-        let membership = RoomMembership::find(&connection, &room.id, &user.id)?;
+        let membership = RoomMembership::require_membership(
+            &connection,
+            &room.id,
+            &user.id,
+            &["join", "leave"],
+        )?;
 
-        if membership.is_none() {
-            Err(ApiError::unauthorized(
-                "The user is not a member of the room".to_string(),
-            ))?
-        }
-
-        let membership_state = membership.clone().unwrap().membership;
         let mut events = Vec::<Event>::new();
 
-        match membership_state.as_ref() {
+        match membership.membership.as_ref() {
             "join" => {
                 events.append(&mut Event::get_room_full_state(&connection, &room_id)?);
             }
             "leave" => {
-                let last_event = Event::find(&connection, &membership.unwrap().event_id)?
+                let last_event = Event::find(&connection, &membership.event_id)?
                     .expect("A room membership should be associated with an event");
 
                 events.append(&mut Event::get_room_state_events_until(
@@ -72,11 +63,7 @@ impl Handler for RoomState {
             _ => {}
         }
 
-        let mut state_events: Vec<StateEvent> = Vec::new();
-
-        for event in events {
-            state_events.push(event.try_into()?);
-        }
+        let state_events = Event::into_state_events(&connection, events)?;
 
         Ok(Response::with((
             Status::Ok,
@@ -85,6 +72,78 @@ impl Handler for RoomState {
     }
 }
 
+/// The `/rooms/:room_id/state/:event_type/:state_key` and `/rooms/:room_id/state/:event_type`
+/// endpoints.
+#[derive(Clone, Copy, Debug)]
+pub struct GetStateEvent;
+
+middleware_chain!(
+    GetStateEvent,
+    [RoomIdParam, EventTypeParam, AccessTokenAuth]
+);
+
+impl Handler for GetStateEvent {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = User::from_request(request)?;
+
+        let room_id = RoomIdParam::from_request(request)?;
+
+        let event_type = EventTypeParam::from_request(request)?.to_string();
+
+        let state_key = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .find("state_key")
+            .unwrap_or("")
+            .to_string();
+
+        let connection = DB::from_request(request)?;
+
+        let room = match Room::find(&connection, &room_id)? {
+            Some(room) => room,
+            None => Err(ApiError::unauthorized(
+                "The room was not found on this server".to_string(),
+            ))?,
+        };
+
+        let membership = RoomMembership::require_membership(
+            &connection,
+            &room.id,
+            &user.id,
+            &["join", "leave"],
+        )?;
+
+        // Users who left the room can only see its state from before they left.
+        let last_event = match membership.membership.as_ref() {
+            "leave" => Some(
+                Event::find(&connection, &membership.event_id)?
+                    .expect("A room membership should be associated with an event"),
+            ),
+            _ => None,
+        };
+
+        let mut event = match Event::find_room_state_event(
+            &connection,
+            &room.id,
+            &event_type,
+            &state_key,
+            last_event.as_ref(),
+        )? {
+            Some(event) => event,
+            None => Err(ApiError::not_found(
+                "The room has no state event with this type and state key.".to_string(),
+            ))?,
+        };
+
+        Event::apply_redactions(&connection, slice::from_mut(&mut event))?;
+
+        let content: Value = from_str(&event.content).map_err(ApiError::from)?;
+
+        Ok(Response::with((Status::Ok, SerializableResponse(content))))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::test::Test;
@@ -355,4 +414,246 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn single_state_event() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.topic",
+            r#"{"topic": "Topic for Alice"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+            room_id, alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("topic").unwrap().as_str().unwrap(),
+            "Topic for Alice"
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.member/{}?access_token={}",
+            room_id, alice.id, alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("membership").unwrap().as_str().unwrap(),
+            "join"
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.name?access_token={}",
+            room_id, alice.token
+        ));
+        assert_eq!(response.status, Status::NotFound);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+            room_id, bob.token
+        ));
+        assert_eq!(response.status, Status::Forbidden);
+    }
+
+    #[test]
+    fn single_state_event_is_the_latest() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = format!(r#"{{"invite": ["{}"], "topic": "First topic"}}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.topic",
+            r#"{"topic": "Second topic"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let topic_path = |access_token: &str| {
+            format!(
+                "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+                room_id, access_token
+            )
+        };
+
+        let response = test.get(&topic_path(&bob.token));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("topic").unwrap().as_str().unwrap(),
+            "Second topic"
+        );
+
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.topic",
+            r#"{"topic": "Third topic"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        // Bob only sees the state from before leaving.
+        let response = test.get(&topic_path(&bob.token));
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("topic").unwrap().as_str().unwrap(),
+            "Second topic"
+        );
+
+        let response = test.get(&topic_path(&alice.token));
+        assert_eq!(
+            response.json().get("topic").unwrap().as_str().unwrap(),
+            "Third topic"
+        );
+    }
+
+    #[test]
+    fn redacted_state_event() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let response = test.send_state_event(
+            &alice.token,
+            &room_id,
+            "m.room.topic",
+            r#"{"topic": "Secret topic"}"#,
+        );
+        assert_eq!(response.status, Status::Ok);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let redact_path = format!(
+            "/_matrix/client/r0/rooms/{}/redact/{}/1?access_token={}",
+            room_id, event_id, alice.token
+        );
+        assert_eq!(test.put(&redact_path, "{}").status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.topic?access_token={}",
+            room_id, alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.json().as_object().unwrap().is_empty());
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id, alice.token
+        ));
+        assert_eq!(response.status, Status::Ok);
+        let topic = response
+            .json()
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event.get("type").unwrap().as_str().unwrap() == "m.room.topic")
+            .unwrap();
+        assert!(topic
+            .get("content")
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            topic
+                .pointer("/unsigned/redacted_because/type")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "m.room.redaction"
+        );
+    }
+
+    #[test]
+    fn redacted_previous_state_in_prev_content() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            bob.id, bob.token
+        );
+        assert_eq!(
+            test.put(&displayname_path, r#"{"displayname": "Bob"}"#)
+                .status,
+            Status::Ok
+        );
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state/m.room.member/{}?access_token={}",
+            room_id, bob.id, alice.token
+        ));
+        assert_eq!(
+            response
+                .json()
+                .get("displayname")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Bob"
+        );
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id, alice.token
+        ));
+        let join_event_id = response
+            .json()
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event.get("state_key").unwrap().as_str().unwrap() == bob.id)
+            .unwrap()
+            .get("event_id")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let redact_path = format!(
+            "/_matrix/client/r0/rooms/{}/redact/{}/1?access_token={}",
+            room_id, join_event_id, alice.token
+        );
+        assert_eq!(test.put(&redact_path, "{}").status, Status::Ok);
+        assert_eq!(test.leave_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.get(&format!(
+            "/_matrix/client/r0/rooms/{}/state?access_token={}",
+            room_id, alice.token
+        ));
+        let leave_event = response
+            .json()
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|event| event.get("state_key").unwrap().as_str().unwrap() == bob.id)
+            .unwrap()
+            .clone();
+        assert_eq!(
+            leave_event
+                .pointer("/unsigned/prev_content/membership")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "join"
+        );
+        assert!(leave_event
+            .pointer("/unsigned/prev_content/displayname")
+            .is_none());
+    }
 }
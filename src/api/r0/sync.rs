@@ -1,6 +1,9 @@
 //! Endpoints for syncing.
+use std::cmp;
 use std::error::Error;
 use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
 use std::u64;
 
 use iron::status::Status;
@@ -13,11 +16,19 @@ use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
 use crate::middleware::{AccessTokenAuth, MiddlewareChain};
+use crate::models::access_token::AccessToken;
 use crate::models::user::User;
 use crate::modifier::SerializableResponse;
 use crate::query::{self, Batch, SyncOptions};
 
+/// How long to wait between checks for new events while a sync request is long-polling.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
 /// The `/sync` endpoint.
+///
+/// Incremental syncs without any updates wait up to `timeout` milliseconds, capped by the
+/// configured `max_sync_timeout`, for new events before returning. A waiting request keeps one of
+/// Iron's fixed number of worker threads busy for that time.
 #[derive(Clone, Copy, Debug)]
 pub struct Sync;
 
@@ -25,13 +36,10 @@ middleware_chain!(Sync, [AccessTokenAuth]);
 
 impl Handler for Sync {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user = User::from_request(request)?;
+
+        let access_token = AccessToken::from_request(request)?;
 
-        let connection = DB::from_request(request)?;
         let config = Config::from_request(request)?;
 
         let url: Url = request.url.clone().into();
@@ -91,7 +99,42 @@ impl Handler for Sync {
             timeout,
         };
 
-        let response = query::Sync::sync(&connection, &config.domain, &user, options)?;
+        // Initial and full state syncs always return everything right away.
+        let wait = if options.since.is_some() && !options.full_state {
+            cmp::min(options.timeout, config.max_sync_timeout)
+        } else {
+            0
+        };
+        let deadline = Instant::now() + Duration::from_millis(wait);
+
+        if config.presence_enabled {
+            let connection = DB::from_request(request)?;
+
+            query::Sync::update_presence(&connection, &config.domain, &user, options.set_presence)?;
+        }
+
+        let response = loop {
+            // The connection goes back to the pool between checks instead of being held while
+            // waiting.
+            let response = {
+                let connection = DB::from_request(request)?;
+
+                query::Sync::sync(
+                    &connection,
+                    config.presence_enabled,
+                    &user,
+                    &access_token,
+                    options.clone(),
+                )?
+            };
+
+            let now = Instant::now();
+            if !response.is_empty() || now >= deadline {
+                break response;
+            }
+
+            thread::sleep(cmp::min(POLL_INTERVAL, deadline - now));
+        };
 
         Ok(Response::with((Status::Ok, SerializableResponse(response))))
     }
@@ -100,6 +143,10 @@ impl Handler for Sync {
 #[cfg(test)]
 mod tests {
     use std::convert::TryFrom;
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     use crate::test::Test;
     use iron::status::Status;
@@ -108,7 +155,7 @@ mod tests {
     use serde_json::from_str;
 
     use crate::models::filter::ContentFilter;
-    use crate::query::SyncOptions;
+    use crate::query::{Batch, SyncOptions};
 
     #[test]
     fn sync_without_new_events() {
@@ -314,6 +361,247 @@ mod tests {
         );
     }
 
+    #[test]
+    fn message_in_joined_room_is_included_in_an_incremental_sync() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let dan = test.create_user();
+        assert_eq!(test.join_room(&dan.token, &room_id).status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&dan.token, options);
+        assert!(response
+            .json()
+            .pointer(&format!("/rooms/join/{}", room_id))
+            .is_some());
+        let since = Test::get_next_batch(&response);
+
+        let response = test.send_message(&carl.token, &room_id, "Hi Dan", 1);
+        assert_eq!(response.status, Status::Ok);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(since),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&dan.token, options);
+        let events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            EventId::try_from(events[0].get("event_id").unwrap().as_str().unwrap())
+                .unwrap()
+                .opaque_id(),
+            event_id
+        );
+        assert_eq!(events[0].get("sender").unwrap().as_str().unwrap(), carl.id);
+    }
+
+    #[test]
+    fn limited_timeline_has_prev_batch() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        for i in 0..5 {
+            let response = test.send_message(&carl.token, &room_id, &format!("Hi {}", i), i);
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{"room":{"timeline":{"limit":3}}}"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+        let next_batch =
+            Batch::from_str(response.json().get("next_batch").unwrap().as_str().unwrap()).unwrap();
+        let timeline = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline", room_id))
+            .unwrap();
+
+        assert_eq!(timeline.get("limited").unwrap().as_bool().unwrap(), true);
+        assert_eq!(timeline.get("events").unwrap().as_array().unwrap().len(), 3);
+
+        let prev_batch =
+            Batch::from_str(timeline.get("prev_batch").unwrap().as_str().unwrap()).unwrap();
+        assert!(prev_batch.room_key > 0);
+        assert!(prev_batch.room_key < next_batch.room_key);
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{"room":{"timeline":{"limit":100}}}"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+        let timeline = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline", room_id))
+            .unwrap();
+
+        assert_eq!(timeline.get("limited").unwrap().as_bool().unwrap(), false);
+        assert_eq!(timeline.get("prev_batch").unwrap().as_str().unwrap(), "");
+    }
+
+    #[test]
+    fn timeline_events_have_an_age() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.send_message(&carl.token, &room_id, "Hi", 1);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+        let events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+
+        let message = events
+            .iter()
+            .find(|e| e.get("type").unwrap().as_str().unwrap() == "m.room.message")
+            .unwrap();
+        let age = message.pointer("/unsigned/age").unwrap().as_i64().unwrap();
+
+        assert!(age >= 0);
+        assert!(age < 60_000);
+    }
+
+    #[test]
+    fn transaction_id_only_returned_to_sender() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        assert_eq!(test.join_room(&bob.token, &room_id).status, Status::Ok);
+
+        let response = test.send_message(&carl.token, &room_id, "Hi", 1);
+        assert_eq!(response.status, Status::Ok);
+
+        let find_message_unsigned = |access_token: &str| {
+            let options = SyncOptions {
+                filter: None,
+                since: None,
+                full_state: false,
+                set_presence: None,
+                timeout: 0,
+            };
+            let response = test.sync(access_token, options);
+            let events = response
+                .json()
+                .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+                .unwrap()
+                .as_array()
+                .unwrap()
+                .clone();
+
+            events
+                .into_iter()
+                .find(|e| e.get("type").unwrap().as_str().unwrap() == "m.room.message")
+                .unwrap()
+                .get("unsigned")
+                .unwrap()
+                .clone()
+        };
+
+        let unsigned = find_message_unsigned(&carl.token);
+        assert_eq!(
+            unsigned.get("transaction_id").unwrap().as_str().unwrap(),
+            "1"
+        );
+
+        let unsigned = find_message_unsigned(&bob.token);
+        assert!(unsigned.get("transaction_id").is_none());
+    }
+
+    #[test]
+    fn redacted_event_in_timeline() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.send_message(&carl.token, &room_id, "Oops", 1);
+        assert_eq!(response.status, Status::Ok);
+        let event_id = response.json().get("event_id").unwrap().as_str().unwrap();
+
+        let redact_path = format!(
+            "/_matrix/client/r0/rooms/{}/redact/{}/1?access_token={}",
+            room_id, event_id, carl.token
+        );
+        let response = test.put(&redact_path, r#"{"reason": "Typo"}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+        let events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+
+        let message = events
+            .iter()
+            .find(|e| e.get("type").unwrap().as_str().unwrap() == "m.room.message")
+            .unwrap();
+        let message_id = message.get("event_id").unwrap().as_str().unwrap();
+        assert_eq!(EventId::try_from(message_id).unwrap().opaque_id(), event_id);
+        assert!(message
+            .get("content")
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .is_empty());
+
+        let redacted_because = message.pointer("/unsigned/redacted_because").unwrap();
+        assert_eq!(
+            redacted_because.get("type").unwrap().as_str().unwrap(),
+            "m.room.redaction"
+        );
+        assert_eq!(
+            redacted_because.get("redacts").unwrap().as_str().unwrap(),
+            message_id
+        );
+        assert_eq!(
+            redacted_because
+                .pointer("/content/reason")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Typo"
+        );
+    }
+
     /// [https://github.com/matrix-org/sytest/blob/0eba37fc567d65f0a005090548c8df4d0e43775f/tests/31sync/04timeline.pl#L223]
     #[test]
     fn syncing_a_new_room_with_a_large_timeline_limit_isnt_limited() {
@@ -479,6 +767,48 @@ mod tests {
         );
     }
 
+    #[test]
+    fn sync_invite_is_direct() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let room_options = format!(r#"{{"invite": ["{}"], "is_direct": true}}"#, bob.id);
+        let room_id = test.create_room_with_params(&alice.token, &room_options);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+
+        let response = test.sync(&bob.token, options);
+        assert_eq!(response.status, Status::Ok);
+
+        let invited_state_events = response
+            .json()
+            .pointer(&format!("/rooms/invite/{}/invite_state/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+
+        let invite_event = invited_state_events
+            .iter()
+            .find(|e| {
+                e.get("type").unwrap().as_str().unwrap() == "m.room.member"
+                    && e.pointer("/content/membership").unwrap().as_str().unwrap() == "invite"
+            })
+            .unwrap();
+
+        assert!(invite_event
+            .pointer("/content/is_direct")
+            .unwrap()
+            .as_bool()
+            .unwrap());
+    }
+
     #[test]
     fn sync_invited_room_state() {
         let test = Test::new();
@@ -739,6 +1069,40 @@ mod tests {
         }
     }
 
+    #[test]
+    fn include_leave_toggles_left_rooms() {
+        let test = Test::new();
+        let (_, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+
+        let response = test.join_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Ok);
+        let response = test.leave_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Ok);
+
+        for &(include_leave, expected) in &[("false", false), ("true", true)] {
+            let filter = format!(r#"{{"room":{{"include_leave":{}}}}}"#, include_leave);
+            let options = SyncOptions {
+                filter: Some(from_str(&filter).unwrap()),
+                since: None,
+                full_state: false,
+                set_presence: None,
+                timeout: 0,
+            };
+
+            let response = test.sync(&bob.token, options);
+            assert_eq!(response.status, Status::Ok);
+
+            let leave_rooms = response
+                .json()
+                .pointer("/rooms/leave")
+                .unwrap()
+                .as_object()
+                .unwrap();
+            assert_eq!(leave_rooms.contains_key(&room_id), expected);
+        }
+    }
+
     #[test]
     fn sync_left_room_timeline() {
         let test = Test::new();
@@ -911,27 +1275,15 @@ mod tests {
     }
 
     #[test]
-    fn initial_state() {
-        let test = Test::new();
-        let user = test.create_user();
-
-        let sync_path = format!("/_matrix/client/r0/sync?access_token={}", user.token,);
-
-        let response = test.get(&sync_path);
-        assert_eq!(response.status, Status::Ok);
-        let rooms = response.json().get("rooms").unwrap();
-        let join = rooms.get("join").unwrap();
-        assert!(join.is_object());
-        assert_eq!(join.as_object().unwrap().len(), 0);
-    }
-
-    #[test]
-    fn initial_state_find_joined_rooms() {
+    fn full_state_for_multiple_rooms() {
         let test = Test::new();
-        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let alice = test.create_user();
 
-        let response = test.send_message(&carl.token, &room_id, "Hi Test", 1);
-        assert_eq!(response.status, Status::Ok);
+        let room_ids: Vec<String> = (1..=3)
+            .map(|n| {
+                test.create_room_with_params(&alice.token, &format!(r#"{{"name": "Room {}"}}"#, n))
+            })
+            .collect();
 
         let options = SyncOptions {
             filter: None,
@@ -940,36 +1292,139 @@ mod tests {
             set_presence: None,
             timeout: 0,
         };
-        let response = test.sync(&carl.token, options);
-        assert_eq!(response.status, Status::Ok);
-        let events = response
-            .json()
-            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
-            .unwrap();
-        assert!(events.is_array());
-        assert_eq!(events.as_array().unwrap().len(), 6);
-    }
 
-    #[test]
-    fn basic_since_state() {
-        let test = Test::new();
-        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let response = test.sync(&alice.token, options);
+        let next_batch = Test::get_next_batch(&response);
 
         let options = SyncOptions {
             filter: None,
-            since: None,
-            full_state: false,
+            since: Some(next_batch),
+            full_state: true,
             set_presence: None,
             timeout: 0,
         };
-        let response = test.sync(&carl.token, options);
-        let next_batch = Test::get_next_batch(&response);
 
-        test.send_message(&carl.token, &room_id, "test 1", 1);
-        assert_eq!(response.status, Status::Ok);
+        let response = test.sync(&alice.token, options);
 
-        let options = SyncOptions {
-            filter: None,
+        for (n, room_id) in room_ids.iter().enumerate() {
+            let state_events = response
+                .json()
+                .pointer(&format!("/rooms/join/{}/state/events", room_id))
+                .unwrap()
+                .as_array()
+                .unwrap();
+
+            assert!(!state_events.is_empty());
+
+            for e in state_events.iter() {
+                assert_eq!(e.get("room_id").unwrap().as_str().unwrap(), room_id);
+
+                if e.get("type").unwrap().as_str().unwrap() == "m.room.name" {
+                    assert_eq!(
+                        e.pointer("/content/name").unwrap().as_str().unwrap(),
+                        format!("Room {}", n + 1)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn room_filter_only_includes_selected_rooms() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let room_a = test.create_room(&alice.token);
+        let room_b = test.create_room(&alice.token);
+
+        let options = SyncOptions {
+            filter: Some(from_str(&format!(r#"{{"room":{{"rooms":["{}"]}}}}"#, room_a)).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        let join = response.json().pointer("/rooms/join").unwrap();
+
+        assert!(join.get(&room_a).is_some());
+        assert!(join.get(&room_b).is_none());
+
+        let options = SyncOptions {
+            filter: Some(
+                from_str(&format!(r#"{{"room":{{"not_rooms":["{}"]}}}}"#, room_a)).unwrap(),
+            ),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        let join = response.json().pointer("/rooms/join").unwrap();
+
+        assert!(join.get(&room_a).is_none());
+        assert!(join.get(&room_b).is_some());
+    }
+
+    #[test]
+    fn initial_state() {
+        let test = Test::new();
+        let user = test.create_user();
+
+        let sync_path = format!("/_matrix/client/r0/sync?access_token={}", user.token,);
+
+        let response = test.get(&sync_path);
+        assert_eq!(response.status, Status::Ok);
+        let rooms = response.json().get("rooms").unwrap();
+        let join = rooms.get("join").unwrap();
+        assert!(join.is_object());
+        assert_eq!(join.as_object().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn initial_state_find_joined_rooms() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.send_message(&carl.token, &room_id, "Hi Test", 1);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+        assert_eq!(response.status, Status::Ok);
+        let events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap();
+        assert!(events.is_array());
+        assert_eq!(events.as_array().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn basic_since_state() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+        let next_batch = Test::get_next_batch(&response);
+
+        test.send_message(&carl.token, &room_id, "test 1", 1);
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
             since: Some(next_batch),
             full_state: false,
             set_presence: None,
@@ -1108,6 +1563,172 @@ mod tests {
         assert_eq!(array.len(), 0);
     }
 
+    #[test]
+    fn repeated_sync_does_not_repeat_presence() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let response = test.join_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Ok);
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id, alice.token
+        );
+        let response = test.post(
+            &presence_list_path,
+            &format!(r#"{{"invite":["{}"], "drop": []}}"#, bob.id),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        test.sync(&bob.token, options.clone());
+
+        let response = test.sync(&alice.token, options.clone());
+        let senders: Vec<_> = response
+            .json()
+            .pointer("/presence/events")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|event| event.get("sender").unwrap().as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(senders, vec![bob.id.clone()]);
+        let since = Test::get_next_batch(&response);
+
+        // Bob is still online, so syncing again doesn't change his presence.
+        test.sync(&bob.token, options);
+
+        let options = SyncOptions {
+            filter: None,
+            since: Some(since),
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        assert!(response
+            .json()
+            .pointer("/presence/events")
+            .map_or(true, |events| events.as_array().unwrap().is_empty()));
+    }
+
+    #[test]
+    fn presence_filter_with_zero_limit_excludes_presence() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let response = test.join_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Ok);
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id, alice.token
+        );
+        let response = test.post(
+            &presence_list_path,
+            &format!(r#"{{"invite":["{}"], "drop": []}}"#, bob.id),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        test.update_presence(&bob.token, &bob.id, r#"{"presence":"online"}"#);
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{"presence":{"limit":0}}"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        let array = response
+            .json()
+            .pointer("/presence/events")
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(array.len(), 0);
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{"presence":{"limit":1}}"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        let array = response
+            .json()
+            .pointer("/presence/events")
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(array.len(), 1);
+    }
+
+    #[test]
+    fn own_presence_is_excluded() {
+        let test = Test::new();
+        let (alice, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let bob = test.create_user();
+        let response = test.join_room(&bob.token, &room_id);
+        assert_eq!(response.status, Status::Ok);
+
+        let presence_list_path = format!(
+            "/_matrix/client/r0/presence/list/{}?access_token={}",
+            alice.id, alice.token
+        );
+        let response = test.post(
+            &presence_list_path,
+            &format!(r#"{{"invite":["{}", "{}"], "drop": []}}"#, alice.id, bob.id),
+        );
+        assert_eq!(response.status, Status::Ok);
+
+        test.update_presence(&bob.token, &bob.id, r#"{"presence":"online"}"#);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        let array = response
+            .json()
+            .pointer("/presence/events")
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(array.len(), 1);
+        assert_eq!(array[0].get("sender").unwrap().as_str().unwrap(), bob.id);
+
+        let filter = format!(r#"{{"presence":{{"senders":["{}"]}}}}"#, alice.id);
+        let options = SyncOptions {
+            filter: Some(from_str(&filter).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        let array = response
+            .json()
+            .pointer("/presence/events")
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(array.len(), 1);
+        assert_eq!(array[0].get("sender").unwrap().as_str().unwrap(), alice.id);
+    }
+
     #[test]
     fn invalid_since() {
         let test = Test::new();
@@ -1167,4 +1788,191 @@ mod tests {
         ));
         assert_eq!(response.status, Status::BadRequest);
     }
+
+    #[test]
+    fn account_data_filtered_by_types() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        for (data_type, content) in &[
+            ("m.direct", r#"{"@bob:ruma.test": ["!abc:ruma.test"]}"#),
+            ("org.example.config", r#"{"key": "value"}"#),
+        ] {
+            let account_data_path = format!(
+                "/_matrix/client/r0/user/{}/account_data/{}?access_token={}",
+                alice.id, data_type, alice.token
+            );
+            let response = test.put(&account_data_path, content);
+            assert_eq!(response.status, Status::Ok);
+        }
+
+        let options = SyncOptions {
+            filter: Some(from_str(r#"{"account_data":{"types":["m.direct"]}}"#).unwrap()),
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&alice.token, options);
+        let array = response
+            .json()
+            .pointer("/account_data/events")
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(array.len(), 1);
+        assert_eq!(array[0].get("type").unwrap().as_str().unwrap(), "m.direct");
+    }
+
+    #[test]
+    fn long_polling_returns_when_an_event_arrives() {
+        let test = Arc::new(Test::new());
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+        let since = Test::get_next_batch(&response);
+
+        let sender = {
+            let test = Arc::clone(&test);
+            let token = carl.token.clone();
+            let room_id = room_id.clone();
+
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                test.send_message(&token, &room_id, "Hi", 1)
+            })
+        };
+
+        let started = Instant::now();
+        let options = SyncOptions {
+            filter: None,
+            since: Some(since),
+            full_state: false,
+            set_presence: None,
+            timeout: 10_000,
+        };
+        let response = test.sync(&carl.token, options);
+        assert!(started.elapsed() < Duration::from_millis(10_000));
+
+        let sent = sender.join().unwrap();
+        assert_eq!(sent.status, Status::Ok);
+        let event_id = sent.json().get("event_id").unwrap().as_str().unwrap();
+
+        let events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/timeline/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            EventId::try_from(events[0].get("event_id").unwrap().as_str().unwrap())
+                .unwrap()
+                .opaque_id(),
+            event_id
+        );
+    }
+
+    #[test]
+    fn long_polling_returns_when_a_receipt_arrives() {
+        let test = Arc::new(Test::new());
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+        let alice = test.create_user();
+        assert_eq!(test.join_room(&alice.token, &room_id).status, Status::Ok);
+
+        let response = test.send_message(&carl.token, &room_id, "Hi", 1);
+        let event_id = response
+            .json()
+            .get("event_id")
+            .unwrap()
+            .as_str()
+            .unwrap()
+            .to_string();
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+        let since = Test::get_next_batch(&response);
+
+        let sender = {
+            let test = Arc::clone(&test);
+            let receipt_path = format!(
+                "/_matrix/client/r0/rooms/{}/receipt/m.read/{}?access_token={}",
+                room_id, event_id, alice.token
+            );
+
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(200));
+                test.post(&receipt_path, "{}")
+            })
+        };
+
+        let started = Instant::now();
+        let options = SyncOptions {
+            filter: None,
+            since: Some(since),
+            full_state: false,
+            set_presence: None,
+            timeout: 10_000,
+        };
+        let response = test.sync(&carl.token, options);
+        assert!(started.elapsed() < Duration::from_millis(10_000));
+        assert_eq!(sender.join().unwrap().status, Status::Ok);
+
+        let events = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/ephemeral/events", room_id))
+            .unwrap()
+            .as_array()
+            .unwrap();
+        assert!(events
+            .iter()
+            .any(|event| event.get("type").unwrap().as_str().unwrap() == "m.receipt"));
+    }
+
+    #[test]
+    fn long_polling_is_capped_by_max_sync_timeout() {
+        let test = Test::with_config(|config| config.max_sync_timeout = 200);
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let options = SyncOptions {
+            filter: None,
+            since: None,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(&carl.token, options);
+        let since = Test::get_next_batch(&response);
+
+        let started = Instant::now();
+        let options = SyncOptions {
+            filter: None,
+            since: Some(since),
+            full_state: false,
+            set_presence: None,
+            timeout: 10_000,
+        };
+        let response = test.sync(&carl.token, options);
+        let elapsed = started.elapsed();
+
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_millis(10_000));
+        assert!(response
+            .json()
+            .pointer(&format!("/rooms/join/{}", room_id))
+            .is_none());
+    }
 }
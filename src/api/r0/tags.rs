@@ -1,10 +1,8 @@
 //! Endpoints for tags.
-use std::collections::HashMap;
-
 use bodyparser;
 use iron::status::Status;
 use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
-use ruma_events::tag::TagInfo;
+use serde::Serializer;
 use serde_json::Value;
 
 use crate::db::DB;
@@ -12,9 +10,9 @@ use crate::error::ApiError;
 use crate::middleware::{
     AccessTokenAuth, JsonRequest, MiddlewareChain, RoomIdParam, TagParam, UserIdParam,
 };
-use crate::models::tags::RoomTag;
+use crate::models::tags::{validate_order, RoomTag, TagInfo};
 use crate::models::user::User;
-use crate::modifier::{EmptyResponse, SerializableResponse};
+use crate::modifier::{empty_response, SerializableResponse};
 
 /// The GET `/user/:user_id/rooms/:room_id/tags` endpoint.
 #[derive(Clone, Copy, Debug)]
@@ -25,27 +23,24 @@ middleware_chain!(GetTags, [UserIdParam, RoomIdParam, AccessTokenAuth]);
 /// The body of the response for this API.
 #[derive(Debug, Serialize)]
 pub struct TagsResponse {
-    /// A map of tag names to information about the tag.
-    tags: HashMap<String, TagInfo>,
+    /// A map of tag names to information about the tag, sorted by the tags' order.
+    #[serde(serialize_with = "serialize_ordered_map")]
+    tags: Vec<(String, TagInfo)>,
+}
+
+/// Serialize tags as a JSON object whose keys keep the order of the tags.
+fn serialize_ordered_map<S>(tags: &[(String, TagInfo)], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_map(tags.iter().map(|(tag, info)| (tag, info)))
 }
 
 impl Handler for GetTags {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
-        let room_id = request
-            .extensions
-            .get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a RoomId")
-            .clone();
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
+        let room_id = RoomIdParam::from_request(request)?;
+        let user = User::from_request(request)?;
 
         // Check if the given user_id corresponds to the authenticated user.
         if user_id != user.id {
@@ -81,26 +76,10 @@ middleware_chain!(
 
 impl Handler for PutTag {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
-        let room_id = request
-            .extensions
-            .get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a RoomId")
-            .clone();
-        let tag = request
-            .extensions
-            .get::<TagParam>()
-            .expect("TagParam should ensure a tag")
-            .clone();
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
+        let room_id = RoomIdParam::from_request(request)?;
+        let tag = TagParam::from_request(request)?;
+        let user = User::from_request(request)?;
 
         // Check if the given user_id corresponds to the authenticated user.
         if user_id != user.id {
@@ -110,7 +89,10 @@ impl Handler for PutTag {
         }
 
         let content = match request.get::<bodyparser::Struct<Value>>() {
-            Ok(Some(content)) => content.to_string(),
+            Ok(Some(content)) => {
+                validate_order(&content)?;
+                content.to_string()
+            }
             Ok(None) => "".to_string(),
             Err(_) => Err(ApiError::bad_json(None))?,
         };
@@ -119,7 +101,7 @@ impl Handler for PutTag {
 
         RoomTag::upsert(&connection, user_id, room_id, tag, content)?;
 
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        Ok(empty_response())
     }
 }
 
@@ -134,26 +116,10 @@ middleware_chain!(
 
 impl Handler for DeleteTag {
     fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
-        let user_id = request
-            .extensions
-            .get::<UserIdParam>()
-            .expect("UserIdParam should ensure a UserId")
-            .clone();
-        let room_id = request
-            .extensions
-            .get::<RoomIdParam>()
-            .expect("RoomIdParam should ensure a RoomId")
-            .clone();
-        let user = request
-            .extensions
-            .get::<User>()
-            .expect("AccessTokenAuth should ensure a user")
-            .clone();
-        let tag = request
-            .extensions
-            .get::<TagParam>()
-            .expect("TagParam should ensure a tag")
-            .clone();
+        let user_id = UserIdParam::from_request(request)?;
+        let room_id = RoomIdParam::from_request(request)?;
+        let user = User::from_request(request)?;
+        let tag = TagParam::from_request(request)?;
 
         // Check if the given user_id corresponds to the authenticated user.
         if user_id != user.id {
@@ -166,7 +132,7 @@ impl Handler for DeleteTag {
 
         RoomTag::delete(&connection, user_id, room_id, tag)?;
 
-        Ok(Response::with(EmptyResponse(Status::Ok)))
+        Ok(empty_response())
     }
 }
 
@@ -182,13 +148,7 @@ mod tests {
 
         let room_id = test.create_public_room(&carl.token);
 
-        test.create_tag(
-            &carl.token,
-            &room_id,
-            &carl.id,
-            "work",
-            r#"{"order":"test"}"#,
-        );
+        test.create_tag(&carl.token, &room_id, &carl.id, "work", r#"{"order":0.5}"#);
 
         let get_tags_path = format!(
             "/_matrix/client/r0/user/{}/rooms/{}/tags?access_token={}",
@@ -202,7 +162,7 @@ mod tests {
         let chunk = chunk.as_object().unwrap();
         assert_eq!(chunk.len(), 1);
         let content = chunk.get("work").unwrap();
-        assert_eq!(content.to_string(), r#"{"order":"test"}"#);
+        assert_eq!(content.to_string(), r#"{"order":0.5}"#);
     }
 
     #[test]
@@ -250,7 +210,7 @@ mod tests {
             &room_id,
             carl.id.as_str(),
             "delete",
-            r#"{"order":"test"}"#,
+            r#"{"order":0.5}"#,
         );
 
         let delete_tag_path = format!(
@@ -269,21 +229,9 @@ mod tests {
 
         let room_id = test.create_public_room(&carl.token);
 
-        test.create_tag(
-            &carl.token,
-            &room_id,
-            &carl.id,
-            "test",
-            r#"{"order":"test"}"#,
-        );
+        test.create_tag(&carl.token, &room_id, &carl.id, "test", r#"{"order":0.5}"#);
 
-        test.create_tag(
-            &carl.token,
-            &room_id,
-            &carl.id,
-            "test",
-            r#"{"order":"test2"}"#,
-        );
+        test.create_tag(&carl.token, &room_id, &carl.id, "test", r#"{"order":0.25}"#);
 
         let get_tags_path = format!(
             "/_matrix/client/r0/user/{}/rooms/{}/tags?access_token={}",
@@ -294,7 +242,7 @@ mod tests {
         let chunk = response.json().get("tags").unwrap();
         let chunk = chunk.as_object().unwrap();
         let content = chunk.get("test").unwrap();
-        assert_eq!(content.to_string(), r#"{"order":"test2"}"#);
+        assert_eq!(content.to_string(), r#"{"order":0.25}"#);
     }
 
     #[test]
@@ -309,7 +257,7 @@ mod tests {
             &room_id,
             &carl.id,
             "delete",
-            r#"{"order":"test"}"#,
+            r#"{"order":0.5}"#,
         );
 
         let delete_tag_path = format!(
@@ -332,6 +280,53 @@ mod tests {
         assert_eq!(chunk.len(), 0);
     }
 
+    #[test]
+    fn delete_one_of_two_tags() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let room_id = test.create_public_room(&carl.token);
+
+        test.create_tag(
+            &carl.token,
+            &room_id,
+            &carl.id,
+            "m.favourite",
+            r#"{"order":0.5}"#,
+        );
+        test.create_tag(
+            &carl.token,
+            &room_id,
+            &carl.id,
+            "u.work",
+            r#"{"order":0.25}"#,
+        );
+
+        let get_tags_path = format!(
+            "/_matrix/client/r0/user/{}/rooms/{}/tags?access_token={}",
+            carl.id, room_id, carl.token
+        );
+
+        let response = test.get(&get_tags_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.body,
+            r#"{"tags":{"u.work":{"order":0.25},"m.favourite":{"order":0.5}}}"#
+        );
+
+        let delete_tag_path = format!(
+            "/_matrix/client/r0/user/{}/rooms/{}/tags/m.favourite?access_token={}",
+            carl.id, room_id, carl.token
+        );
+
+        let response = test.delete(&delete_tag_path);
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.get(&get_tags_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body, r#"{"tags":{"u.work":{"order":0.25}}}"#);
+    }
+
     #[test]
     fn put_tag_invalid_room() {
         let test = Test::new();
@@ -365,7 +360,7 @@ mod tests {
     }
 
     #[test]
-    fn delete_tags_invalid_room_and_tag() {
+    fn delete_nonexistent_tag() {
         let test = Test::new();
         let carl = test.create_user();
         let room_id = test.create_public_room(&carl.token);
@@ -376,7 +371,14 @@ mod tests {
         );
 
         let response = test.delete(&delete_tag_path);
-        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(response.body, "{}");
+    }
+
+    #[test]
+    fn delete_tag_invalid_room() {
+        let test = Test::new();
+        let carl = test.create_user();
 
         let room_id = "!n8f893n9:ruma.test";
 
@@ -388,4 +390,53 @@ mod tests {
         let response = test.delete(&delete_tag_path);
         assert_eq!(response.status, Status::NotFound);
     }
+
+    #[test]
+    fn tags_sorted_by_order() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = test.create_public_room(&carl.token);
+
+        test.create_tag(&carl.token, &room_id, &carl.id, "a", r#"{}"#);
+        test.create_tag(&carl.token, &room_id, &carl.id, "b", r#"{"order":0.9}"#);
+        test.create_tag(&carl.token, &room_id, &carl.id, "c", r#"{"order":0.1}"#);
+
+        let get_tags_path = format!(
+            "/_matrix/client/r0/user/{}/rooms/{}/tags?access_token={}",
+            carl.id, room_id, carl.token
+        );
+
+        let response = test.get(&get_tags_path);
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.body,
+            r#"{"tags":{"c":{"order":0.1},"b":{"order":0.9},"a":{}}}"#
+        );
+    }
+
+    #[test]
+    fn put_tag_invalid_order() {
+        let test = Test::new();
+        let carl = test.create_user();
+        let room_id = test.create_public_room(&carl.token);
+
+        let put_tag_path = format!(
+            "/_matrix/client/r0/user/{}/rooms/{}/tags/work?access_token={}",
+            carl.id, room_id, carl.token
+        );
+
+        for content in &[
+            r#"{"order":1.5}"#,
+            r#"{"order":-0.1}"#,
+            r#"{"order":"test"}"#,
+        ] {
+            let response = test.put(&put_tag_path, content);
+
+            assert_eq!(response.status, Status::UnprocessableEntity);
+            assert_eq!(
+                response.json().get("errcode").unwrap().as_str().unwrap(),
+                "M_BAD_JSON"
+            );
+        }
+    }
 }
@@ -0,0 +1,216 @@
+//! Endpoints for typing notifications.
+
+use std::cmp;
+
+use bodyparser;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain, RoomIdParam, UserIdParam};
+use crate::models::room_membership::RoomMembership;
+use crate::models::typing::Typing;
+use crate::models::user::User;
+use crate::modifier::empty_response;
+
+/// The time in milliseconds a user is marked as typing if the request does not specify one.
+const DEFAULT_TIMEOUT: u64 = 30_000;
+
+/// The longest time in milliseconds a user can be marked as typing at once.
+const MAX_TIMEOUT: u64 = 120_000;
+
+/// The PUT `/rooms/:room_id/typing/:user_id` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct PutTyping;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct PutTypingRequest {
+    /// Whether the user is typing or not.
+    typing: bool,
+    /// The length of time in milliseconds to mark the user as typing.
+    timeout: Option<u64>,
+}
+
+middleware_chain!(
+    PutTyping,
+    [JsonRequest, RoomIdParam, UserIdParam, AccessTokenAuth]
+);
+
+impl Handler for PutTyping {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user = User::from_request(request)?;
+
+        let room_id = RoomIdParam::from_request(request)?;
+        let user_id = UserIdParam::from_request(request)?;
+
+        if user_id != user.id {
+            Err(ApiError::unauthorized(
+                "The given user_id does not correspond to the authenticated user".to_string(),
+            ))?;
+        }
+
+        let typing_request = match request.get::<bodyparser::Struct<PutTypingRequest>>() {
+            Ok(Some(typing_request)) => typing_request,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        let connection = DB::from_request(request)?;
+
+        RoomMembership::require_membership(&connection, &room_id, &user.id, &["join"])?;
+
+        if typing_request.typing {
+            let timeout = cmp::min(
+                typing_request.timeout.unwrap_or(DEFAULT_TIMEOUT),
+                MAX_TIMEOUT,
+            );
+
+            Typing::start(&connection, &room_id, &user.id, timeout as i64)?;
+        } else {
+            Typing::stop(&connection, &room_id, &user.id)?;
+        }
+
+        Ok(empty_response())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+    use serde_json::Value;
+
+    use crate::query::{Batch, SyncOptions};
+    use crate::test::Test;
+
+    fn typing_user_ids(test: &Test, access_token: &str, room_id: &str) -> Option<Value> {
+        typing_user_ids_since(test, access_token, room_id, None).0
+    }
+
+    /// Sync and return the users typing in the room, if included, and the next batch.
+    fn typing_user_ids_since(
+        test: &Test,
+        access_token: &str,
+        room_id: &str,
+        since: Option<Batch>,
+    ) -> (Option<Value>, Batch) {
+        let options = SyncOptions {
+            filter: None,
+            since,
+            full_state: false,
+            set_presence: None,
+            timeout: 0,
+        };
+        let response = test.sync(access_token, options);
+
+        let user_ids = response
+            .json()
+            .pointer(&format!("/rooms/join/{}/ephemeral/events", room_id))
+            .and_then(|events| {
+                events
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .find(|event| event.get("type").unwrap().as_str().unwrap() == "m.typing")
+            })
+            .map(|event| event.pointer("/content/user_ids").unwrap().clone());
+
+        (user_ids, Test::get_next_batch(&response))
+    }
+
+    #[test]
+    fn start_and_stop_typing() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        assert!(test.join_room(&bob.token, &room_id).status.is_success());
+
+        let typing_path = format!(
+            "/_matrix/client/r0/rooms/{}/typing/{}?access_token={}",
+            room_id, alice.id, alice.token
+        );
+
+        let response = test.put(&typing_path, r#"{"typing": true, "timeout": 30000}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        assert_eq!(
+            typing_user_ids(&test, &bob.token, &room_id),
+            Some(Value::Array(vec![Value::String(alice.id.clone())]))
+        );
+
+        let response = test.put(&typing_path, r#"{"typing": false}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        assert_eq!(typing_user_ids(&test, &bob.token, &room_id), None);
+    }
+
+    #[test]
+    fn typing_changes_in_incremental_sync() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        assert!(test.join_room(&bob.token, &room_id).status.is_success());
+
+        let (_, next_batch) = typing_user_ids_since(&test, &bob.token, &room_id, None);
+
+        let typing_path = format!(
+            "/_matrix/client/r0/rooms/{}/typing/{}?access_token={}",
+            room_id, alice.id, alice.token
+        );
+        let response = test.put(&typing_path, r#"{"typing": true, "timeout": 30000}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let (user_ids, next_batch) =
+            typing_user_ids_since(&test, &bob.token, &room_id, Some(next_batch));
+        assert_eq!(
+            user_ids,
+            Some(Value::Array(vec![Value::String(alice.id.clone())]))
+        );
+
+        // Nothing changed while Alice keeps typing.
+        let (user_ids, next_batch) =
+            typing_user_ids_since(&test, &bob.token, &room_id, Some(next_batch));
+        assert_eq!(user_ids, None);
+
+        let response = test.put(&typing_path, r#"{"typing": false}"#);
+        assert_eq!(response.status, Status::Ok);
+
+        let (user_ids, _) = typing_user_ids_since(&test, &bob.token, &room_id, Some(next_batch));
+        assert_eq!(user_ids, Some(Value::Array(Vec::new())));
+    }
+
+    #[test]
+    fn typing_for_other_user() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+        assert!(test.join_room(&bob.token, &room_id).status.is_success());
+
+        let typing_path = format!(
+            "/_matrix/client/r0/rooms/{}/typing/{}?access_token={}",
+            room_id, bob.id, alice.token
+        );
+
+        let response = test.put(&typing_path, r#"{"typing": true, "timeout": 30000}"#);
+        assert_eq!(response.status, Status::Forbidden);
+        assert_eq!(typing_user_ids(&test, &alice.token, &room_id), None);
+    }
+
+    #[test]
+    fn typing_in_room_not_joined() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+        let room_id = test.create_public_room(&alice.token);
+
+        let typing_path = format!(
+            "/_matrix/client/r0/rooms/{}/typing/{}?access_token={}",
+            room_id, bob.id, bob.token
+        );
+
+        let response = test.put(&typing_path, r#"{"typing": true, "timeout": 30000}"#);
+        assert_eq!(response.status, Status::Forbidden);
+    }
+}
@@ -1,5 +1,7 @@
 //! Endpoints for information about supported versions of the Matrix spec.
 
+use std::collections::BTreeMap;
+
 use iron::{status, Handler, IronResult, Request, Response};
 
 use crate::modifier::SerializableResponse;
@@ -9,13 +11,18 @@ use crate::modifier::SerializableResponse;
 pub struct Versions {
     /// A list of API versions supported by the homeserver.
     versions: Vec<&'static str>,
+    /// Unstable features of the homeserver, mapped to whether they are enabled.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    unstable_features: BTreeMap<String, bool>,
 }
 
 impl Versions {
-    /// Returns the list of supported `Versions` of the Matrix spec.
-    pub fn supported() -> Self {
+    /// Returns the list of supported `Versions` of the Matrix spec along with the given unstable
+    /// features.
+    pub fn supported(unstable_features: BTreeMap<String, bool>) -> Self {
         Self {
             versions: vec!["r0.2.0"],
+            unstable_features,
         }
     }
 }
@@ -25,3 +32,42 @@ impl Handler for Versions {
         Ok(Response::with((status::Ok, SerializableResponse(&self))))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use iron::headers::ContentType;
+    use iron::status::Status;
+
+    use crate::test::Test;
+
+    #[test]
+    fn json_content_type() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/client/versions");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.headers.get::<ContentType>().unwrap(),
+            &ContentType::json()
+        );
+        assert_eq!(response.body, r#"{"versions":["r0.2.0"]}"#);
+    }
+
+    #[test]
+    fn unstable_features() {
+        let test = Test::with_config(|config| {
+            config
+                .unstable_features
+                .insert("m.lazy_load_members".to_string(), false);
+        });
+
+        let response = test.get("/_matrix/client/versions");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.body,
+            r#"{"versions":["r0.2.0"],"unstable_features":{"m.lazy_load_members":false}}"#
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Ruma-specific API endpoints that are not part of the Matrix spec.
+
+use std::collections::HashMap;
+
+use bodyparser;
+use diesel::prelude::*;
+use diesel::sql_query;
+use iron::status::Status;
+use iron::{Chain, Handler, IronResult, Plugin, Request, Response};
+use ruma_identifiers::UserId;
+
+use crate::config::Config;
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::middleware::{AccessTokenAuth, JsonRequest, MiddlewareChain};
+use crate::models::profile::Profile;
+use crate::modifier::SerializableResponse;
+
+/// The maximum number of users whose profiles can be requested at once.
+const MAX_PROFILES: usize = 100;
+
+/// The POST `/ruma/profiles` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct PostProfiles;
+
+/// The body of the request for this API.
+#[derive(Clone, Debug, Deserialize)]
+struct PostProfilesRequest {
+    /// The users whose profiles to return.
+    user_ids: Vec<UserId>,
+}
+
+/// The profile of a single user.
+#[derive(Clone, Debug, Serialize)]
+struct ProfileResponse {
+    /// The user's avatar URL if they have set one, otherwise not present.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    avatar_url: Option<String>,
+    /// The user's display name if they have set one, otherwise not present.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    displayname: Option<String>,
+}
+
+middleware_chain!(PostProfiles, [JsonRequest, AccessTokenAuth]);
+
+impl Handler for PostProfiles {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let user_ids = match request.get::<bodyparser::Struct<PostProfilesRequest>>() {
+            Ok(Some(profiles_request)) => profiles_request.user_ids,
+            Ok(None) | Err(_) => Err(ApiError::bad_json(None))?,
+        };
+
+        if user_ids.len() > MAX_PROFILES {
+            Err(ApiError::too_large(format!(
+                "At most {} profiles can be requested at once.",
+                MAX_PROFILES
+            )))?;
+        }
+
+        let connection = DB::from_request(request)?;
+        let config = Config::from_request(request)?;
+
+        // Unknown users are left out of the response instead of failing the whole request.
+        let response: HashMap<UserId, ProfileResponse> =
+            Profile::get_profiles(&connection, &user_ids)?
+                .into_iter()
+                .map(|profile| {
+                    let profile_response = ProfileResponse {
+                        avatar_url: profile
+                            .avatar_url
+                            .or_else(|| config.default_avatar_url.clone()),
+                        displayname: profile.displayname,
+                    };
+
+                    (profile.id, profile_response)
+                })
+                .collect();
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+/// The GET `/ruma/health` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct Health;
+
+/// The response of this API.
+#[derive(Clone, Debug, Serialize)]
+struct HealthResponse {
+    /// Whether the database can be queried, either "ok" or "down".
+    database: &'static str,
+    /// The state of the database connection pool, if the database is reachable.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pool: Option<PoolHealth>,
+}
+
+/// The state of the database connection pool.
+#[derive(Clone, Debug, Serialize)]
+struct PoolHealth {
+    /// The number of connections currently managed by the pool.
+    connections: u32,
+    /// The number of those connections that are idle.
+    idle: u32,
+}
+
+impl Handler for Health {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let down = HealthResponse {
+            database: "down",
+            pool: None,
+        };
+
+        let connection = match DB::from_request(request) {
+            Ok(connection) => connection,
+            Err(_) => {
+                return Ok(Response::with((
+                    Status::ServiceUnavailable,
+                    SerializableResponse(down),
+                )))
+            }
+        };
+
+        if sql_query("SELECT 1").execute(&*connection).is_err() {
+            return Ok(Response::with((
+                Status::ServiceUnavailable,
+                SerializableResponse(down),
+            )));
+        }
+
+        let state = DB::pool_state_from_request(request)?;
+
+        let response = HealthResponse {
+            database: "ok",
+            pool: Some(PoolHealth {
+                connections: state.connections,
+                idle: state.idle_connections,
+            }),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use crate::test::Test;
+
+    #[test]
+    fn known_and_unknown_users() {
+        let test = Test::new();
+        let alice = test.create_user();
+        let bob = test.create_user();
+
+        let displayname_path = format!(
+            "/_matrix/client/r0/profile/{}/displayname?access_token={}",
+            bob.id, bob.token
+        );
+        assert!(test
+            .put(&displayname_path, r#"{"displayname": "Bob"}"#)
+            .status
+            .is_success());
+
+        let body = format!(
+            r#"{{"user_ids": ["{}", "{}", "@unknown:ruma.test"]}}"#,
+            alice.id, bob.id
+        );
+        let response = test.post(
+            &format!("/ruma/profiles?access_token={}", alice.token),
+            &body,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let profiles = response.json().as_object().unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert!(profiles.get("@unknown:ruma.test").is_none());
+        assert!(profiles
+            .get(&alice.id)
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            profiles
+                .get(&bob.id)
+                .unwrap()
+                .get("displayname")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Bob"
+        );
+    }
+
+    #[test]
+    fn too_many_users() {
+        let test = Test::new();
+        let alice = test.create_user();
+
+        let user_ids: Vec<String> = (0..101)
+            .map(|i| format!(r#""@user{}:ruma.test""#, i))
+            .collect();
+        let body = format!(r#"{{"user_ids": [{}]}}"#, user_ids.join(","));
+        let response = test.post(
+            &format!("/ruma/profiles?access_token={}", alice.token),
+            &body,
+        );
+
+        assert_eq!(response.status, Status::PayloadTooLarge);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_TOO_LARGE"
+        );
+    }
+
+    #[test]
+    fn healthy() {
+        let test = Test::new();
+
+        let response = test.get("/ruma/health");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.json().get("database").unwrap().as_str().unwrap(),
+            "ok"
+        );
+
+        let pool = response.json().get("pool").unwrap();
+        let connections = pool.get("connections").unwrap().as_u64().unwrap();
+        let idle = pool.get("idle").unwrap().as_u64().unwrap();
+        assert!(connections >= 1);
+        assert!(idle < connections);
+    }
+}
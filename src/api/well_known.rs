@@ -0,0 +1,93 @@
+//! Endpoints under `/.well-known/matrix` for discovering the server.
+
+use iron::status::Status;
+use iron::{Handler, IronResult, Request, Response};
+
+use crate::config::Config;
+use crate::modifier::SerializableResponse;
+
+/// The GET `/.well-known/matrix/client` endpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientDiscovery;
+
+/// The response of this API.
+#[derive(Clone, Debug, Serialize)]
+struct ClientDiscoveryResponse {
+    /// Information about the homeserver to connect to.
+    #[serde(rename = "m.homeserver")]
+    homeserver: ServerInformation,
+    /// Information about the identity server to use, if one is configured.
+    #[serde(rename = "m.identity_server", skip_serializing_if = "Option::is_none")]
+    identity_server: Option<ServerInformation>,
+}
+
+/// The location of a server.
+#[derive(Clone, Debug, Serialize)]
+struct ServerInformation {
+    /// The base URL of the server.
+    base_url: String,
+}
+
+impl Handler for ClientDiscovery {
+    fn handle(&self, request: &mut Request<'_, '_>) -> IronResult<Response> {
+        let config = Config::from_request(request)?;
+
+        let response = ClientDiscoveryResponse {
+            homeserver: ServerInformation {
+                base_url: format!("https://{}", config.domain),
+            },
+            identity_server: config
+                .identity_server
+                .clone()
+                .map(|base_url| ServerInformation { base_url }),
+        };
+
+        Ok(Response::with((Status::Ok, SerializableResponse(response))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use iron::status::Status;
+
+    use crate::test::Test;
+
+    #[test]
+    fn client_discovery() {
+        let test = Test::new();
+
+        let response = test.get("/.well-known/matrix/client");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .pointer("/m.homeserver/base_url")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "https://ruma.test"
+        );
+        assert!(response.json().get("m.identity_server").is_none());
+    }
+
+    #[test]
+    fn client_discovery_with_identity_server() {
+        let test = Test::with_config(|config| {
+            config.identity_server = Some("https://identity.ruma.test".to_string())
+        });
+
+        let response = test.get("/.well-known/matrix/client");
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response
+                .json()
+                .pointer("/m.identity_server/base_url")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "https://identity.ruma.test"
+        );
+    }
+}
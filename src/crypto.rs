@@ -1,6 +1,8 @@
 //! Cryptographic operations.
 
+use argon2rs::defaults::{KIB, LANES};
 use argon2rs::verifier::Encoded;
+use argon2rs::{Argon2, Variant};
 use base64::encode;
 use rand::{rngs::OsRng, RngCore};
 
@@ -16,15 +18,19 @@ pub fn generate_macaroon_secret_key() -> Result<String, CliError> {
     Ok(encode(&key))
 }
 
-/// Hash a password with Argon2.
-pub fn hash_password(password: &str) -> Result<String, ApiError> {
+/// Hash a password with Argon2, using `cost` passes over the memory.
+pub fn hash_password(password: &str, cost: u32) -> Result<String, ApiError> {
     let salt = generate_salt()?;
-    let encoded_hash = Encoded::default2i(password.as_bytes(), &salt, &[], &[]).to_u8();
+    let argon2 = Argon2::new(cost, LANES, KIB, Variant::Argon2i)?;
+    let encoded_hash = Encoded::new(argon2, password.as_bytes(), &salt, &[], &[]).to_u8();
 
     String::from_utf8(encoded_hash).map_err(ApiError::from)
 }
 
 /// Verifies a password with Argon2.
+///
+/// The hashes are compared in constant time. The cost is read from the encoded hash, so hashes
+/// created with a different cost can still be verified.
 pub fn verify_password(encoded_hash: &[u8], plaintext_password: &str) -> Result<bool, ApiError> {
     let encoded = Encoded::from_u8(encoded_hash).map_err(ApiError::from)?;
 
@@ -40,3 +46,21 @@ fn generate_salt() -> Result<[u8; 16], ApiError> {
 
     Ok(salt)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_password, verify_password};
+
+    #[test]
+    fn hash_honors_cost() {
+        let encoded_hash = hash_password("secret", 5).unwrap();
+
+        assert!(encoded_hash.contains("t=5"));
+        assert!(verify_password(encoded_hash.as_bytes(), "secret").unwrap());
+    }
+
+    #[test]
+    fn zero_cost_is_rejected() {
+        assert!(hash_password("secret", 0).is_err());
+    }
+}
@@ -1,26 +1,41 @@
 //! Database-related functionality.
 
-use diesel::pg::PgConnection;
-use diesel::r2d2::{Builder, ConnectionManager, Pool, PoolError as R2d2Error, PooledConnection};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use diesel::pg::{Pg, PgConnection, PgQueryBuilder};
+use diesel::query_builder::{QueryBuilder, QueryFragment};
+use diesel::r2d2::{
+    Builder, ConnectionManager, Pool, PoolError as R2d2Error, PooledConnection, State,
+};
+use diesel::result::QueryResult;
 use iron::typemap::Key;
 use iron::{Plugin, Request};
 use persistent::Write;
 
 use crate::error::ApiError;
 
+/// Whether instrumented SQL statements are logged. Set from `Config::log_instrumented_sql` on startup.
+static LOG_SQL: AtomicBool = AtomicBool::new(false);
+
 /// An Iron plugin for attaching a database connection pool to an Iron request.
 #[derive(Clone, Copy, Debug)]
 pub struct DB;
 
 impl DB {
     /// Creates a connection pool for the PostgreSQL database at the given URL.
+    ///
+    /// Acquiring a connection from the pool fails after waiting for `connection_timeout`.
     pub fn create_connection_pool(
         r2d2_pool_builder: Builder<ConnectionManager<PgConnection>>,
         postgres_url: &str,
+        connection_timeout: Duration,
     ) -> Result<Pool<ConnectionManager<PgConnection>>, R2d2Error> {
         let connection_manager = ConnectionManager::new(postgres_url);
 
-        r2d2_pool_builder.build(connection_manager)
+        r2d2_pool_builder
+            .connection_timeout(connection_timeout)
+            .build(connection_manager)
     }
 
     /// Extract a database conection from the pool stored in the request.
@@ -31,8 +46,179 @@ impl DB {
         let pool = mutex.lock().map_err(ApiError::from)?;
         pool.get().map_err(ApiError::from)
     }
+
+    /// Return the current state of the connection pool stored in the request.
+    pub fn pool_state_from_request(request: &mut Request<'_, '_>) -> Result<State, ApiError> {
+        let mutex = request.get::<Write<Self>>().map_err(ApiError::from)?;
+        let pool = mutex.lock().map_err(ApiError::from)?;
+        Ok(pool.state())
+    }
+
+    /// Enables or disables logging of instrumented SQL statements.
+    pub fn set_log_sql(enabled: bool) {
+        LOG_SQL.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Executes a query with `run`, logging its SQL and execution time if SQL logging is enabled.
+    ///
+    /// Diesel has no hook for every executed statement, so only queries wrapped in this function
+    /// are logged. Only the SQL with its placeholders is logged. Bind parameters are never
+    /// included, since they can contain secrets such as password hashes or access tokens.
+    pub fn instrument<Q, T, F>(query: Q, run: F) -> QueryResult<T>
+    where
+        Q: QueryFragment<Pg>,
+        F: FnOnce(Q) -> QueryResult<T>,
+    {
+        Self::instrument_with(query, run, LOG_SQL.load(Ordering::Relaxed), |entry| {
+            info!("{}", entry)
+        })
+    }
+
+    /// Executes a query with `run`, passing its log entry to `log` if `enabled`.
+    fn instrument_with<Q, T, F, L>(query: Q, run: F, enabled: bool, log: L) -> QueryResult<T>
+    where
+        Q: QueryFragment<Pg>,
+        F: FnOnce(Q) -> QueryResult<T>,
+        L: FnOnce(String),
+    {
+        if !enabled {
+            return run(query);
+        }
+
+        let sql = Self::sql(&query);
+        let start = Instant::now();
+        let result = run(query);
+
+        log(Self::sql_log_entry(&sql, start.elapsed()));
+
+        result
+    }
+
+    /// Renders the SQL of a query without its bind parameters.
+    fn sql<Q>(query: &Q) -> String
+    where
+        Q: QueryFragment<Pg>,
+    {
+        let mut query_builder = PgQueryBuilder::default();
+
+        match query.to_sql(&mut query_builder) {
+            Ok(()) => query_builder.finish(),
+            Err(error) => format!("<unprintable query: {}>", error),
+        }
+    }
+
+    /// Formats the log entry for an executed SQL statement.
+    fn sql_log_entry(sql: &str, elapsed: Duration) -> String {
+        format!(
+            "SQL ({}.{:03}ms): {}",
+            elapsed.as_millis(),
+            elapsed.subsec_micros() % 1000,
+            sql
+        )
+    }
 }
 
 impl Key for DB {
     type Value = Pool<ConnectionManager<PgConnection>>;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use diesel::prelude::*;
+    use diesel::r2d2::Pool;
+    use iron::modifier::Modifier;
+    use iron::status::Status;
+    use iron::Response;
+    use serde_json::to_value;
+
+    use super::DB;
+    use crate::error::ApiError;
+    use crate::schema::users;
+    use crate::test::{Test, DATABASE_URL};
+
+    #[test]
+    fn connection_acquisition_times_out_with_service_unavailable() {
+        // Make sure the test database exists.
+        let _test = Test::new();
+
+        let pool = DB::create_connection_pool(
+            Pool::builder().max_size(1),
+            DATABASE_URL,
+            Duration::from_millis(100),
+        )
+        .unwrap();
+
+        let _held_connection = pool.get().unwrap();
+        let started = Instant::now();
+        let error = ApiError::from(pool.get().unwrap_err());
+
+        // The request must fail once the timeout elapses instead of waiting for the connection.
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let mut response = Response::new();
+        error.clone().modify(&mut response);
+
+        assert_eq!(response.status.unwrap(), Status::ServiceUnavailable);
+        assert_eq!(
+            to_value(&error)
+                .unwrap()
+                .get("errcode")
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "M_UNKNOWN"
+        );
+    }
+
+    #[test]
+    fn sql_log_entry_omits_bind_parameters() {
+        let query = users::table
+            .filter(users::id.eq("@carl:ruma.test"))
+            .filter(users::password_hash.eq("hunter2"));
+
+        let entry = DB::sql_log_entry(&DB::sql(&query), Duration::from_micros(1500));
+
+        assert!(entry.starts_with("SQL (1.500ms): SELECT"));
+        assert!(entry.contains("\"users\".\"password_hash\" = $2"));
+        assert!(!entry.contains("hunter2"));
+    }
+
+    #[test]
+    fn instrument_logs_executed_queries_only_if_enabled() {
+        let connection = Test::connection();
+        let query = || {
+            users::table
+                .select(users::id)
+                .filter(users::id.eq("@carl:ruma.test"))
+                .filter(users::password_hash.eq("hunter2"))
+        };
+
+        let mut entries = Vec::new();
+        let user_ids: Vec<String> = DB::instrument_with(
+            query(),
+            |query| query.load(&connection),
+            true,
+            |entry| entries.push(entry),
+        )
+        .unwrap();
+
+        assert!(user_ids.is_empty());
+        assert_eq!(entries.len(), 1);
+        assert!(entries[0].starts_with("SQL ("));
+        assert!(entries[0].contains("\"users\".\"password_hash\" = $2"));
+        assert!(!entries[0].contains("hunter2"));
+
+        let mut entries = Vec::new();
+        DB::instrument_with(
+            query(),
+            |query| query.load::<String>(&connection),
+            false,
+            |entry| entries.push(entry),
+        )
+        .unwrap();
+
+        assert!(entries.is_empty());
+    }
+}
@@ -6,9 +6,10 @@ use std::fmt::{Debug, Display, Formatter};
 use std::io::Error as IoError;
 use std::string::FromUtf8Error;
 use std::sync::PoisonError;
-use std::time::SystemTimeError;
+use std::time::{Duration, SystemTimeError};
 
 use argon2rs::verifier::DecodeError;
+use argon2rs::ParamErr;
 use diesel::r2d2::PoolError as R2d2Error;
 use diesel::result::Error as DieselError;
 use iron::headers::ContentType;
@@ -29,6 +30,9 @@ pub struct ApiError {
     errcode: ApiErrorCode,
     /// A human-readable message describing the error.
     error: String,
+    /// How long the client should wait before retrying the request, in milliseconds.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after_ms: Option<u64>,
 }
 
 /// The error code for a client-facing error.
@@ -36,6 +40,8 @@ pub struct ApiError {
 pub enum ApiErrorCode {
     /// The requested room alias is already taken.
     AliasTaken,
+    /// The given room alias does not point to the room it is used for.
+    BadAlias,
     /// Request contained an event that was not valid input for the requested API.
     BadEvent,
     /// The request contained valid JSON, but it was malformed in some way,
@@ -47,6 +53,8 @@ pub enum ApiErrorCode {
     GuestAccessForbidden,
     /// An input parameter didn't have a valid format.
     InvalidParam,
+    /// The desired user ID is not a valid user name.
+    InvalidUsername,
     /// Too many requests have been sent in a short period of time. Wait a while then try again.
     LimitExceeded,
     /// A required input parameter was not supplied, e.g. query string or URL path-based parameter.
@@ -55,12 +63,21 @@ pub enum ApiErrorCode {
     NotFound,
     /// Request did not contain valid JSON.
     NotJson,
+    /// The request or one of its parameters is too large.
+    TooLarge,
+    /// The server is temporarily unable to handle the request, e.g. no database connection is free.
+    ///
+    /// The specification has no error code for this, so it is reported as `M_UNKNOWN`.
+    Unavailable,
     /// Ruma does not implement the requested API.
     Unimplemented,
     /// Errors not fitting into another category.
     Unknown,
     /// The access token specified was not recognised.
     UnknownToken,
+    /// The request body is in a format the server does not understand, e.g. because of its
+    /// `Content-Type`.
+    Unrecognized,
 }
 
 /// An operator-facing error.
@@ -93,6 +110,17 @@ impl ApiError {
         Self {
             errcode: ApiErrorCode::AliasTaken,
             error: message.unwrap_or_else(|| "Alias already taken.".to_string()),
+            retry_after_ms: None,
+        }
+    }
+
+    /// Create an error for room aliases that do not point to the room they are used for.
+    pub fn bad_alias<T: Into<Option<String>>>(message: T) -> Self {
+        let message = message.into();
+        Self {
+            errcode: ApiErrorCode::BadAlias,
+            error: message.unwrap_or_else(|| "Alias does not point to this room.".to_string()),
+            retry_after_ms: None,
         }
     }
 
@@ -102,6 +130,7 @@ impl ApiError {
         Self {
             errcode: ApiErrorCode::BadEvent,
             error: message.unwrap_or_else(|| "Invalid event data.".to_string()),
+            retry_after_ms: None,
         }
     }
 
@@ -112,6 +141,7 @@ impl ApiError {
             errcode: ApiErrorCode::BadJson,
             error: message
                 .unwrap_or_else(|| "Invalid or missing key-value pairs in JSON.".to_string()),
+            retry_after_ms: None,
         }
     }
 
@@ -121,6 +151,7 @@ impl ApiError {
         Self {
             errcode: ApiErrorCode::GuestAccessForbidden,
             error: message.unwrap_or_else(|| "Guest accounts are forbidden.".to_string()),
+            retry_after_ms: None,
         }
     }
 
@@ -129,6 +160,16 @@ impl ApiError {
         Self {
             errcode: ApiErrorCode::InvalidParam,
             error: format!("Parameter '{}' is not valid: {}", param_name, msg),
+            retry_after_ms: None,
+        }
+    }
+
+    /// Create an error for registrations with a user name that is not valid.
+    pub fn invalid_username(msg: &str) -> Self {
+        Self {
+            errcode: ApiErrorCode::InvalidUsername,
+            error: format!("The user name is not valid: {}", msg),
+            retry_after_ms: None,
         }
     }
 
@@ -137,6 +178,7 @@ impl ApiError {
         Self {
             errcode: ApiErrorCode::MissingParam,
             error: format!("Missing value for required parameter: {}.", param_name),
+            retry_after_ms: None,
         }
     }
 
@@ -146,6 +188,7 @@ impl ApiError {
         Self {
             errcode: ApiErrorCode::NotFound,
             error: message.unwrap_or_else(|| "No resource was found for this request.".to_string()),
+            retry_after_ms: None,
         }
     }
 
@@ -155,6 +198,7 @@ impl ApiError {
         Self {
             errcode: ApiErrorCode::NotJson,
             error: message.unwrap_or_else(|| "No JSON found in request body.".to_string()),
+            retry_after_ms: None,
         }
     }
 
@@ -162,10 +206,21 @@ impl ApiError {
     pub fn wrong_content_type<T: Into<Option<String>>>(message: T) -> Self {
         let message = message.into();
         Self {
-            errcode: ApiErrorCode::NotJson,
+            errcode: ApiErrorCode::Unrecognized,
             error: message.unwrap_or_else(|| {
                 "Request's Content-Type header must be application/json.".to_string()
             }),
+            retry_after_ms: None,
+        }
+    }
+
+    /// Create an error for requests that are too large, e.g. by listing too many items.
+    pub fn too_large<T: Into<Option<String>>>(message: T) -> Self {
+        let message = message.into();
+        Self {
+            errcode: ApiErrorCode::TooLarge,
+            error: message.unwrap_or_else(|| "The request is too large.".to_string()),
+            retry_after_ms: None,
         }
     }
 
@@ -175,6 +230,20 @@ impl ApiError {
         Self {
             errcode: ApiErrorCode::Forbidden,
             error: message.unwrap_or_else(|| "Authentication is required.".to_string()),
+            retry_after_ms: None,
+        }
+    }
+
+    /// Create an error for requests that cannot be handled right now because a resource such as
+    /// a database connection is not available.
+    pub fn unavailable<T: Into<Option<String>>>(message: T) -> Self {
+        let message = message.into();
+        Self {
+            errcode: ApiErrorCode::Unavailable,
+            error: message.unwrap_or_else(|| {
+                "The server is temporarily unable to handle the request.".to_string()
+            }),
+            retry_after_ms: None,
         }
     }
 
@@ -185,15 +254,28 @@ impl ApiError {
             errcode: ApiErrorCode::Unimplemented,
             error: message
                 .unwrap_or_else(|| "The homeserver does not implement this API.".to_string()),
+            retry_after_ms: None,
         }
     }
 
-    /// Create an error for Matrix APIs that Ruma intentionally does not implement.
-    pub fn limited_rate<T: Into<Option<String>>>(message: T) -> Self {
+    /// Create an error for requests that exceed a rate limit and may be retried after the given
+    /// duration.
+    pub fn limited_rate<T: Into<Option<String>>>(message: T, retry_after: Duration) -> Self {
         let message = message.into();
         Self {
             errcode: ApiErrorCode::LimitExceeded,
             error: message.unwrap_or_else(|| "Too many retry!".to_string()),
+            retry_after_ms: Some(retry_after.as_millis() as u64),
+        }
+    }
+
+    /// Create an error for access tokens that are unknown or have been revoked.
+    pub fn unknown_token<T: Into<Option<String>>>(message: T) -> Self {
+        let message = message.into();
+        Self {
+            errcode: ApiErrorCode::UnknownToken,
+            error: message.unwrap_or_else(|| "Unrecognised access token.".to_string()),
+            retry_after_ms: None,
         }
     }
 
@@ -203,6 +285,7 @@ impl ApiError {
         Self {
             errcode: ApiErrorCode::Unknown,
             error: message.unwrap_or_else(|| "An unknown server-side error occurred.".to_string()),
+            retry_after_ms: None,
         }
     }
 }
@@ -235,6 +318,14 @@ impl From<DecodeError> for ApiError {
     }
 }
 
+impl From<ParamErr> for ApiError {
+    fn from(error: ParamErr) -> Self {
+        debug!("Converting to ApiError from: {:?}", error);
+
+        Self::unknown(None)
+    }
+}
+
 impl From<DieselError> for ApiError {
     fn from(error: DieselError) -> Self {
         debug!("Converting to ApiError from: {:?}", error);
@@ -271,7 +362,7 @@ impl From<R2d2Error> for ApiError {
     fn from(error: R2d2Error) -> Self {
         debug!("Converting to ApiError from: {:?}", error);
 
-        Self::unknown(None)
+        Self::unavailable(None)
     }
 }
 
@@ -325,6 +416,14 @@ impl Modifier<Response> for ApiError {
     fn modify(self, response: &mut Response) {
         response.headers.set(ContentType::json());
         response.status = Some(self.errcode.status_code());
+        if let Some(retry_after_ms) = self.retry_after_ms {
+            // Retry-After is given in whole seconds, so round up to not retry too early.
+            let retry_after_secs = (retry_after_ms + 999) / 1000;
+            response.headers.set_raw(
+                "Retry-After",
+                vec![retry_after_secs.to_string().into_bytes()],
+            );
+        }
         response.body = Some(Box::new(
             to_string(&self).expect("ApiError should always serialize"),
         ));
@@ -338,13 +437,18 @@ impl ApiErrorCode {
             ApiErrorCode::AliasTaken => Status::Conflict,
             ApiErrorCode::BadEvent | ApiErrorCode::BadJson => Status::UnprocessableEntity,
             ApiErrorCode::Forbidden | ApiErrorCode::GuestAccessForbidden => Status::Forbidden,
-            ApiErrorCode::InvalidParam | ApiErrorCode::MissingParam | ApiErrorCode::NotJson => {
-                Status::BadRequest
-            }
+            ApiErrorCode::BadAlias
+            | ApiErrorCode::InvalidParam
+            | ApiErrorCode::InvalidUsername
+            | ApiErrorCode::MissingParam
+            | ApiErrorCode::NotJson => Status::BadRequest,
             ApiErrorCode::LimitExceeded => Status::TooManyRequests,
             ApiErrorCode::NotFound | ApiErrorCode::Unimplemented => Status::NotFound,
+            ApiErrorCode::TooLarge => Status::PayloadTooLarge,
+            ApiErrorCode::Unavailable => Status::ServiceUnavailable,
             ApiErrorCode::Unknown => Status::InternalServerError,
             ApiErrorCode::UnknownToken => Status::Unauthorized,
+            ApiErrorCode::Unrecognized => Status::UnsupportedMediaType,
         }
     }
 }
@@ -356,18 +460,23 @@ impl Serialize for ApiErrorCode {
     {
         let value = match *self {
             ApiErrorCode::AliasTaken => "IO_RUMA_ALIAS_TAKEN",
+            ApiErrorCode::BadAlias => "M_BAD_ALIAS",
             ApiErrorCode::BadEvent => "IO_RUMA_BAD_EVENT",
             ApiErrorCode::BadJson => "M_BAD_JSON",
             ApiErrorCode::Forbidden => "M_FORBIDDEN",
             ApiErrorCode::GuestAccessForbidden => "M_GUEST_ACCESS_FORBIDDEN",
             ApiErrorCode::InvalidParam => "IO_RUMA_INVALID_PARAM",
+            ApiErrorCode::InvalidUsername => "M_INVALID_USERNAME",
             ApiErrorCode::LimitExceeded => "M_LIMIT_EXCEEDED",
             ApiErrorCode::MissingParam => "M_MISSING_PARAM",
             ApiErrorCode::NotFound => "M_NOT_FOUND",
             ApiErrorCode::NotJson => "M_NOT_JSON",
+            ApiErrorCode::TooLarge => "M_TOO_LARGE",
+            ApiErrorCode::Unavailable => "M_UNKNOWN",
             ApiErrorCode::Unimplemented => "IO_RUMA_UNIMPLEMENTED",
             ApiErrorCode::Unknown => "M_UNKNOWN",
             ApiErrorCode::UnknownToken => "M_UNKNOWN_TOKEN",
+            ApiErrorCode::Unrecognized => "M_UNRECOGNIZED",
         };
 
         serializer.serialize_str(value)
@@ -44,6 +44,8 @@ pub mod middleware;
 /// API endpoints as Iron handlers.
 pub mod api {
     pub mod r0;
+    pub mod ruma;
+    pub mod well_known;
 }
 pub mod authentication;
 pub mod config;
@@ -0,0 +1,29 @@
+//! Iron middleware to restrict endpoints to server administrators.
+
+use iron::{BeforeMiddleware, IronError, IronResult, Request};
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::models::user::User;
+
+/// Only lets users listed as `admins` in the configuration through.
+///
+/// Must be linked after `AccessTokenAuth`, since it checks the authenticated user.
+#[derive(Clone, Copy, Debug)]
+pub struct AdminOnly;
+
+impl BeforeMiddleware for AdminOnly {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        let config = Config::from_request(request)?;
+
+        let user = User::from_request(request)?;
+
+        if config.admins.contains(&user.id) {
+            Ok(())
+        } else {
+            Err(IronError::from(ApiError::unauthorized(
+                "Only server administrators may use this API".to_string(),
+            )))
+        }
+    }
+}
@@ -13,13 +13,15 @@ use crate::config::Config;
 use crate::db::DB;
 use crate::error::ApiError;
 use crate::models::access_token::AccessToken;
-use crate::models::user::User;
+use crate::models::user::{local_user_id, User};
 
 /// Handles access token authentication for all API endpoints that require it.
 #[derive(Clone, Copy, Debug)]
 pub struct AccessTokenAuth;
 
 /// Handles Matrix's interactive authentication protocol for all API endpoints that require it.
+///
+/// If linked after `AccessTokenAuth`, the credentials must belong to the user of the access token.
 #[derive(Clone, Debug)]
 pub struct UIAuth {
     /// The interactive authentication flows to use.
@@ -42,7 +44,7 @@ impl BeforeMiddleware for AccessTokenAuth {
         if let Some((_, ref token)) = query_pairs.find(|&(ref key, _)| key == "access_token") {
             let access_token = match AccessToken::find_valid_by_token(&connection, token)? {
                 Some(access_token) => access_token,
-                None => Err(ApiError::unauthorized("Unknown token".to_string()))?,
+                None => Err(ApiError::unknown_token("Unknown token".to_string()))?,
             };
 
             match User::find_active_user(&connection, &access_token.user_id)? {
@@ -78,7 +80,13 @@ impl BeforeMiddleware for UIAuth {
 
                     let connection = DB::from_request(request)?;
 
-                    if let Ok(user) = auth_params.authenticate(&connection) {
+                    let user = auth_params.authenticate(&connection)?;
+                    let is_token_user = request
+                        .extensions
+                        .get::<User>()
+                        .map_or(true, |token_user| token_user.id == user.id);
+
+                    if is_token_user {
                         request.extensions.insert::<User>(user);
 
                         return Ok(());
@@ -103,12 +111,10 @@ fn get_user_id_and_password(json: &Value, config: &Config) -> Result<(UserId, St
     match (username, password) {
         (Some(username), Some(password)) => match UserId::try_from(username) {
             Ok(user_id) => Ok((user_id, password.to_string())),
-            Err(_) => {
-                match UserId::try_from(format!("@{}:{}", username, &config.domain).as_ref()) {
-                    Ok(user_id) => Ok((user_id, password.to_string())),
-                    Err(_) => Err(()),
-                }
-            }
+            Err(_) => match local_user_id(username, &config.domain) {
+                Ok(user_id) => Ok((user_id, password.to_string())),
+                Err(_) => Err(()),
+            },
         },
         _ => Err(()),
     }
@@ -0,0 +1,83 @@
+//! Iron middleware to answer HEAD requests with the GET handlers.
+
+use iron::method::Method;
+use iron::typemap::Key;
+use iron::{AfterMiddleware, BeforeMiddleware, IronError, IronResult, Request, Response};
+
+/// Routes HEAD requests to the GET handler of the same path and drops the response body.
+///
+/// Must be linked both before and after the router.
+#[derive(Clone, Copy, Debug)]
+pub struct HeadRequest;
+
+impl Key for HeadRequest {
+    type Value = ();
+}
+
+impl BeforeMiddleware for HeadRequest {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        if request.method == Method::Head {
+            request.method = Method::Get;
+            request.extensions.insert::<Self>(());
+        }
+
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for HeadRequest {
+    fn after(&self, request: &mut Request<'_, '_>, mut response: Response) -> IronResult<Response> {
+        if request.extensions.contains::<Self>() {
+            response.body = None;
+        }
+
+        Ok(response)
+    }
+
+    fn catch(&self, request: &mut Request<'_, '_>, mut error: IronError) -> IronResult<Response> {
+        if request.extensions.contains::<Self>() {
+            error.response.body = None;
+        }
+
+        Err(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test::Test;
+    use iron::method::Method;
+    use iron::status::Status;
+
+    #[test]
+    fn head_versions() {
+        let test = Test::new();
+
+        let response = test.request(Method::Head, "/_matrix/client/versions", "");
+
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn head_profile() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let response = test.request(
+            Method::Head,
+            &format!("/_matrix/client/r0/profile/{}", carl.id),
+            "",
+        );
+        assert_eq!(response.status, Status::Ok);
+        assert!(response.body.is_empty());
+
+        let response = test.request(
+            Method::Head,
+            "/_matrix/client/r0/profile/@unknown:ruma.test",
+            "",
+        );
+        assert_eq!(response.status, Status::NotFound);
+        assert!(response.body.is_empty());
+    }
+}
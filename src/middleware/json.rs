@@ -1,15 +1,24 @@
 //! Iron middleware to handle verifying the presence of valid JSON in a request.
 
-use bodyparser;
-use iron::headers::ContentType;
+use std::io::{Error as IoError, ErrorKind};
+
+use bodyparser::{self, BodyError, BodyErrorCause};
+use iron::headers::{ContentLength, ContentType};
 use iron::mime::{Mime, SubLevel, TopLevel};
 use iron::typemap::Key;
 use iron::{BeforeMiddleware, IronResult, Plugin, Request};
 use serde_json::Value;
 
+use crate::config::Config;
 use crate::error::ApiError;
 
+/// The message of the I/O error bodyparser's `MaxBodyLength` fails with once a body exceeds it.
+const BODY_TOO_BIG: &str = "Body is too big";
+
 /// Ensures that requests contain valid JSON and stores the parsed JSON in the Iron request.
+///
+/// Bodies larger than the configured `max_body_bytes` are rejected with `M_TOO_LARGE`. Unless
+/// `strict_content_type` is disabled, the `Content-Type` must be `application/json`.
 #[derive(Clone, Copy, Debug)]
 pub struct JsonRequest;
 
@@ -19,21 +28,202 @@ impl Key for JsonRequest {
 
 impl BeforeMiddleware for JsonRequest {
     fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
-        if request
-            .headers
-            .get::<ContentType>()
-            .and_then(|content_type| match **content_type {
-                Mime(TopLevel::Application, SubLevel::Json, _) => Some(()),
-                _ => None,
-            })
-            .is_none()
-        {
-            Err(ApiError::wrong_content_type(None))?
+        let config = Config::from_request(request)?;
+
+        if config.strict_content_type {
+            if request
+                .headers
+                .get::<ContentType>()
+                .and_then(|content_type| match **content_type {
+                    Mime(TopLevel::Application, SubLevel::Json, _) => Some(()),
+                    _ => None,
+                })
+                .is_none()
+            {
+                Err(ApiError::wrong_content_type(None))?
+            }
+        } else if !request.headers.has::<ContentType>() {
+            // bodyparser does not read bodies without a `Content-Type` at all.
+            request.headers.set(ContentType::json());
+        }
+
+        let max_body_bytes = config.max_body_bytes;
+        let too_large = ApiError::too_large(format!(
+            "The request body must not be larger than {} bytes.",
+            max_body_bytes
+        ));
+
+        if let Some(&ContentLength(length)) = request.headers.get::<ContentLength>() {
+            if length > max_body_bytes as u64 {
+                Err(too_large.clone())?;
+            }
         }
 
+        // Bodies without a `Content-Length` are cut off by bodyparser's `MaxBodyLength`, which is
+        // linked with the same limit and surfaces as an I/O error.
         match request.get::<bodyparser::Json>() {
             Ok(Some(_)) => Ok(()),
+            Err(BodyError {
+                cause: BodyErrorCause::IoError(ref error),
+                ..
+            }) if is_body_too_big(error) => Err(too_large)?,
+            Err(BodyError {
+                cause: BodyErrorCause::IoError(error),
+                ..
+            }) => Err(ApiError::unknown(format!(
+                "Failed to read the request body: {}",
+                error
+            )))?,
             Ok(_) | Err(_) => Err(ApiError::not_json(None))?,
         }
     }
 }
+
+/// Whether an I/O error while reading a body is bodyparser's `MaxBodyLength` cutting it off.
+fn is_body_too_big(error: &IoError) -> bool {
+    error.kind() == ErrorKind::Other && error.to_string() == BODY_TOO_BIG
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error as IoError, ErrorKind};
+
+    use iron::headers::{ContentType, Headers};
+    use iron::method::Method;
+    use iron::status::Status;
+
+    use super::{is_body_too_big, BODY_TOO_BIG};
+    use crate::test::Test;
+
+    #[test]
+    fn only_the_body_limit_is_too_large() {
+        assert!(is_body_too_big(&IoError::new(
+            ErrorKind::Other,
+            BODY_TOO_BIG
+        )));
+        assert!(!is_body_too_big(&IoError::new(
+            ErrorKind::ConnectionReset,
+            "connection reset by peer"
+        )));
+        assert!(!is_body_too_big(&IoError::new(
+            ErrorKind::Other,
+            "unexpected end of file"
+        )));
+    }
+
+    #[test]
+    fn oversized_body() {
+        let test = Test::with_config(|config| config.max_body_bytes = 64);
+        let carl = test.create_user();
+
+        let topic = "a".repeat(64);
+        let response = test.post(
+            &format!("/_matrix/client/r0/createRoom?access_token={}", carl.token),
+            &format!(r#"{{"topic": "{}"}}"#, topic),
+        );
+
+        assert_eq!(response.status, Status::PayloadTooLarge);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_TOO_LARGE"
+        );
+    }
+
+    #[test]
+    fn body_within_limit() {
+        let test = Test::with_config(|config| config.max_body_bytes = 64);
+        let carl = test.create_user();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/createRoom?access_token={}", carl.token),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::Ok);
+    }
+
+    #[test]
+    fn missing_content_type_with_strict_content_type() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let response = test.request_with_headers(
+            Method::Post,
+            &format!("/_matrix/client/r0/createRoom?access_token={}", carl.token),
+            "{}",
+            Headers::new(),
+        );
+
+        assert_eq!(response.status, Status::UnsupportedMediaType);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNRECOGNIZED"
+        );
+    }
+
+    #[test]
+    fn wrong_content_type() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let mut headers = Headers::new();
+        headers.set(ContentType::plaintext());
+        let response = test.request_with_headers(
+            Method::Post,
+            &format!("/_matrix/client/r0/createRoom?access_token={}", carl.token),
+            "{}",
+            headers,
+        );
+
+        assert_eq!(response.status, Status::UnsupportedMediaType);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNRECOGNIZED"
+        );
+    }
+
+    #[test]
+    fn malformed_body() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        let response = test.post(
+            &format!("/_matrix/client/r0/createRoom?access_token={}", carl.token),
+            "{not json",
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_JSON"
+        );
+    }
+
+    #[test]
+    fn missing_content_type_without_strict_content_type() {
+        let test = Test::with_config(|config| config.strict_content_type = false);
+        let carl = test.create_user();
+
+        let response = test.request_with_headers(
+            Method::Post,
+            &format!("/_matrix/client/r0/createRoom?access_token={}", carl.token),
+            "{}",
+            Headers::new(),
+        );
+
+        assert_eq!(response.status, Status::Ok);
+
+        let response = test.request_with_headers(
+            Method::Post,
+            &format!("/_matrix/client/r0/createRoom?access_token={}", carl.token),
+            "not json",
+            Headers::new(),
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_JSON"
+        );
+    }
+}
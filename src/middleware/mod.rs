@@ -2,17 +2,23 @@
 
 use iron::Chain;
 
+mod admin;
 mod authentication;
+mod head_request;
 mod json;
 mod path_params;
+mod rate_limit;
 mod response_headers;
 
+pub use self::admin::AdminOnly;
 pub use self::authentication::{AccessTokenAuth, UIAuth};
+pub use self::head_request::HeadRequest;
 pub use self::json::JsonRequest;
 pub use self::path_params::{
-    DataTypeParam, EventTypeParam, FilterIdParam, RoomAliasIdParam, RoomIdOrAliasParam,
-    RoomIdParam, TagParam, TransactionIdParam, UserIdParam,
+    DataTypeParam, EventIdParam, EventTypeParam, FilterIdParam, RoomAliasIdParam,
+    RoomIdOrAliasParam, RoomIdParam, TagParam, TransactionIdParam, UserIdParam,
 };
+pub use self::rate_limit::RateLimit;
 pub use self::response_headers::ResponseHeaders;
 
 /// `middleware_chain!(JoinRoom, []);`
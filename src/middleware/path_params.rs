@@ -8,16 +8,39 @@ use iron::typemap::Key;
 use iron::{BeforeMiddleware, IronResult, Request};
 use router::Router;
 use ruma_events::EventType;
-use ruma_identifiers::{RoomAliasId, RoomId, RoomIdOrAliasId, UserId};
+use ruma_identifiers::{EventId, RoomAliasId, RoomId, RoomIdOrAliasId, UserId};
 
 use crate::config::Config;
 use crate::error::{ApiError, MapApiError};
 use url::percent_encoding::percent_decode;
 
+/// Return the value a path parameter middleware stored in the request.
+///
+/// Fails with an internal server error instead of panicking if the middleware did not run, e.g.
+/// because it is missing from the handler's chain.
+fn extracted_param<P>(request: &Request<'_, '_>, name: &str) -> Result<P::Value, ApiError>
+where
+    P: Key,
+    P::Value: Clone,
+{
+    request
+        .extensions
+        .get::<P>()
+        .cloned()
+        .ok_or_else(|| ApiError::unknown(format!("Path parameter '{}' was not extracted.", name)))
+}
+
 /// Extracts a `RoomId` from the URL path parameter `room_id`.
 #[derive(Clone, Copy, Debug)]
 pub struct RoomIdParam;
 
+impl RoomIdParam {
+    /// Return the `RoomId` extracted by this middleware.
+    pub fn from_request(request: &Request<'_, '_>) -> Result<RoomId, ApiError> {
+        extracted_param::<Self>(request, "room_id")
+    }
+}
+
 impl Key for RoomIdParam {
     type Value = RoomId;
 }
@@ -49,6 +72,13 @@ impl BeforeMiddleware for RoomIdParam {
 #[derive(Clone, Copy, Debug)]
 pub struct RoomIdOrAliasParam;
 
+impl RoomIdOrAliasParam {
+    /// Return the `RoomIdOrAliasId` extracted by this middleware.
+    pub fn from_request(request: &Request<'_, '_>) -> Result<RoomIdOrAliasId, ApiError> {
+        extracted_param::<Self>(request, "room_id_or_alias")
+    }
+}
+
 impl Key for RoomIdOrAliasParam {
     type Value = RoomIdOrAliasId;
 }
@@ -83,6 +113,13 @@ impl BeforeMiddleware for RoomIdOrAliasParam {
 #[derive(Clone, Copy, Debug)]
 pub struct UserIdParam;
 
+impl UserIdParam {
+    /// Return the `UserId` extracted by this middleware.
+    pub fn from_request(request: &Request<'_, '_>) -> Result<UserId, ApiError> {
+        extracted_param::<Self>(request, "user_id")
+    }
+}
+
 impl Key for UserIdParam {
     type Value = UserId;
 }
@@ -117,6 +154,13 @@ impl BeforeMiddleware for UserIdParam {
 #[derive(Clone, Copy, Debug)]
 pub struct DataTypeParam;
 
+impl DataTypeParam {
+    /// Return the data type extracted by this middleware.
+    pub fn from_request(request: &Request<'_, '_>) -> Result<String, ApiError> {
+        extracted_param::<Self>(request, "type")
+    }
+}
+
 impl Key for DataTypeParam {
     type Value = String;
 }
@@ -145,6 +189,13 @@ impl BeforeMiddleware for DataTypeParam {
 #[derive(Clone, Copy, Debug)]
 pub struct FilterIdParam;
 
+impl FilterIdParam {
+    /// Return the filter ID extracted by this middleware.
+    pub fn from_request(request: &Request<'_, '_>) -> Result<i64, ApiError> {
+        extracted_param::<Self>(request, "filter_id")
+    }
+}
+
 impl Key for FilterIdParam {
     type Value = i64;
 }
@@ -171,9 +222,19 @@ impl BeforeMiddleware for FilterIdParam {
 }
 
 /// Extracts `RoomAliasId` from the URL path parameter `room_alias`.
+///
+/// The parameter is either a full, percent-encoded alias like `#room:example.com` or just the
+/// alias' localpart, which is assumed to belong to this homeserver.
 #[derive(Clone, Copy, Debug)]
 pub struct RoomAliasIdParam;
 
+impl RoomAliasIdParam {
+    /// Return the `RoomAliasId` extracted by this middleware.
+    pub fn from_request(request: &Request<'_, '_>) -> Result<RoomAliasId, ApiError> {
+        extracted_param::<Self>(request, "room_alias")
+    }
+}
+
 impl Key for RoomAliasIdParam {
     type Value = RoomAliasId;
 }
@@ -192,7 +253,17 @@ impl BeforeMiddleware for RoomAliasIdParam {
             Some(room_alias) => {
                 debug!("room_alias param: {}", room_alias);
 
-                RoomAliasId::try_from(format!("#{}:{}", room_alias, config.domain).as_ref())
+                let room_alias = percent_decode(room_alias.as_bytes())
+                    .decode_utf8()
+                    .map_err(|err| ApiError::invalid_param("room_alias", err.description()))?;
+
+                let room_alias = if room_alias.starts_with('#') {
+                    room_alias.into_owned()
+                } else {
+                    format!("#{}:{}", room_alias, config.domain)
+                };
+
+                RoomAliasId::try_from(room_alias.as_ref())
                     .map_api_err(|err| ApiError::invalid_param("room_alias", err.description()))?
             }
             None => Err(ApiError::missing_param("room_alias"))?,
@@ -204,10 +275,67 @@ impl BeforeMiddleware for RoomAliasIdParam {
     }
 }
 
+/// Extracts an `EventId` from the URL path parameter `event_id`.
+///
+/// Event IDs without a hostname are assumed to belong to this homeserver.
+#[derive(Clone, Copy, Debug)]
+pub struct EventIdParam;
+
+impl EventIdParam {
+    /// Return the `EventId` extracted by this middleware.
+    pub fn from_request(request: &Request<'_, '_>) -> Result<EventId, ApiError> {
+        extracted_param::<Self>(request, "event_id")
+    }
+}
+
+impl Key for EventIdParam {
+    type Value = EventId;
+}
+
+impl BeforeMiddleware for EventIdParam {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        let params = request
+            .extensions
+            .get::<Router>()
+            .expect("Params object is missing")
+            .clone();
+
+        let config = Config::from_request(request)?;
+
+        let event_id = match params.find("event_id") {
+            Some(event_id) => {
+                let decoded_event_id = percent_decode(event_id.as_bytes())
+                    .decode_utf8()
+                    .map_err(|err| ApiError::invalid_param("event_id", err.description()))?;
+
+                EventId::try_from(decoded_event_id.as_ref())
+                    .or_else(|_| {
+                        EventId::try_from(
+                            format!("${}:{}", decoded_event_id, config.domain).as_ref(),
+                        )
+                    })
+                    .map_api_err(|err| ApiError::invalid_param("event_id", err.description()))?
+            }
+            None => Err(ApiError::missing_param("event_id"))?,
+        };
+
+        request.extensions.insert::<Self>(event_id);
+
+        Ok(())
+    }
+}
+
 /// Extracts `EventType` from the URL path parameter `event_type`.
 #[derive(Clone, Copy, Debug)]
 pub struct EventTypeParam;
 
+impl EventTypeParam {
+    /// Return the `EventType` extracted by this middleware.
+    pub fn from_request(request: &Request<'_, '_>) -> Result<EventType, ApiError> {
+        extracted_param::<Self>(request, "event_type")
+    }
+}
+
 impl Key for EventTypeParam {
     type Value = EventType;
 }
@@ -235,6 +363,13 @@ impl BeforeMiddleware for EventTypeParam {
 #[derive(Clone, Copy, Debug)]
 pub struct TagParam;
 
+impl TagParam {
+    /// Return the tag extracted by this middleware.
+    pub fn from_request(request: &Request<'_, '_>) -> Result<String, ApiError> {
+        extracted_param::<Self>(request, "tag")
+    }
+}
+
 impl Key for TagParam {
     type Value = String;
 }
@@ -261,6 +396,13 @@ impl BeforeMiddleware for TagParam {
 #[derive(Clone, Copy, Debug)]
 pub struct TransactionIdParam;
 
+impl TransactionIdParam {
+    /// Return the transaction ID extracted by this middleware.
+    pub fn from_request(request: &Request<'_, '_>) -> Result<String, ApiError> {
+        extracted_param::<Self>(request, "transaction_id")
+    }
+}
+
 impl Key for TransactionIdParam {
     type Value = String;
 }
@@ -284,3 +426,72 @@ impl BeforeMiddleware for TransactionIdParam {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::test::{Response, Test};
+    use iron::headers::Headers;
+    use iron::status::Status;
+    use iron::{IronResult, Request};
+    use iron_test::request;
+
+    use super::UserIdParam;
+
+    #[test]
+    fn missing_middleware_is_an_error_instead_of_a_panic() {
+        let handler = |request: &mut Request<'_, '_>| -> IronResult<iron::Response> {
+            UserIdParam::from_request(request)?;
+
+            Ok(iron::Response::with(Status::Ok))
+        };
+
+        let error = request::get("http://localhost/", Headers::new(), &handler)
+            .expect_err("the handler should fail without UserIdParam");
+        let response = Response::from_iron_response(error.response);
+
+        assert_eq!(response.status, Status::InternalServerError);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNKNOWN"
+        );
+    }
+
+    #[test]
+    fn malformed_room_id() {
+        let test = Test::new();
+        let carl = test.create_user();
+
+        for room_id in &["room", "!room", "%23room:ruma.test"] {
+            let response = test.get(&format!(
+                "/_matrix/client/r0/rooms/{}/members?access_token={}",
+                room_id, carl.token
+            ));
+
+            assert_eq!(response.status, Status::BadRequest);
+            assert_eq!(
+                response.json().get("errcode").unwrap().as_str().unwrap(),
+                "IO_RUMA_INVALID_PARAM"
+            );
+        }
+    }
+
+    #[test]
+    fn malformed_event_id() {
+        let test = Test::new();
+        let (carl, room_id) = test.initial_fixtures(r#"{"visibility": "public"}"#);
+
+        let response = test.put(
+            &format!(
+                "/_matrix/client/r0/rooms/{}/redact/$event:ruma.test:port/1?access_token={}",
+                room_id, carl.token
+            ),
+            "{}",
+        );
+
+        assert_eq!(response.status, Status::BadRequest);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "IO_RUMA_INVALID_PARAM"
+        );
+    }
+}
@@ -0,0 +1,245 @@
+//! Iron middleware to limit how often users may call an endpoint.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use iron::{BeforeMiddleware, IronError, IronResult, Request};
+use ruma_identifiers::UserId;
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::models::user::User;
+
+/// Limits the number of requests each user may make to an endpoint per minute or hour.
+///
+/// Must be linked after `AccessTokenAuth`, since requests are counted per authenticated user.
+/// Every `RateLimit` has its own bucket, so each route it is linked into is limited separately.
+/// Clones share the bucket of the original, so several routes can be limited together.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    /// Reads the maximum number of requests per user and window from the configuration.
+    ///
+    /// A value of 0 disables the limit.
+    max_requests: fn(&Config) -> u32,
+    /// The period of time in which at most `max_requests` requests are allowed.
+    window: Duration,
+    /// Whether the users listed as `admins` in the configuration are not limited.
+    exempt_admins: bool,
+    /// The requests counted so far.
+    requests: Arc<Mutex<Requests>>,
+}
+
+/// The requests counted by a `RateLimit`.
+#[derive(Debug, Default)]
+struct Requests {
+    /// The times of each user's requests within the last window.
+    by_user: HashMap<UserId, VecDeque<Instant>>,
+    /// When the users without requests in the last window were last removed.
+    swept_at: Option<Instant>,
+}
+
+impl Requests {
+    /// Drop the requests that are older than `window` at `now`.
+    fn expire(requests: &mut VecDeque<Instant>, window: Duration, now: Instant) {
+        while let Some(&oldest) = requests.front() {
+            if now.duration_since(oldest) < window {
+                break;
+            }
+
+            requests.pop_front();
+        }
+    }
+
+    /// Remove the users without requests in the last window, at most once per window, so that
+    /// users who stopped making requests don't stay in memory forever.
+    fn sweep(&mut self, window: Duration, now: Instant) {
+        if let Some(swept_at) = self.swept_at {
+            if now.duration_since(swept_at) < window {
+                return;
+            }
+        }
+
+        self.by_user.retain(|_, requests| {
+            Self::expire(requests, window, now);
+
+            !requests.is_empty()
+        });
+        self.swept_at = Some(now);
+    }
+}
+
+impl RateLimit {
+    /// Creates a new `RateLimit` per minute with its own bucket of requests.
+    pub fn new(max_per_minute: fn(&Config) -> u32) -> Self {
+        Self::with_window(max_per_minute, Duration::from_secs(60))
+    }
+
+    /// Creates a new `RateLimit` per hour with its own bucket of requests.
+    pub fn per_hour(max_per_hour: fn(&Config) -> u32) -> Self {
+        Self::with_window(max_per_hour, Duration::from_secs(60 * 60))
+    }
+
+    /// Don't limit the users listed as `admins` in the configuration.
+    pub fn exempt_admins(mut self) -> Self {
+        self.exempt_admins = true;
+        self
+    }
+
+    /// Creates a new `RateLimit` with the given window and its own bucket of requests.
+    fn with_window(max_requests: fn(&Config) -> u32, window: Duration) -> Self {
+        Self {
+            max_requests,
+            window,
+            exempt_admins: false,
+            requests: Arc::new(Mutex::new(Requests::default())),
+        }
+    }
+
+    /// Counts a request of the user made at `now` if it is within the limit.
+    ///
+    /// Otherwise, returns how long the user has to wait until the oldest request in the window
+    /// expires and the request is not counted.
+    fn record_request(
+        &self,
+        user_id: UserId,
+        max_requests: usize,
+        now: Instant,
+    ) -> Result<Option<Duration>, ApiError> {
+        let mut requests = self.requests.lock().map_err(ApiError::from)?;
+        requests.sweep(self.window, now);
+
+        let user_requests = requests
+            .by_user
+            .entry(user_id)
+            .or_insert_with(VecDeque::new);
+        Requests::expire(user_requests, self.window, now);
+
+        if user_requests.len() >= max_requests {
+            let oldest = *user_requests
+                .front()
+                .expect("there should be at least one request when the limit is reached");
+
+            return Ok(Some(self.window - now.duration_since(oldest)));
+        }
+
+        user_requests.push_back(now);
+
+        Ok(None)
+    }
+}
+
+impl BeforeMiddleware for RateLimit {
+    fn before(&self, request: &mut Request<'_, '_>) -> IronResult<()> {
+        let config = Config::from_request(request)?;
+        let max_requests = (self.max_requests)(&config) as usize;
+
+        if max_requests == 0 {
+            return Ok(());
+        }
+
+        let user_id = User::from_request(request)?.id;
+
+        if self.exempt_admins && config.admins.contains(&user_id) {
+            return Ok(());
+        }
+
+        if let Some(retry_after) = self.record_request(user_id, max_requests, Instant::now())? {
+            return Err(IronError::from(ApiError::limited_rate(
+                "Too many requests, try again later.".to_string(),
+                retry_after,
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::{Duration, Instant};
+
+    use ruma_identifiers::UserId;
+
+    use super::RateLimit;
+
+    #[test]
+    fn requests_allowed_again_after_window() {
+        let rate_limit = RateLimit::new(|_| 2);
+        let carl = UserId::try_from("@carl:ruma.test").unwrap();
+        let start = Instant::now();
+
+        assert!(rate_limit
+            .record_request(carl.clone(), 2, start)
+            .unwrap()
+            .is_none());
+        assert!(rate_limit
+            .record_request(carl.clone(), 2, start + Duration::from_secs(10))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            rate_limit
+                .record_request(carl.clone(), 2, start + Duration::from_secs(20))
+                .unwrap(),
+            Some(Duration::from_secs(40))
+        );
+
+        // The first request has left the window, the second one is still in it.
+        assert!(rate_limit
+            .record_request(carl.clone(), 2, start + Duration::from_secs(60))
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            rate_limit
+                .record_request(carl.clone(), 2, start + Duration::from_secs(61))
+                .unwrap(),
+            Some(Duration::from_secs(9))
+        );
+    }
+
+    #[test]
+    fn users_are_limited_separately() {
+        let rate_limit = RateLimit::per_hour(|_| 1);
+        let carl = UserId::try_from("@carl:ruma.test").unwrap();
+        let alice = UserId::try_from("@alice:ruma.test").unwrap();
+        let now = Instant::now();
+
+        assert!(rate_limit
+            .record_request(carl.clone(), 1, now)
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            rate_limit.record_request(carl, 1, now).unwrap(),
+            Some(Duration::from_secs(60 * 60))
+        );
+        assert!(rate_limit.record_request(alice, 1, now).unwrap().is_none());
+    }
+
+    #[test]
+    fn users_without_recent_requests_are_removed() {
+        let rate_limit = RateLimit::new(|_| 2);
+        let carl = UserId::try_from("@carl:ruma.test").unwrap();
+        let alice = UserId::try_from("@alice:ruma.test").unwrap();
+        let start = Instant::now();
+
+        assert!(rate_limit
+            .record_request(carl.clone(), 2, start)
+            .unwrap()
+            .is_none());
+        assert!(rate_limit
+            .record_request(alice.clone(), 2, start + Duration::from_secs(30))
+            .unwrap()
+            .is_none());
+
+        // Carl's only request has left the window, so only Alice is kept.
+        assert!(rate_limit
+            .record_request(alice.clone(), 2, start + Duration::from_secs(60))
+            .unwrap()
+            .is_none());
+
+        let requests = rate_limit.requests.lock().unwrap();
+        assert!(!requests.by_user.contains_key(&carl));
+        assert_eq!(requests.by_user[&alice].len(), 2);
+    }
+}
@@ -1,15 +1,53 @@
 //! Iron middleware to add HTTP headers to API responses.
 
+use std::str::from_utf8;
+
 use iron::headers::{
-    AccessControlAllowHeaders, AccessControlAllowMethods, AccessControlAllowOrigin, Server,
+    AccessControlAllowHeaders, AccessControlAllowMethods, AccessControlAllowOrigin,
+    AccessControlRequestHeaders, Server, Vary,
 };
 use iron::method::Method;
 use iron::{status, AfterMiddleware, IronError, IronResult, Request, Response};
 use unicase::UniCase;
 
 /// Adds a number of response headers to Ruma HTTP responses.
-#[derive(Clone, Copy, Debug)]
-pub struct ResponseHeaders;
+#[derive(Clone, Debug)]
+pub struct ResponseHeaders {
+    /// The origins allowed to make cross-origin requests. Any origin is allowed if empty.
+    cors_allowed_origins: Vec<String>,
+}
+
+impl ResponseHeaders {
+    /// Create a `ResponseHeaders` that allows cross-origin requests from the given origins.
+    pub fn new(cors_allowed_origins: Vec<String>) -> Self {
+        Self {
+            cors_allowed_origins,
+        }
+    }
+
+    /// The value of the `Access-Control-Allow-Origin` header for a request, if CORS is allowed.
+    fn allowed_origin(&self, request: &Request<'_, '_>) -> Option<AccessControlAllowOrigin> {
+        if self.cors_allowed_origins.is_empty() {
+            return Some(AccessControlAllowOrigin::Any);
+        }
+
+        let origin = request
+            .headers
+            .get_raw("Origin")
+            .and_then(|values| values.first())
+            .and_then(|value| from_utf8(value).ok())?;
+
+        if self
+            .cors_allowed_origins
+            .iter()
+            .any(|allowed_origin| allowed_origin == origin)
+        {
+            Some(AccessControlAllowOrigin::Value(origin.to_string()))
+        } else {
+            None
+        }
+    }
+}
 
 /// Adds a Server header to HTTP responses
 fn add_server_header(response: &mut Response) {
@@ -20,35 +58,73 @@ fn add_server_header(response: &mut Response) {
     )));
 }
 
-/// Adds Cross-Origin Resource Sharing headers to HTTP responses.
-fn add_cors_headers(response: &mut Response) {
-    response.headers.set(AccessControlAllowHeaders(vec![
+/// The request headers allowed in cross-origin requests unless a preflight asks for others.
+fn default_allowed_headers() -> Vec<UniCase<String>> {
+    vec![
         UniCase("accept".to_string()),
         UniCase("content-type".to_string()),
-    ]));
+    ]
+}
+
+/// Adds Cross-Origin Resource Sharing headers to HTTP responses.
+fn add_cors_headers(
+    response: &mut Response,
+    allowed_origin: AccessControlAllowOrigin,
+    allowed_headers: Vec<UniCase<String>>,
+) {
+    // Caches must not serve a response allowing one origin to another origin.
+    if allowed_origin != AccessControlAllowOrigin::Any {
+        response
+            .headers
+            .set(Vary::Items(vec![UniCase("origin".to_string())]));
+    }
+
+    response
+        .headers
+        .set(AccessControlAllowHeaders(allowed_headers));
     response.headers.set(AccessControlAllowMethods(vec![
         Method::Get,
         Method::Post,
         Method::Put,
         Method::Delete,
+        Method::Options,
     ]));
-    response.headers.set(AccessControlAllowOrigin::Any);
+    response.headers.set(allowed_origin);
 }
 
 impl AfterMiddleware for ResponseHeaders {
     fn after(&self, request: &mut Request<'_, '_>, mut response: Response) -> IronResult<Response> {
+        let mut allowed_headers = default_allowed_headers();
+
         if request.method == Method::Options {
             response = Response::with(status::Ok);
+
+            // Allow whatever headers the preflight request announces for the actual request.
+            if let Some(AccessControlRequestHeaders(requested_headers)) =
+                request.headers.get::<AccessControlRequestHeaders>()
+            {
+                allowed_headers = requested_headers.clone();
+            }
         }
         add_server_header(&mut response);
-        add_cors_headers(&mut response);
+
+        if let Some(allowed_origin) = self.allowed_origin(request) {
+            add_cors_headers(&mut response, allowed_origin, allowed_headers);
+        }
 
         Ok(response)
     }
 
-    fn catch(&self, _: &mut Request<'_, '_>, mut error: IronError) -> IronResult<Response> {
+    fn catch(&self, request: &mut Request<'_, '_>, mut error: IronError) -> IronResult<Response> {
         add_server_header(&mut error.response);
-        add_cors_headers(&mut error.response);
+
+        if let Some(allowed_origin) = self.allowed_origin(request) {
+            add_cors_headers(
+                &mut error.response,
+                allowed_origin,
+                default_allowed_headers(),
+            );
+        }
 
         Err(error)
     }
@@ -58,11 +134,26 @@ impl AfterMiddleware for ResponseHeaders {
 mod tests {
     use crate::test::{Response, Test};
     use iron::headers::{
-        AccessControlAllowHeaders, AccessControlAllowMethods, AccessControlAllowOrigin, Server,
+        AccessControlAllowHeaders, AccessControlAllowMethods, AccessControlAllowOrigin,
+        AccessControlRequestHeaders, Headers, Server,
     };
     use iron::method::Method;
+    use iron::status::Status;
     use unicase::UniCase;
 
+    fn get_versions_from_origin(test: &Test, origin: &str) -> Response {
+        let mut headers = Headers::new();
+        headers.set_raw("Origin", vec![origin.as_bytes().to_vec()]);
+
+        test.request_with_headers(Method::Get, "/_matrix/client/versions", "", headers)
+    }
+
+    fn test_with_allowed_origins() -> Test {
+        Test::with_config(|config| {
+            config.cors_allowed_origins = vec!["https://allowed.ruma.test".to_string()]
+        })
+    }
+
     fn check_for_modified_headers(response: &Response) {
         assert_eq!(
             response.headers.get::<Server>().unwrap(),
@@ -85,7 +176,8 @@ mod tests {
                 Method::Get,
                 Method::Post,
                 Method::Put,
-                Method::Delete
+                Method::Delete,
+                Method::Options
             ])
         );
         assert_eq!(
@@ -120,4 +212,74 @@ mod tests {
         // Check to see if the expected headers have been added to the response.
         check_for_modified_headers(&response);
     }
+
+    #[test]
+    fn preflight_echoes_requested_headers() {
+        let test = Test::new();
+        let mut headers = Headers::new();
+        headers.set(AccessControlRequestHeaders(vec![
+            UniCase("authorization".to_string()),
+            UniCase("content-type".to_string()),
+        ]));
+
+        let response = test.request_with_headers(
+            Method::Options,
+            "/_matrix/client/r0/account/whoami",
+            "",
+            headers,
+        );
+
+        assert_eq!(response.status, Status::Ok);
+        assert_eq!(
+            response.headers.get::<AccessControlAllowHeaders>().unwrap(),
+            &AccessControlAllowHeaders(vec![
+                UniCase("authorization".to_string()),
+                UniCase("content-type".to_string())
+            ])
+        );
+        assert!(response
+            .headers
+            .get::<AccessControlAllowMethods>()
+            .unwrap()
+            .contains(&Method::Options));
+    }
+
+    #[test]
+    fn allowed_origin() {
+        let test = test_with_allowed_origins();
+        let response = get_versions_from_origin(&test, "https://allowed.ruma.test");
+
+        assert_eq!(
+            response.headers.get::<AccessControlAllowOrigin>().unwrap(),
+            &AccessControlAllowOrigin::Value("https://allowed.ruma.test".to_string())
+        );
+        assert!(response
+            .headers
+            .get::<AccessControlAllowMethods>()
+            .is_some());
+    }
+
+    #[test]
+    fn disallowed_origin() {
+        let test = test_with_allowed_origins();
+        let response = get_versions_from_origin(&test, "https://evil.ruma.test");
+
+        assert!(response.headers.get::<AccessControlAllowOrigin>().is_none());
+        assert!(response
+            .headers
+            .get::<AccessControlAllowMethods>()
+            .is_none());
+        assert!(response.headers.get::<Server>().is_some());
+    }
+
+    #[test]
+    fn any_origin_without_allowed_origins() {
+        let test = Test::new();
+        let response = get_versions_from_origin(&test, "https://anywhere.ruma.test");
+
+        assert_eq!(
+            response.headers.get::<AccessControlAllowOrigin>().unwrap(),
+            &AccessControlAllowOrigin::Any
+        );
+    }
 }
@@ -7,11 +7,13 @@ use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::result::Error as DieselError;
 use iron::typemap::Key;
+use iron::Request;
 use macaroons::caveat::Caveat;
 use macaroons::token::Token;
 use macaroons::v1::V1Token;
 use ruma_identifiers::UserId;
 
+use crate::db::DB;
 use crate::error::ApiError;
 use crate::schema::access_tokens;
 
@@ -31,6 +33,8 @@ pub struct AccessToken {
     pub created_at: PgTimestamp,
     /// The time the access token was last modified.
     pub updated_at: PgTimestamp,
+    /// The ID of the device the access token was issued to, if the client specified one.
+    pub device_id: Option<String>,
 }
 
 /// A new access token, not yet saved.
@@ -41,18 +45,22 @@ pub struct NewAccessToken {
     pub user_id: UserId,
     /// The value of the access token. This is a Base64-encoded macaroon.
     pub value: String,
+    /// The ID of the device the access token is issued to.
+    pub device_id: Option<String>,
 }
 
 impl AccessToken {
-    /// Create a new `AccessToken` for the given user.
+    /// Create a new `AccessToken` for the given user, optionally linked to one of their devices.
     pub fn create(
         connection: &PgConnection,
         user_id: &UserId,
+        device_id: Option<String>,
         macaroon_secret_key: &[u8],
     ) -> Result<Self, ApiError> {
         let new_access_token = NewAccessToken {
             user_id: user_id.clone(),
             value: create_macaroon(macaroon_secret_key, user_id)?,
+            device_id,
         };
 
         diesel::insert_into(access_tokens::table)
@@ -68,10 +76,12 @@ impl AccessToken {
         connection: &PgConnection,
         token: &str,
     ) -> Result<Option<Self>, ApiError> {
-        let token = access_tokens::table
+        let query = access_tokens::table
             .filter(access_tokens::value.eq(token))
             .filter(access_tokens::revoked.eq(false))
-            .first(connection);
+            .limit(1);
+
+        let token = DB::instrument(query, |query| query.get_result(connection));
 
         match token {
             Ok(token) => Ok(Some(token)),
@@ -89,12 +99,45 @@ impl AccessToken {
             Err(error) => Err(ApiError::from(error)),
         }
     }
+
+    /// Revoke all access tokens of the given user at once, except for the one given as `keep`.
+    pub fn revoke_all_for_user(
+        connection: &PgConnection,
+        user_id: &UserId,
+        keep: Option<&Self>,
+    ) -> Result<(), ApiError> {
+        let mut query = diesel::update(access_tokens::table)
+            .filter(access_tokens::user_id.eq(user_id))
+            .set(access_tokens::revoked.eq(true))
+            .into_boxed();
+
+        if let Some(kept) = keep {
+            query = query.filter(access_tokens::id.ne(kept.id));
+        }
+
+        query
+            .execute(connection)
+            .map(|_| ())
+            .map_err(ApiError::from)
+    }
 }
 
 impl Key for AccessToken {
     type Value = Self;
 }
 
+impl AccessToken {
+    /// Return the access token that `AccessTokenAuth` authenticated the request with.
+    ///
+    /// Fails with an internal server error instead of panicking if the middleware did not run,
+    /// e.g. because it is missing from the handler's chain.
+    pub fn from_request(request: &Request<'_, '_>) -> Result<Self, ApiError> {
+        request.extensions.get::<Self>().cloned().ok_or_else(|| {
+            ApiError::unknown("No authenticated access token was found.".to_string())
+        })
+    }
+}
+
 /// Creates a macaroon for the given user using the master cryptographic key.
 fn create_macaroon(macaroon_secret_key: &[u8], user_id: &UserId) -> Result<String, ApiError> {
     let expiration = match Utc::now().checked_add_signed(Duration::hours(1)) {
@@ -1,5 +1,7 @@
 //! Matrix events.
 
+use std::cmp;
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 
 use diesel::dsl::{any, max};
@@ -11,7 +13,7 @@ use ruma_events::call::answer::AnswerEvent;
 use ruma_events::call::candidates::CandidatesEvent;
 use ruma_events::call::hangup::HangupEvent;
 use ruma_events::call::invite::InviteEvent;
-use ruma_events::collections::all::StateEvent;
+use ruma_events::collections::all::{RoomEvent as AnyRoomEvent, StateEvent};
 use ruma_events::room::aliases::AliasesEvent;
 use ruma_events::room::avatar::AvatarEvent;
 use ruma_events::room::canonical_alias::CanonicalAliasEvent;
@@ -23,6 +25,7 @@ use ruma_events::room::member::MemberEvent;
 use ruma_events::room::message::MessageEvent;
 use ruma_events::room::name::NameEvent;
 use ruma_events::room::power_levels::PowerLevelsEvent;
+use ruma_events::room::redaction::RedactionEvent;
 use ruma_events::room::third_party_invite::ThirdPartyInviteEvent;
 use ruma_events::room::topic::TopicEvent;
 use ruma_events::stripped::{
@@ -36,13 +39,15 @@ use ruma_events::{
     StateEvent as RumaStateEventTrait,
 };
 use ruma_identifiers::{EventId, RoomId, UserId};
-use serde_json::{from_str, to_string};
+use serde_json::{from_str, to_string, to_value, Map, Value};
 
 use crate::error::ApiError;
+use crate::models::access_token::AccessToken;
+use crate::models::presence_status::get_now;
 use crate::schema::events;
 
 /// A list of all the state events.
-const STATE_EVENTS: [EventType; 12] = [
+pub const STATE_EVENTS: [EventType; 12] = [
     EventType::RoomAliases,
     EventType::RoomAvatar,
     EventType::RoomCanonicalAlias,
@@ -73,6 +78,16 @@ pub struct NewEvent {
     pub sender: UserId,
     /// An event subtype that determines whether or not the event will overwrite a previous one.
     pub state_key: Option<String>,
+    /// The event redacted by this event, if it is a redaction.
+    pub redacts: Option<EventId>,
+    /// The transaction ID the sender used to send the event.
+    ///
+    /// Must only be returned to the device that sent the event.
+    pub transaction_id: Option<String>,
+    /// The ID of the access token that was used to send the event.
+    pub access_token_id: Option<i64>,
+    /// JSON of the content of the state event this event replaced, if any.
+    pub prev_content: Option<String>,
 }
 
 /// A Matrix event.
@@ -94,6 +109,16 @@ pub struct Event {
     pub content: String,
     /// The time the event was created.
     pub created_at: PgTimestamp,
+    /// The event redacted by this event, if it is a redaction.
+    pub redacts: Option<EventId>,
+    /// The transaction ID the sender used to send the event.
+    ///
+    /// Must only be returned to the device that sent the event.
+    pub transaction_id: Option<String>,
+    /// The ID of the access token that was used to send the event.
+    pub access_token_id: Option<i64>,
+    /// JSON of the content of the state event this event replaced, if any.
+    pub prev_content: Option<String>,
 }
 
 impl Event {
@@ -150,6 +175,227 @@ impl Event {
             })
     }
 
+    /// Return the redaction events for the given events, keyed by the ID of the redacted event.
+    pub fn find_redactions(
+        connection: &PgConnection,
+        event_ids: &[EventId],
+    ) -> Result<HashMap<EventId, Self>, ApiError> {
+        if event_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let redactions: Vec<Self> = events::table
+            .filter(events::event_type.eq(EventType::RoomRedaction.to_string()))
+            .filter(events::redacts.eq(any(event_ids)))
+            .order(events::ordering.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        Ok(redactions
+            .into_iter()
+            .filter_map(|redaction| {
+                let redacts = redaction.redacts.clone()?;
+
+                Some((redacts, redaction))
+            })
+            .collect())
+    }
+
+    /// Strip the content of every redacted event, and the `prev_content` of every event whose
+    /// previous state was redacted, down to the keys that survive a redaction.
+    ///
+    /// Returns the redaction events, keyed by the ID of the redacted event, so that they can be
+    /// attached to the events returned to clients.
+    pub fn apply_redactions(
+        connection: &PgConnection,
+        events: &mut [Self],
+    ) -> Result<HashMap<EventId, Self>, ApiError> {
+        let mut event_ids: Vec<EventId> = events.iter().map(|event| event.id.clone()).collect();
+        let mut prev_event_ids = HashMap::new();
+
+        for event in events.iter() {
+            if event.prev_content.is_none() {
+                continue;
+            }
+
+            let (room_id, state_key) = match (&event.room_id, &event.state_key) {
+                (Some(room_id), Some(state_key)) => (room_id, state_key),
+                _ => continue,
+            };
+
+            if let Some(prev_event) = Self::find_room_state_event(
+                connection,
+                room_id,
+                &event.event_type,
+                state_key,
+                Some(event),
+            )? {
+                event_ids.push(prev_event.id.clone());
+                prev_event_ids.insert(event.id.clone(), prev_event.id);
+            }
+        }
+
+        let redactions = Self::find_redactions(connection, &event_ids)?;
+
+        for event in events.iter_mut() {
+            if redactions.contains_key(&event.id) {
+                let content = redacted_content(&event.event_type, &event.content)?;
+                event.content = to_string(&content).map_err(ApiError::from)?;
+            }
+
+            let prev_event_redacted = prev_event_ids
+                .get(&event.id)
+                .map_or(false, |prev_event_id| {
+                    redactions.contains_key(prev_event_id)
+                });
+
+            if prev_event_redacted {
+                if let Some(ref prev_content) = event.prev_content {
+                    let prev_content = redacted_content(&event.event_type, prev_content)?;
+                    event.prev_content = Some(to_string(&prev_content).map_err(ApiError::from)?);
+                }
+            }
+        }
+
+        Ok(redactions)
+    }
+
+    /// Convert state events into the format returned to clients, with their redactions applied.
+    pub fn into_state_events(
+        connection: &PgConnection,
+        mut events: Vec<Self>,
+    ) -> Result<Vec<StateEvent>, ApiError> {
+        let mut redactions = Self::apply_redactions(connection, &mut events)?;
+
+        events
+            .into_iter()
+            .map(|event| match redactions.remove(&event.id) {
+                Some(redaction) => event.into_redacted_state_event(redaction),
+                None => event.try_into(),
+            })
+            .collect()
+    }
+
+    /// Convert a redacted state event into a state event for clients.
+    ///
+    /// See `into_redacted_room_event`.
+    pub fn into_redacted_state_event(self, redaction: Self) -> Result<StateEvent, ApiError> {
+        match self.into_redacted_room_event(redaction)? {
+            AnyRoomEvent::CustomState(event) => Ok(StateEvent::CustomState(event)),
+            _ => Err(ApiError::unknown(
+                "Redacted state event without state key.".to_string(),
+            )),
+        }
+    }
+
+    /// Convert a redacted event into a room event for the timeline.
+    ///
+    /// The content is stripped down to the keys that survive a redaction and the redaction event
+    /// is attached as `unsigned.redacted_because`.
+    pub fn into_redacted_room_event(self, redaction: Self) -> Result<AnyRoomEvent, ApiError> {
+        let content = redacted_content(&self.event_type, &self.content)?;
+        let redaction: RedactionEvent = redaction.try_into()?;
+
+        let mut unsigned = self.unsigned();
+        unsigned.insert(
+            "redacted_because".to_string(),
+            to_value(redaction).map_err(ApiError::from)?,
+        );
+
+        let event_type = EventType::from(self.event_type.as_ref());
+
+        let room_event = match self.state_key {
+            Some(state_key) => AnyRoomEvent::CustomState(CustomStateEvent {
+                content,
+                event_id: self.id,
+                event_type,
+                origin_server_ts: 0,
+                prev_content: None,
+                room_id: self.room_id,
+                sender: self.sender,
+                state_key,
+                unsigned: Some(Value::Object(unsigned)),
+            }),
+            None => AnyRoomEvent::CustomRoom(CustomRoomEvent {
+                content,
+                event_id: self.id,
+                event_type,
+                origin_server_ts: 0,
+                room_id: self.room_id,
+                sender: self.sender,
+                unsigned: Some(Value::Object(unsigned)),
+            }),
+        };
+
+        Ok(room_event)
+    }
+
+    /// The `unsigned` data of the event as returned to clients.
+    ///
+    /// Contains the event's `age`, the time in milliseconds since it was created, its
+    /// `transaction_id` if it is still set and the `prev_content` of the state it replaced, if
+    /// known.
+    fn unsigned(&self) -> Map<String, Value> {
+        let created_at = self.created_at.0 / 1000;
+        let age = cmp::max(0, get_now() - created_at);
+
+        let mut unsigned = Map::new();
+        unsigned.insert("age".to_string(), Value::from(age));
+
+        if let Some(ref transaction_id) = self.transaction_id {
+            unsigned.insert(
+                "transaction_id".to_string(),
+                Value::from(transaction_id.clone()),
+            );
+        }
+
+        if let Some(prev_content) = self
+            .prev_content
+            .as_ref()
+            .and_then(|prev_content| from_str::<Value>(prev_content).ok())
+        {
+            unsigned.insert("prev_content".to_string(), prev_content);
+        }
+
+        unsigned
+    }
+
+    /// Hide the event's transaction ID unless it was sent with the given access token.
+    pub fn hide_transaction_id_from_others(&mut self, access_token: &AccessToken) {
+        if self.access_token_id != Some(access_token.id) {
+            self.transaction_id = None;
+        }
+    }
+
+    /// Strip the content of all events sent by the given user down to the keys that survive a
+    /// redaction, e.g. when their account is erased.
+    ///
+    /// The `prev_content` of the events is removed as well. Returns the number of erased events.
+    pub fn erase_by_sender(connection: &PgConnection, sender: &UserId) -> Result<usize, ApiError> {
+        connection
+            .transaction::<usize, ApiError, _>(|| {
+                let events: Vec<Self> = events::table
+                    .filter(events::sender.eq(sender))
+                    .get_results(connection)
+                    .map_err(ApiError::from)?;
+
+                for event in &events {
+                    let content = redacted_content(&event.event_type, &event.content)?;
+
+                    diesel::update(events::table.find(&event.id))
+                        .set((
+                            events::content.eq(to_string(&content).map_err(ApiError::from)?),
+                            events::prev_content.eq(None::<String>),
+                        ))
+                        .execute(connection)
+                        .map_err(ApiError::from)?;
+                }
+
+                Ok(events.len())
+            })
+            .map_err(ApiError::from)
+    }
+
     /// Look up an event given its `EventId`.
     pub fn find(connection: &PgConnection, event_id: &EventId) -> Result<Option<Self>, ApiError> {
         match events::table.find(event_id).first(connection) {
@@ -159,6 +405,33 @@ impl Event {
         }
     }
 
+    /// Look up the room's state event with the given type and state key.
+    ///
+    /// If `until` is given, the state before that event is used instead of the current state.
+    pub fn find_room_state_event(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        event_type: &str,
+        state_key: &str,
+        until: Option<&Self>,
+    ) -> Result<Option<Self>, ApiError> {
+        let mut query = events::table
+            .filter(events::room_id.eq(room_id))
+            .filter(events::event_type.eq(event_type))
+            .filter(events::state_key.eq(state_key))
+            .into_boxed();
+
+        if let Some(until) = until {
+            query = query.filter(events::ordering.lt(until.ordering));
+        }
+
+        query
+            .order(events::ordering.desc())
+            .first(connection)
+            .optional()
+            .map_err(ApiError::from)
+    }
+
     /// Return the room's state before a specified event.
     pub fn get_room_state_events_until(
         connection: &PgConnection,
@@ -214,6 +487,37 @@ impl Event {
     }
 }
 
+/// Strip an event's content down to the keys that are preserved when the event is redacted.
+fn redacted_content(event_type: &str, content: &str) -> Result<Value, ApiError> {
+    let preserved_keys: &[&str] = match EventType::from(event_type) {
+        EventType::RoomAliases => &["aliases"],
+        EventType::RoomCreate => &["creator"],
+        EventType::RoomHistoryVisibility => &["history_visibility"],
+        EventType::RoomJoinRules => &["join_rule"],
+        EventType::RoomMember => &["membership"],
+        EventType::RoomPowerLevels => &[
+            "ban",
+            "events",
+            "events_default",
+            "kick",
+            "redact",
+            "state_default",
+            "users",
+            "users_default",
+        ],
+        _ => &[],
+    };
+
+    let content: Map<String, Value> = from_str(content).map_err(ApiError::from)?;
+
+    Ok(Value::Object(
+        content
+            .into_iter()
+            .filter(|(key, _)| preserved_keys.contains(&key.as_str()))
+            .collect(),
+    ))
+}
+
 macro_rules! impl_try_from_room_event_for_new_event {
     ($ty:ty) => {
         impl TryFrom<$ty> for NewEvent {
@@ -227,6 +531,10 @@ macro_rules! impl_try_from_room_event_for_new_event {
                     room_id: event.room_id().map(|room_id| room_id.clone()),
                     sender: event.sender().clone(),
                     state_key: None,
+                    redacts: None,
+                    transaction_id: None,
+                    access_token_id: None,
+                    prev_content: None,
                 })
             }
         }
@@ -246,6 +554,10 @@ macro_rules! impl_try_from_state_event_for_new_event {
                     room_id: event.room_id().map(|room_id| room_id.clone()),
                     sender: event.sender().clone(),
                     state_key: Some(event.state_key().to_string()),
+                    redacts: None,
+                    transaction_id: None,
+                    access_token_id: None,
+                    prev_content: None,
                 })
             }
         }
@@ -258,6 +570,8 @@ macro_rules! impl_try_into_room_event_for_event {
             type Error = ApiError;
 
             fn try_into(self) -> Result<$ty, Self::Error> {
+                let unsigned = self.unsigned();
+
                 Ok($ty {
                     content: from_str(&self.content).map_err(ApiError::from)?,
                     event_id: self.id,
@@ -270,7 +584,7 @@ macro_rules! impl_try_into_room_event_for_event {
                     origin_server_ts: 0,
                     room_id: self.room_id,
                     sender: self.sender,
-                    unsigned: None,
+                    unsigned: Some(Value::Object(unsigned)),
                 })
             }
         }
@@ -283,6 +597,8 @@ macro_rules! impl_try_into_state_event_for_event {
             type Error = ApiError;
 
             fn try_into(self) -> Result<$ty, Self::Error> {
+                let unsigned = self.unsigned();
+
                 Ok($ty {
                     content: from_str(&self.content).map_err(ApiError::from)?,
                     event_id: self.id,
@@ -296,8 +612,8 @@ macro_rules! impl_try_into_state_event_for_event {
                     prev_content: None,
                     room_id: self.room_id,
                     sender: self.sender,
-                    state_key: "".to_string(),
-                    unsigned: None,
+                    state_key: self.state_key.unwrap_or_default(),
+                    unsigned: Some(Value::Object(unsigned)),
                 })
             }
         }
@@ -312,7 +628,7 @@ macro_rules! impl_try_into_stripped_state_event_for_event {
             fn try_into(self) -> Result<$ty, Self::Error> {
                 Ok($ty {
                     content: from_str(&self.content).map_err(ApiError::from)?,
-                    state_key: "".to_string(),
+                    state_key: self.state_key.unwrap_or_default(),
                     event_type: EventType::from(self.event_type.as_ref()),
                     sender: self.sender,
                 })
@@ -379,6 +695,8 @@ impl TryInto<MemberEvent> for Event {
     type Error = ApiError;
 
     fn try_into(self) -> Result<MemberEvent, Self::Error> {
+        let unsigned = self.unsigned();
+
         Ok(MemberEvent {
             content: from_str(&self.content)?,
             event_id: self.id,
@@ -396,8 +714,51 @@ impl TryInto<MemberEvent> for Event {
             prev_content: None,
             room_id: self.room_id,
             sender: self.sender,
-            state_key: "".to_string(),
-            unsigned: None,
+            state_key: self.state_key.unwrap_or_default(),
+            unsigned: Some(Value::Object(unsigned)),
+        })
+    }
+}
+
+impl TryFrom<RedactionEvent> for NewEvent {
+    type Error = ApiError;
+
+    fn try_from(event: RedactionEvent) -> Result<Self, Self::Error> {
+        Ok(Self {
+            content: to_string(&event.content).map_err(ApiError::from)?,
+            event_type: EventType::RoomRedaction.to_string(),
+            id: event.event_id,
+            room_id: event.room_id,
+            sender: event.sender,
+            state_key: None,
+            redacts: Some(event.redacts),
+            transaction_id: None,
+            access_token_id: None,
+            prev_content: None,
+        })
+    }
+}
+
+impl TryInto<RedactionEvent> for Event {
+    type Error = ApiError;
+
+    fn try_into(self) -> Result<RedactionEvent, Self::Error> {
+        let unsigned = self.unsigned();
+        let redacts = self.redacts.ok_or_else(|| {
+            ApiError::unknown("Redaction event without redacted event.".to_string())
+        })?;
+
+        Ok(RedactionEvent {
+            content: from_str(&self.content)?,
+            event_id: self.id,
+            event_type: EventType::RoomRedaction,
+            // FIXME: This is a dummy value just to satisfy event types' new schema.
+            // See https://github.com/matrix-org/matrix-doc/issues/2064
+            origin_server_ts: 0,
+            redacts,
+            room_id: self.room_id,
+            sender: self.sender,
+            unsigned: Some(Value::Object(unsigned)),
         })
     }
 }
@@ -20,7 +20,8 @@ pub struct EventFilter {
     #[serde(default)]
     pub not_types: Vec<String>,
     /// The maximum number of events to return.
-    pub limit: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
     /// A list of senders IDs to include.
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default = "default_vec_user_id")]
@@ -35,6 +36,64 @@ pub struct EventFilter {
     pub not_senders: Vec<UserId>,
 }
 
+impl EventFilter {
+    /// Apply the filter to a list of events.
+    ///
+    /// `describe` returns the type of an event and its sender, if it has one.
+    pub fn apply<T, F>(&self, events: Vec<T>, describe: F) -> Vec<T>
+    where
+        F: Fn(&T) -> (String, Option<UserId>),
+    {
+        let mut events: Vec<T> = events
+            .into_iter()
+            .filter(|event| {
+                let (event_type, sender) = describe(event);
+
+                self.includes_type(&event_type)
+                    && sender.map_or(true, |sender| self.includes_sender(&sender))
+            })
+            .collect();
+
+        if let Some(limit) = self.limit {
+            events.truncate(limit);
+        }
+
+        events
+    }
+
+    /// Whether events of the given type pass the filter.
+    fn includes_type(&self, event_type: &str) -> bool {
+        if self
+            .not_types
+            .iter()
+            .any(|pattern| type_matches(pattern, event_type))
+        {
+            return false;
+        }
+
+        self.types.is_empty()
+            || self
+                .types
+                .iter()
+                .any(|pattern| type_matches(pattern, event_type))
+    }
+
+    /// Whether events sent by the given user pass the filter.
+    fn includes_sender(&self, sender: &UserId) -> bool {
+        !self.not_senders.contains(sender)
+            && (self.senders.is_empty() || self.senders.contains(sender))
+    }
+}
+
+/// Whether an event type matches a type pattern of a filter. A trailing '*' matches any suffix.
+fn type_matches(pattern: &str, event_type: &str) -> bool {
+    if pattern.ends_with('*') {
+        event_type.starts_with(&pattern[..pattern.len() - 1])
+    } else {
+        pattern == event_type
+    }
+}
+
 /// Defines the default format of a `RoomEventFilter`
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoomEventFilter {
@@ -110,6 +169,13 @@ pub struct RoomFilter {
     pub rooms: Vec<RoomId>,
 }
 
+impl RoomFilter {
+    /// Whether the room with the given ID passes the filter's `rooms` and `not_rooms` lists.
+    pub fn includes_room(&self, room_id: &RoomId) -> bool {
+        !self.not_rooms.contains(room_id) && (self.rooms.is_empty() || self.rooms.contains(room_id))
+    }
+}
+
 /// Predefined `EventFormat` types.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum EventFormat {
@@ -183,6 +249,47 @@ pub struct ContentFilter {
     pub event_fields: Vec<String>,
 }
 
+impl ContentFilter {
+    /// Check the parts of the filter that deserialization alone does not validate.
+    ///
+    /// Limits are unsigned, so negative values are already rejected while parsing. Each entry of
+    /// `event_fields` must be a dotted path of non-empty field names in which a backslash only
+    /// escapes a '.' or another backslash.
+    pub fn validate(&self) -> Result<(), ApiError> {
+        match self
+            .event_fields
+            .iter()
+            .find(|field| !is_valid_field_path(field))
+        {
+            Some(field) => Err(ApiError::bad_json(format!(
+                "Invalid path in event_fields: {}",
+                field
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Whether `path` is a well-formed dotted path to an event field.
+fn is_valid_field_path(path: &str) -> bool {
+    let mut segment_len = 0;
+    let mut chars = path.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('.') | Some('\\') => segment_len += 1,
+                _ => return false,
+            },
+            '.' if segment_len == 0 => return false,
+            '.' => segment_len = 0,
+            _ => segment_len += 1,
+        }
+    }
+
+    segment_len > 0
+}
+
 /// A new Matrix filter, not yet saved.
 #[derive(Debug, Insertable)]
 #[table_name = "filters"]
@@ -221,7 +328,26 @@ impl Filter {
         Ok(filter.id)
     }
 
+    /// Return the ID of a `Filter` of the given `UserId` with exactly the given content, if any.
+    pub fn find_id_by_content(
+        connection: &PgConnection,
+        user_id: UserId,
+        content: &str,
+    ) -> Result<Option<i64>, ApiError> {
+        filters::table
+            .select(filters::id)
+            .filter(filters::user_id.eq(user_id))
+            .filter(filters::content.eq(content))
+            .order(filters::id.asc())
+            .first(connection)
+            .optional()
+            .map_err(ApiError::from)
+    }
+
     /// Return `Filter`'s for given `UserId` and `id`.
+    ///
+    /// A filter that exists but belongs to another user is not found either, and results in the
+    /// same error as a missing filter.
     pub fn find(connection: &PgConnection, user_id: UserId, id: i64) -> Result<Self, ApiError> {
         let filter = filters::table
             .filter(filters::id.eq(id))
@@ -230,8 +356,44 @@ impl Filter {
 
         match filter {
             Ok(filter) => Ok(filter),
-            Err(DieselError::NotFound) => Err(ApiError::not_found("".to_string())),
+            Err(DieselError::NotFound) => Err(Self::not_found()),
             Err(err) => Err(ApiError::from(err)),
         }
     }
+
+    /// The error for a filter that doesn't exist or belongs to another user.
+    ///
+    /// Both cases must look the same to clients, so the existence of other users' filters isn't
+    /// leaked.
+    pub fn not_found() -> ApiError {
+        ApiError::not_found("No filter with this ID was found.".to_string())
+    }
+
+    /// Return all `Filter`s of the given `UserId`, oldest first.
+    pub fn list_for_user(
+        connection: &PgConnection,
+        user_id: &UserId,
+    ) -> Result<Vec<Self>, ApiError> {
+        filters::table
+            .filter(filters::user_id.eq(user_id))
+            .order(filters::id.asc())
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Delete the `Filter` for given `UserId` and `id`.
+    pub fn delete(connection: &PgConnection, user_id: UserId, id: i64) -> Result<(), ApiError> {
+        let filter = filters::table
+            .filter(filters::id.eq(id))
+            .filter(filters::user_id.eq(user_id));
+
+        let deleted = diesel::delete(filter)
+            .execute(connection)
+            .map_err(ApiError::from)?;
+
+        match deleted {
+            0 => Err(Self::not_found()),
+            _ => Ok(()),
+        }
+    }
 }
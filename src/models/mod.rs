@@ -6,11 +6,14 @@ pub mod presence_list;
 pub mod presence_status;
 pub mod profile;
 pub mod pusher;
+pub mod receipt;
 pub mod room;
 pub mod room_alias;
 pub mod room_membership;
+pub mod room_state;
 pub mod tags;
 pub mod transaction;
+pub mod typing;
 pub mod user;
 
 /// Helper function for skipping `false` fields when serializing with serde.
@@ -10,7 +10,7 @@ use ruma_events::EventType;
 use ruma_identifiers::UserId;
 
 use crate::error::ApiError;
-use crate::models::presence_status::{get_now, PresenceStatus};
+use crate::models::presence_status::PresenceStatus;
 use crate::models::profile::Profile;
 use crate::models::room_membership::RoomMembership;
 use crate::models::user::User;
@@ -141,7 +141,7 @@ impl PresenceList {
             presence_key = cmp::max(last_update, presence_key);
 
             let presence_state: PresenceState = status.presence.parse().unwrap();
-            let last_active_ago = get_now() - last_update;
+            let last_active_ago = status.last_active_ago();
 
             let profile: Option<&Profile> =
                 profiles.iter().find(|profile| profile.id == status.user_id);
@@ -159,7 +159,7 @@ impl PresenceList {
                     avatar_url,
                     currently_active: Some(PresenceState::Online == presence_state),
                     displayname,
-                    last_active_ago: Some(last_active_ago as u64),
+                    last_active_ago: Some(last_active_ago),
                     presence: presence_state,
                 },
                 event_type: EventType::Presence,
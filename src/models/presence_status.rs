@@ -1,5 +1,7 @@
 //! Storage and querying of presence status.
 
+use std::cmp;
+
 use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
 use diesel::dsl::any;
 use diesel::pg::data_types::PgTimestamp;
@@ -12,6 +14,10 @@ use ruma_identifiers::{EventId, UserId};
 use crate::error::ApiError;
 use crate::schema::presence_status;
 
+/// How long in milliseconds the last activity of a user whose presence did not change is left as
+/// it is before it is recorded again.
+const LAST_ACTIVE_GRANULARITY: i64 = 60_000;
+
 /// A Matrix presence status, not saved yet.
 #[derive(Debug, Clone, Insertable)]
 #[table_name = "presence_status"]
@@ -45,7 +51,7 @@ pub struct PresenceStatus {
     pub updated_at: PgTimestamp,
 }
 
-/// Return current time in milliseconds
+/// Return current UTC time in milliseconds.
 pub fn get_now() -> i64 {
     let now = Utc::now().naive_utc();
     get_milliseconds(now)
@@ -59,6 +65,13 @@ pub fn get_milliseconds(time: NaiveDateTime) -> i64 {
 }
 
 impl PresenceStatus {
+    /// The time in milliseconds since the last update.
+    ///
+    /// Clamped to 0 if the stored timestamp lies in the future, e.g. after the clock was adjusted.
+    pub fn last_active_ago(&self) -> u64 {
+        cmp::max(0, get_now() - self.updated_at.0) as u64
+    }
+
     /// Update or insert a presence status entry.
     pub fn upsert(
         connection: &PgConnection,
@@ -88,6 +101,51 @@ impl PresenceStatus {
             .map_err(ApiError::from)
     }
 
+    /// Record that a user is active with the given presence, e.g. because they are syncing.
+    ///
+    /// Unlike `upsert`, this keeps the status message and only writes if the presence changed or
+    /// the last activity was recorded more than `LAST_ACTIVE_GRANULARITY` ago. The event ID only
+    /// changes along with the presence, so that users who sync regularly don't wake up the syncs
+    /// of everyone watching their presence.
+    pub fn set_active(
+        connection: &PgConnection,
+        homeserver_domain: &str,
+        user_id: &UserId,
+        presence: PresenceState,
+    ) -> Result<(), ApiError> {
+        let presence = presence.to_string();
+
+        connection
+            .transaction::<(), ApiError, _>(|| match Self::find_by_uid(connection, user_id)? {
+                Some(ref status) if status.presence == presence => {
+                    let now = get_now();
+
+                    if now - status.updated_at.0 < LAST_ACTIVE_GRANULARITY {
+                        return Ok(());
+                    }
+
+                    diesel::update(status)
+                        .set(presence_status::updated_at.eq(PgTimestamp(now)))
+                        .execute(connection)
+                        .map_err(ApiError::from)?;
+
+                    Ok(())
+                }
+                Some(mut status) => {
+                    let event_id = EventId::new(homeserver_domain).map_err(ApiError::from)?;
+                    let status_msg = status.status_msg.clone();
+
+                    status.update(connection, presence.clone(), status_msg, &event_id)
+                }
+                None => {
+                    let event_id = EventId::new(homeserver_domain).map_err(ApiError::from)?;
+
+                    Self::create(connection, user_id, presence.clone(), None, &event_id)
+                }
+            })
+            .map_err(ApiError::from)
+    }
+
     /// Update a presence status entry.
     fn update(
         &mut self,
@@ -143,6 +201,26 @@ impl PresenceStatus {
         }
     }
 
+    /// Set the presence of every user who hasn't been active for longer than `older_than` to
+    /// offline, returning the number of updated entries.
+    ///
+    /// The last active timestamp is left untouched, so `last_active_ago` stays accurate.
+    pub fn mark_stale_offline(
+        connection: &PgConnection,
+        older_than: Duration,
+    ) -> Result<usize, ApiError> {
+        let threshold = PgTimestamp(get_now() - older_than.num_milliseconds());
+
+        diesel::update(
+            presence_status::table
+                .filter(presence_status::updated_at.lt(threshold))
+                .filter(presence_status::presence.ne("offline")),
+        )
+        .set(presence_status::presence.eq("offline"))
+        .execute(connection)
+        .map_err(ApiError::from)
+    }
+
     /// Get status entries for a list of `UserId`'s which were updated after a
     /// specific point in time.
     pub fn get_users(
@@ -167,3 +245,149 @@ impl PresenceStatus {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use chrono::Duration;
+    use diesel::pg::data_types::PgTimestamp;
+    use diesel::prelude::*;
+    use ruma_events::presence::PresenceState;
+    use ruma_identifiers::{EventId, UserId};
+
+    use super::{get_now, NewPresenceStatus, PresenceStatus, LAST_ACTIVE_GRANULARITY};
+    use crate::schema::presence_status;
+    use crate::test::Test;
+
+    fn status_updated_at(updated_at: i64) -> PresenceStatus {
+        PresenceStatus {
+            user_id: UserId::try_from("@carl:ruma.test").unwrap(),
+            event_id: EventId::new("ruma.test").unwrap(),
+            presence: "online".to_string(),
+            status_msg: None,
+            updated_at: PgTimestamp(updated_at),
+        }
+    }
+
+    #[test]
+    fn last_active_ago_in_the_past() {
+        let status = status_updated_at(get_now() - 5_000);
+        let last_active_ago = status.last_active_ago();
+
+        assert!(last_active_ago >= 5_000);
+        assert!(last_active_ago < 5_500);
+    }
+
+    #[test]
+    fn last_active_ago_clamped_for_future_timestamp() {
+        let status = status_updated_at(get_now() + 60_000);
+
+        assert_eq!(status.last_active_ago(), 0);
+    }
+
+    #[test]
+    fn mark_stale_offline() {
+        let connection = Test::connection();
+
+        let stale_user_id = UserId::try_from("@carl:ruma.test").unwrap();
+        let active_user_id = UserId::try_from("@alice:ruma.test").unwrap();
+        let now = get_now();
+
+        diesel::insert_into(presence_status::table)
+            .values(&vec![
+                NewPresenceStatus {
+                    user_id: stale_user_id.clone(),
+                    event_id: EventId::new("ruma.test").unwrap(),
+                    presence: "online".to_string(),
+                    status_msg: None,
+                    updated_at: PgTimestamp(now - 10 * 60_000),
+                },
+                NewPresenceStatus {
+                    user_id: active_user_id.clone(),
+                    event_id: EventId::new("ruma.test").unwrap(),
+                    presence: "online".to_string(),
+                    status_msg: None,
+                    updated_at: PgTimestamp(now),
+                },
+            ])
+            .execute(&connection)
+            .unwrap();
+
+        let updated =
+            PresenceStatus::mark_stale_offline(&connection, Duration::minutes(5)).unwrap();
+        assert_eq!(updated, 1);
+
+        let stale = PresenceStatus::find_by_uid(&connection, &stale_user_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(stale.presence, "offline");
+        assert_eq!(stale.updated_at.0, now - 10 * 60_000);
+
+        let active = PresenceStatus::find_by_uid(&connection, &active_user_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(active.presence, "online");
+    }
+
+    #[test]
+    fn set_active_only_writes_changes() {
+        let connection = Test::connection();
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+
+        PresenceStatus::set_active(&connection, "ruma.test", &user_id, PresenceState::Online)
+            .unwrap();
+        let first = PresenceStatus::find_by_uid(&connection, &user_id)
+            .unwrap()
+            .unwrap();
+
+        PresenceStatus::set_active(&connection, "ruma.test", &user_id, PresenceState::Online)
+            .unwrap();
+        let unchanged = PresenceStatus::find_by_uid(&connection, &user_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(unchanged.event_id, first.event_id);
+        assert_eq!(unchanged.updated_at.0, first.updated_at.0);
+
+        PresenceStatus::set_active(
+            &connection,
+            "ruma.test",
+            &user_id,
+            PresenceState::Unavailable,
+        )
+        .unwrap();
+        let changed = PresenceStatus::find_by_uid(&connection, &user_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(changed.presence, "unavailable");
+        assert_ne!(changed.event_id, first.event_id);
+    }
+
+    #[test]
+    fn set_active_refreshes_stale_last_active() {
+        let connection = Test::connection();
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+        let event_id = EventId::new("ruma.test").unwrap();
+        let stale = get_now() - 2 * LAST_ACTIVE_GRANULARITY;
+
+        diesel::insert_into(presence_status::table)
+            .values(&NewPresenceStatus {
+                user_id: user_id.clone(),
+                event_id: event_id.clone(),
+                presence: "online".to_string(),
+                status_msg: Some("Lunch".to_string()),
+                updated_at: PgTimestamp(stale),
+            })
+            .execute(&connection)
+            .unwrap();
+
+        PresenceStatus::set_active(&connection, "ruma.test", &user_id, PresenceState::Online)
+            .unwrap();
+        let status = PresenceStatus::find_by_uid(&connection, &user_id)
+            .unwrap()
+            .unwrap();
+        assert_eq!(status.event_id, event_id);
+        assert_eq!(status.status_msg, Some("Lunch".to_string()));
+        assert!(status.updated_at.0 > stale);
+    }
+}
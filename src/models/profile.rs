@@ -1,6 +1,7 @@
 //! Matrix profile.
 
 use diesel::dsl::any;
+use diesel::pg::upsert::excluded;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::result::Error as DieselError;
@@ -28,15 +29,18 @@ impl Profile {
     pub fn update_avatar_url(
         connection: &PgConnection,
         homeserver_domain: &str,
+        presence_enabled: bool,
         user_id: UserId,
         avatar_url: Option<String>,
     ) -> Result<Self, ApiError> {
-        connection
-            .transaction::<Self, ApiError, _>(|| {
-                let maybe_profile = Self::find_by_uid(connection, &user_id)?;
-
-                let profile = if let Some(mut profile) = maybe_profile {
-                    profile.set_avatar_url(connection, avatar_url)?
+        Self::update(
+            connection,
+            homeserver_domain,
+            presence_enabled,
+            user_id.clone(),
+            |maybe_profile| {
+                if let Some(mut profile) = maybe_profile {
+                    profile.set_avatar_url(connection, avatar_url)
                 } else {
                     let new_profile = Self {
                         id: user_id.clone(),
@@ -44,29 +48,28 @@ impl Profile {
                         displayname: None,
                     };
 
-                    Self::create(connection, &new_profile)?
-                };
-
-                PresenceStatus::upsert(connection, homeserver_domain, &user_id, None, None)?;
-
-                Ok(profile)
-            })
-            .map_err(ApiError::from)
+                    Self::create(connection, &new_profile)
+                }
+            },
+        )
     }
 
     /// Update or Create a `Profile` entry with new displayname.
     pub fn update_displayname(
         connection: &PgConnection,
         homeserver_domain: &str,
+        presence_enabled: bool,
         user_id: UserId,
         displayname: Option<String>,
     ) -> Result<Self, ApiError> {
-        connection
-            .transaction::<Self, ApiError, _>(|| {
-                let maybe_profile = Self::find_by_uid(connection, &user_id)?;
-
-                let profile = if let Some(mut profile) = maybe_profile {
-                    profile.set_displayname(connection, displayname)?
+        Self::update(
+            connection,
+            homeserver_domain,
+            presence_enabled,
+            user_id.clone(),
+            |maybe_profile| {
+                if let Some(mut profile) = maybe_profile {
+                    profile.set_displayname(connection, displayname)
                 } else {
                     let new_profile = Self {
                         id: user_id.clone(),
@@ -74,10 +77,68 @@ impl Profile {
                         displayname,
                     };
 
-                    Self::create(connection, &new_profile)?
-                };
+                    Self::create(connection, &new_profile)
+                }
+            },
+        )
+    }
+
+    /// Clear the display name and avatar URL of the user, e.g. when their account is erased.
+    pub fn erase(
+        connection: &PgConnection,
+        homeserver_domain: &str,
+        presence_enabled: bool,
+        user_id: UserId,
+    ) -> Result<Self, ApiError> {
+        let empty_profile = Self {
+            id: user_id.clone(),
+            avatar_url: None,
+            displayname: None,
+        };
+
+        Self::update(
+            connection,
+            homeserver_domain,
+            presence_enabled,
+            user_id,
+            |_| {
+                diesel::insert_into(profiles::table)
+                    .values(&empty_profile)
+                    .on_conflict(profiles::id)
+                    .do_update()
+                    .set((
+                        profiles::avatar_url.eq(None::<String>),
+                        profiles::displayname.eq(None::<String>),
+                    ))
+                    .get_result(connection)
+                    .map_err(ApiError::from)
+            },
+        )
+    }
+
+    /// Apply a change to the user's `Profile` and propagate it.
+    ///
+    /// The joined rooms get new `m.room.member` events, while the presence status is written only
+    /// once no matter how many rooms the user is in, and not at all if presence is disabled.
+    fn update<F>(
+        connection: &PgConnection,
+        homeserver_domain: &str,
+        presence_enabled: bool,
+        user_id: UserId,
+        change: F,
+    ) -> Result<Self, ApiError>
+    where
+        F: FnOnce(Option<Self>) -> Result<Self, ApiError>,
+    {
+        connection
+            .transaction::<Self, ApiError, _>(|| {
+                let maybe_profile = Self::find_by_uid(connection, &user_id)?;
+                let profile = change(maybe_profile)?;
 
-                PresenceStatus::upsert(connection, homeserver_domain, &user_id, None, None)?;
+                Self::update_memberships(connection, homeserver_domain, user_id.clone())?;
+                if presence_enabled {
+                    PresenceStatus::upsert(connection, homeserver_domain, &user_id, None, None)?;
+                }
 
                 Ok(profile)
             })
@@ -113,19 +174,25 @@ impl Profile {
     }
 
     /// Update `RoomMembership`'s due to changed `Profile`.
+    ///
+    /// Only rooms the user has joined get a new `m.room.member` event.
     pub fn update_memberships(
         connection: &PgConnection,
         homeserver_domain: &str,
         user_id: UserId,
     ) -> Result<(), ApiError> {
-        let mut room_memberships = RoomMembership::find_by_uid(connection, user_id.clone())?;
+        let room_memberships = RoomMembership::find_by_uid(connection, user_id.clone())?;
 
-        for room_membership in &mut room_memberships {
+        for mut room_membership in room_memberships
+            .into_iter()
+            .filter(|room_membership| room_membership.membership == "join")
+        {
             let options = RoomMembershipOptions {
                 room_id: room_membership.room_id.clone(),
                 user_id: user_id.clone(),
                 sender: user_id.clone(),
                 membership: "join".to_string(),
+                is_direct: None,
             };
 
             room_membership.update(connection, homeserver_domain, options)?;
@@ -135,9 +202,15 @@ impl Profile {
     }
 
     /// Create a `Profile` entry.
+    ///
+    /// If the profile was created concurrently in the meantime, the existing entry is updated with
+    /// the fields of `new_profile` that are set instead.
     pub fn create(connection: &PgConnection, new_profile: &Self) -> Result<Self, ApiError> {
         diesel::insert_into(profiles::table)
             .values(new_profile)
+            .on_conflict(profiles::id)
+            .do_update()
+            .set((profiles::id.eq(excluded(profiles::id)), new_profile))
             .get_result(connection)
             .map_err(ApiError::from)
     }
@@ -167,3 +240,128 @@ impl Profile {
             .map_err(ApiError::from)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::dsl::sql;
+    use diesel::pg::PgConnection;
+    use diesel::prelude::*;
+    use diesel::sql_types::BigInt;
+    use ruma_identifiers::{RoomId, UserId};
+    use serde_json::Map;
+
+    use super::Profile;
+    use crate::models::presence_status::PresenceStatus;
+    use crate::models::room::{CreationOptions, NewRoom, Room, RoomPreset};
+    use crate::test::Test;
+
+    /// The number of rows inserted into or updated in `presence_status` in the current
+    /// transaction.
+    fn presence_status_writes(connection: &PgConnection) -> i64 {
+        diesel::select(sql::<BigInt>(
+            "(SELECT n_tup_ins + n_tup_upd FROM pg_stat_xact_user_tables \
+             WHERE relname = 'presence_status')",
+        ))
+        .get_result(connection)
+        .unwrap()
+    }
+
+    #[test]
+    fn create_merges_into_existing_profile() {
+        let connection = Test::connection();
+
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+
+        // A request that saw no profile creates it after another one already did.
+        let with_displayname = Profile {
+            id: user_id.clone(),
+            avatar_url: None,
+            displayname: Some("Carl".to_string()),
+        };
+        let with_avatar_url = Profile {
+            id: user_id.clone(),
+            avatar_url: Some("mxc://ruma.test/carl".to_string()),
+            displayname: None,
+        };
+
+        Profile::create(&connection, &with_displayname).unwrap();
+        let profile = Profile::create(&connection, &with_avatar_url).unwrap();
+
+        assert_eq!(profile.id, user_id);
+        assert_eq!(profile.displayname, Some("Carl".to_string()));
+        assert_eq!(profile.avatar_url, Some("mxc://ruma.test/carl".to_string()));
+    }
+
+    #[test]
+    fn update_without_presence() {
+        let connection = Test::connection();
+
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+
+        Profile::update_displayname(
+            &connection,
+            "ruma.test",
+            false,
+            user_id.clone(),
+            Some("Carl".to_string()),
+        )
+        .unwrap();
+        assert!(PresenceStatus::find_by_uid(&connection, &user_id)
+            .unwrap()
+            .is_none());
+
+        Profile::update_displayname(
+            &connection,
+            "ruma.test",
+            true,
+            user_id.clone(),
+            Some("Carl".to_string()),
+        )
+        .unwrap();
+        assert!(PresenceStatus::find_by_uid(&connection, &user_id)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn update_writes_presence_once_for_many_rooms() {
+        let connection = Test::connection();
+
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+
+        for _ in 0..3 {
+            let new_room = NewRoom {
+                id: RoomId::new("ruma.test").unwrap(),
+                user_id: user_id.clone(),
+                public: true,
+            };
+            let creation_options = CreationOptions {
+                alias: None,
+                creation_content: Map::new(),
+                federate: Some(true),
+                initial_state: None,
+                invite_list: None,
+                is_direct: None,
+                name: None,
+                preset: RoomPreset::PublicChat,
+                topic: None,
+            };
+            Room::create(&connection, &new_room, "ruma.test", &creation_options).unwrap();
+        }
+
+        let writes_before = presence_status_writes(&connection);
+
+        Profile::update_displayname(
+            &connection,
+            "ruma.test",
+            true,
+            user_id,
+            Some("Carl".to_string()),
+        )
+        .unwrap();
+
+        assert_eq!(presence_status_writes(&connection) - writes_before, 1);
+    }
+}
@@ -0,0 +1,204 @@
+//! Storage and querying of receipts.
+
+use chrono::NaiveDate;
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::result::Error as DieselError;
+use ruma_identifiers::{EventId, RoomId, UserId};
+use serde_json::{json, Map, Value};
+
+use crate::error::ApiError;
+use crate::models::presence_status::get_now;
+use crate::schema::receipts;
+
+/// The latest event a user has acknowledged in a room with a receipt of a certain type.
+#[derive(Debug, Clone, Insertable, Queryable)]
+#[table_name = "receipts"]
+pub struct Receipt {
+    /// The room the receipt belongs to.
+    pub room_id: RoomId,
+    /// The user who sent the receipt.
+    pub user_id: UserId,
+    /// The type of the receipt, e.g. "m.read".
+    pub receipt_type: String,
+    /// The acknowledged event.
+    pub event_id: EventId,
+    /// Timestamp of the last update.
+    pub updated_at: PgTimestamp,
+}
+
+impl Receipt {
+    /// Set the receipt of the given type for a user in a room, replacing an existing one.
+    pub fn upsert(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        user_id: &UserId,
+        receipt_type: &str,
+        event_id: &EventId,
+    ) -> Result<Self, ApiError> {
+        let receipt = Self {
+            room_id: room_id.clone(),
+            user_id: user_id.clone(),
+            receipt_type: receipt_type.to_string(),
+            event_id: event_id.clone(),
+            updated_at: PgTimestamp(get_now()),
+        };
+
+        diesel::insert_into(receipts::table)
+            .values(&receipt)
+            .on_conflict((receipts::room_id, receipts::user_id, receipts::receipt_type))
+            .do_update()
+            .set((
+                receipts::event_id.eq(&receipt.event_id),
+                receipts::updated_at.eq(receipt.updated_at),
+            ))
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the receipt of the given type for a user in a room.
+    pub fn find(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        user_id: &UserId,
+        receipt_type: &str,
+    ) -> Result<Option<Self>, ApiError> {
+        let receipt = receipts::table
+            .find((room_id, user_id, receipt_type))
+            .first(connection);
+
+        match receipt {
+            Ok(receipt) => Ok(Some(receipt)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
+    /// Return the receipts in a room that were updated after `since` and up to `now`, along with
+    /// the latest of their update times, if any.
+    pub fn find_by_room(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        since: Option<i64>,
+        now: i64,
+    ) -> Result<(Vec<Self>, Option<i64>), ApiError> {
+        let mut query = receipts::table
+            .filter(receipts::room_id.eq(room_id))
+            .filter(receipts::updated_at.le(PgTimestamp(now)))
+            .order((receipts::event_id, receipts::user_id))
+            .into_boxed();
+
+        if let Some(since) = since {
+            query = query.filter(receipts::updated_at.gt(PgTimestamp(since)));
+        }
+
+        let receipts: Vec<Self> = query.get_results(connection).map_err(ApiError::from)?;
+        let changed_at = receipts.iter().map(|receipt| receipt.updated_at.0).max();
+
+        Ok((receipts, changed_at))
+    }
+
+    /// The time of the last update in milliseconds since the Unix epoch.
+    pub fn timestamp(&self) -> i64 {
+        // Receipts store milliseconds since 2000-01-01, like presence.
+        let postgres_epoch = NaiveDate::from_ymd(2000, 1, 1).and_hms(0, 0, 0);
+        postgres_epoch.timestamp_millis() + self.updated_at.0
+    }
+
+    /// Build the content of an `m.receipt` event, which maps event IDs to the receipts for them.
+    pub fn event_content(receipts: &[Self]) -> Value {
+        let mut content = Map::new();
+
+        for receipt in receipts {
+            let event_receipts = content
+                .entry(receipt.event_id.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            let users = event_receipts
+                .as_object_mut()
+                .expect("receipts of an event should be an object")
+                .entry(receipt.receipt_type.clone())
+                .or_insert_with(|| Value::Object(Map::new()));
+
+            users
+                .as_object_mut()
+                .expect("receipts of a type should be an object")
+                .insert(
+                    receipt.user_id.to_string(),
+                    json!({ "ts": receipt.timestamp() }),
+                );
+        }
+
+        Value::Object(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::prelude::*;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+
+    use super::Receipt;
+    use crate::schema::receipts;
+    use crate::test::Test;
+
+    #[test]
+    fn upsert_overwrites_receipt() {
+        let connection = Test::connection();
+
+        let room_id = RoomId::try_from("!receipts:ruma.test").unwrap();
+        let alice = UserId::try_from("@alice:ruma.test").unwrap();
+        let first_event_id = EventId::new("ruma.test").unwrap();
+        let second_event_id = EventId::new("ruma.test").unwrap();
+
+        assert!(Receipt::find(&connection, &room_id, &alice, "m.read")
+            .unwrap()
+            .is_none());
+
+        let first =
+            Receipt::upsert(&connection, &room_id, &alice, "m.read", &first_event_id).unwrap();
+        assert_eq!(first.event_id, first_event_id);
+
+        let second =
+            Receipt::upsert(&connection, &room_id, &alice, "m.read", &second_event_id).unwrap();
+        assert_eq!(second.event_id, second_event_id);
+        assert!(second.updated_at.0 >= first.updated_at.0);
+
+        let receipt = Receipt::find(&connection, &room_id, &alice, "m.read")
+            .unwrap()
+            .unwrap();
+        assert_eq!(receipt.event_id, second_event_id);
+
+        let receipts: i64 = receipts::table.count().get_result(&connection).unwrap();
+        assert_eq!(receipts, 1);
+    }
+
+    #[test]
+    fn find_by_room_since() {
+        let connection = Test::connection();
+
+        let room_id = RoomId::try_from("!receipts:ruma.test").unwrap();
+        let alice = UserId::try_from("@alice:ruma.test").unwrap();
+        let event_id = EventId::new("ruma.test").unwrap();
+
+        let receipt = Receipt::upsert(&connection, &room_id, &alice, "m.read", &event_id).unwrap();
+        let updated_at = receipt.updated_at.0;
+
+        let (receipts, changed_at) =
+            Receipt::find_by_room(&connection, &room_id, None, updated_at).unwrap();
+        assert_eq!(receipts.len(), 1);
+        assert_eq!(changed_at, Some(updated_at));
+
+        let (receipts, changed_at) =
+            Receipt::find_by_room(&connection, &room_id, Some(updated_at), updated_at).unwrap();
+        assert!(receipts.is_empty());
+        assert_eq!(changed_at, None);
+
+        // Receipts updated after `now` are left for the next sync.
+        let (receipts, _) =
+            Receipt::find_by_room(&connection, &room_id, None, updated_at - 1).unwrap();
+        assert!(receipts.is_empty());
+    }
+}
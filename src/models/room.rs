@@ -20,6 +20,7 @@ use ruma_events::room::topic::{TopicEvent, TopicEventContent};
 use ruma_events::stripped::StrippedState;
 use ruma_events::EventType;
 use ruma_identifiers::{EventId, RoomAliasId, RoomId, UserId};
+use serde_json::{from_str, to_string, Map, Value};
 
 use crate::error::ApiError;
 use crate::models::event::{Event, NewEvent};
@@ -27,17 +28,24 @@ use crate::models::room_alias::{NewRoomAlias, RoomAlias};
 use crate::models::room_membership::RoomMembership;
 use crate::schema::{events, rooms};
 
+/// How often `Room::create` generates a new ID when the ID of the room is already taken.
+const MAX_ROOM_ID_RETRIES: usize = 5;
+
 /// Options provided by the user to customize the room upon creation.
 #[derive(Clone, Debug)]
 pub struct CreationOptions {
     /// An initial alias for the room.
     pub alias: Option<String>,
+    /// Extra keys to be added to the content of the m.room.create event.
+    pub creation_content: Map<String, Value>,
     /// Whether or not the room should be federated.
     pub federate: Option<bool>,
     /// A list of state events to set in the new room.
     pub initial_state: Option<Vec<StrippedState>>,
     /// A list of users to invite to the room.
     pub invite_list: Option<Vec<UserId>>,
+    /// Whether the invites are for a direct chat with the creator.
+    pub is_direct: Option<bool>,
     /// An initial name for the room.
     pub name: Option<String>,
     /// A convenience parameter for setting a few default state events.
@@ -96,29 +104,40 @@ pub enum RoomVisibility {
     Public,
 }
 
+/// Generate a random `RoomId` on the given homeserver.
+///
+/// The localpart consists of 18 random alphanumeric characters, so collisions are very unlikely.
+/// `Room::create` still retries with a new ID if one occurs.
+pub fn make_room_id(homeserver_domain: &str) -> Result<RoomId, ApiError> {
+    RoomId::new(homeserver_domain).map_err(ApiError::from)
+}
+
 impl Room {
     /// Creates a new room in the database.
     ///
+    /// If the ID of `new_room` is already taken, the room is created with a new ID from
+    /// `make_room_id` instead.
+    ///
     /// The creation order of the events should be the following:
     /// 1. Events set by presets.
     /// 2. Events listed in initial_state, in the order that they are listed.
     /// 3. Events implied by name and topic.
     /// 4. Invite events implied by invite and invite_3pid.
+    ///
+    /// Invitees that could not be invited do not prevent the room from being created. They are
+    /// returned along with the new room instead.
     pub fn create(
         connection: &PgConnection,
         new_room: &NewRoom,
         homeserver_domain: &str,
         creation_options: &CreationOptions,
-    ) -> Result<Self, ApiError> {
-        connection.transaction::<Self, ApiError, _>(|| {
-            let room: Self = diesel::insert_into(rooms::table)
-                .values(new_room)
-                .get_result(connection)
-                .map_err(ApiError::from)?;
+    ) -> Result<(Self, Vec<UserId>), ApiError> {
+        connection.transaction::<(Self, Vec<UserId>), ApiError, _>(|| {
+            let room = Self::insert_with_unique_id(connection, new_room, homeserver_domain)?;
 
             let mut new_events = Vec::new();
 
-            let new_create_event: NewEvent = CreateEvent {
+            let mut new_create_event: NewEvent = CreateEvent {
                 content: CreateEventContent {
                     creator: new_room.user_id.clone(),
                     federate: creation_options.federate,
@@ -133,6 +152,13 @@ impl Room {
                 unsigned: None,
             }.try_into()?;
 
+            if !creation_options.creation_content.is_empty() {
+                let mut content: Map<String, Value> =
+                    from_str(&new_create_event.content).map_err(ApiError::from)?;
+                content.extend(creation_options.creation_content.clone());
+                new_create_event.content = to_string(&content).map_err(ApiError::from)?;
+            }
+
             new_events.push(new_create_event);
 
             let mut is_canonical_alias_set = false;
@@ -435,7 +461,7 @@ impl Room {
                         invite: 50,
                         kick: 50,
                         redact: 50,
-                        state_default: 0,
+                        state_default: 50,
                         users: user_power,
                         users_default: 0,
                     },
@@ -492,11 +518,12 @@ impl Room {
                 RoomAlias::create(connection, homeserver_domain, &new_room_alias)?;
             }
 
-            if let Some(ref invite_list) = creation_options.invite_list {
-                RoomMembership::create_memberships(connection, &room, invite_list, homeserver_domain)?;
-            }
+            let failed_invites = match creation_options.invite_list {
+                Some(ref invite_list) => RoomMembership::create_memberships(connection, &room, invite_list, creation_options.is_direct, homeserver_domain)?,
+                None => Vec::new(),
+            };
 
-            Ok(room)
+            Ok((room, failed_invites))
         }).map_err(ApiError::from)
     }
 
@@ -536,6 +563,37 @@ impl Room {
         }
     }
 
+    /// Insert the room, generating a new ID for it as long as its ID is already taken.
+    fn insert_with_unique_id(
+        connection: &PgConnection,
+        new_room: &NewRoom,
+        homeserver_domain: &str,
+    ) -> Result<Self, ApiError> {
+        let mut room_id = new_room.id.clone();
+
+        for _ in 0..=MAX_ROOM_ID_RETRIES {
+            let room = diesel::insert_into(rooms::table)
+                .values(&NewRoom {
+                    id: room_id,
+                    user_id: new_room.user_id.clone(),
+                    public: new_room.public,
+                })
+                .on_conflict_do_nothing()
+                .get_result(connection)
+                .optional()
+                .map_err(ApiError::from)?;
+
+            match room {
+                Some(room) => return Ok(room),
+                None => room_id = make_room_id(homeserver_domain)?,
+            }
+        }
+
+        Err(ApiError::unknown(
+            "Failed to generate an unused room ID.".to_string(),
+        ))
+    }
+
     /// Look up a `Room` given the `RoomId`.
     pub fn find(connection: &PgConnection, room_id: &RoomId) -> Result<Option<Self>, ApiError> {
         let result = rooms::table.find(room_id).get_result(connection);
@@ -547,3 +605,97 @@ impl Room {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::prelude::*;
+    use ruma_identifiers::{RoomId, UserId};
+    use serde_json::{from_str, Map, Value};
+
+    use super::{CreationOptions, NewRoom, Room, RoomPreset};
+    use crate::models::event::Event;
+    use crate::schema::{events, rooms};
+    use crate::test::Test;
+
+    #[test]
+    fn create_retries_with_new_id_after_collision() {
+        let connection = Test::connection();
+
+        let room_id = RoomId::try_from("!taken:ruma.test").unwrap();
+        let new_room = NewRoom {
+            id: room_id.clone(),
+            user_id: UserId::try_from("@carl:ruma.test").unwrap(),
+            public: false,
+        };
+
+        diesel::insert_into(rooms::table)
+            .values(&new_room)
+            .execute(&connection)
+            .unwrap();
+
+        let creation_options = CreationOptions {
+            alias: None,
+            creation_content: Map::new(),
+            federate: Some(true),
+            initial_state: None,
+            invite_list: None,
+            is_direct: None,
+            name: None,
+            preset: RoomPreset::PrivateChat,
+            topic: None,
+        };
+
+        let (room, _) =
+            Room::create(&connection, &new_room, "ruma.test", &creation_options).unwrap();
+
+        assert_ne!(room.id, room_id);
+        assert_eq!(room.id.hostname().to_string(), "ruma.test");
+        assert!(Room::find(&connection, &room.id).unwrap().is_some());
+    }
+
+    #[test]
+    fn create_merges_creation_content() {
+        let connection = Test::connection();
+
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+        let new_room = NewRoom {
+            id: RoomId::new("ruma.test").unwrap(),
+            user_id: user_id.clone(),
+            public: false,
+        };
+
+        let mut creation_content = Map::new();
+        creation_content.insert("type".to_string(), Value::String("m.space".to_string()));
+
+        let creation_options = CreationOptions {
+            alias: None,
+            creation_content,
+            federate: Some(false),
+            initial_state: None,
+            invite_list: None,
+            is_direct: None,
+            name: None,
+            preset: RoomPreset::PrivateChat,
+            topic: None,
+        };
+
+        let (room, _) =
+            Room::create(&connection, &new_room, "ruma.test", &creation_options).unwrap();
+
+        let create_event: Event = events::table
+            .filter(events::room_id.eq(&room.id))
+            .filter(events::event_type.eq("m.room.create"))
+            .first(&connection)
+            .unwrap();
+        let content: Value = from_str(&create_event.content).unwrap();
+
+        assert_eq!(content.get("type").unwrap().as_str().unwrap(), "m.space");
+        assert!(!content.get("m.federate").unwrap().as_bool().unwrap());
+        assert_eq!(
+            content.get("creator").unwrap().as_str().unwrap(),
+            user_id.to_string()
+        );
+    }
+}
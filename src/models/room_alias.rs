@@ -94,6 +94,15 @@ impl RoomAlias {
             .map_err(ApiError::from)
     }
 
+    /// Look up a `RoomAlias` entry given its `RoomAliasId`.
+    pub fn find(connection: &PgConnection, alias: &RoomAliasId) -> Result<Option<Self>, ApiError> {
+        match room_aliases::table.find(alias).get_result(connection) {
+            Ok(room_alias) => Ok(Some(room_alias)),
+            Err(DieselError::NotFound) => Ok(None),
+            Err(err) => Err(ApiError::from(err)),
+        }
+    }
+
     /// Return the `RoomAlias` entry for given `RoomAliasId`.
     pub fn find_by_alias(connection: &PgConnection, alias: &RoomAliasId) -> Result<Self, ApiError> {
         room_aliases::table
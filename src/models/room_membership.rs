@@ -3,17 +3,21 @@
 use std::convert::TryInto;
 use std::error::Error;
 
+use diesel::dsl::{max, sql};
 use diesel::expression::dsl::any;
 use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::upsert::excluded;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::result::Error as DieselError;
+use diesel::sql_types::Text;
 use ruma_events::room::join_rules::JoinRule;
 use ruma_events::room::member::{MemberEvent, MemberEventContent, MembershipState};
 use ruma_events::EventType;
 use ruma_identifiers::{EventId, RoomId, UserId};
 use serde_json::{from_value, Value};
 
+use crate::db::DB;
 use crate::error::ApiError;
 use crate::models::event::{Event, NewEvent};
 use crate::models::profile::Profile;
@@ -32,6 +36,21 @@ pub struct RoomMembershipOptions {
     pub sender: UserId,
     /// The current membership state.
     pub membership: String,
+    /// Whether an invite is for a direct chat with the sender.
+    pub is_direct: Option<bool>,
+}
+
+/// Options for looking up the member events of a room.
+#[derive(Clone, Debug, Default)]
+pub struct MemberEventsOptions {
+    /// Return the memberships as they were at this room ordering key instead of the current ones.
+    pub at: Option<i64>,
+    /// Only return member events after this room ordering key.
+    pub from: Option<i64>,
+    /// The maximum number of member events to return.
+    pub limit: Option<i64>,
+    /// Only return member events with this membership state, e.g. "join".
+    pub membership: Option<String>,
 }
 
 /// A new Matrix room membership, not yet saved.
@@ -142,8 +161,16 @@ impl RoomMembership {
                     .execute(connection)
                     .map_err(ApiError::from)?;
 
+                // A user has at most one membership per room, so an existing one is replaced.
                 let memberships: Vec<Self> = diesel::insert_into(room_memberships::table)
                     .values(&new_memberships)
+                    .on_conflict((room_memberships::room_id, room_memberships::user_id))
+                    .do_update()
+                    .set((
+                        room_memberships::event_id.eq(excluded(room_memberships::event_id)),
+                        room_memberships::sender.eq(excluded(room_memberships::sender)),
+                        room_memberships::membership.eq(excluded(room_memberships::membership)),
+                    ))
                     .get_results(connection)
                     .map_err(ApiError::from)?;
                 Ok(memberships)
@@ -223,17 +250,53 @@ impl RoomMembership {
         Ok(room_memberships)
     }
 
-    /// Update an existing `RoomMembership` entry or insert a new one.
-    pub fn upsert(
+    /// Join a user to a room.
+    ///
+    /// Fails if the room doesn't exist, if the user is banned from it or if the room is
+    /// invite-only and the user is neither invited nor its creator.
+    pub fn join(
         connection: &PgConnection,
-        domain: &str,
-        options: RoomMembershipOptions,
+        homeserver_domain: &str,
+        room_id: RoomId,
+        user_id: &UserId,
     ) -> Result<Self, ApiError> {
-        let room_membership = Self::find(connection, &options.room_id, &options.user_id)?;
+        let room = match Room::find(connection, &room_id)? {
+            Some(room) => room,
+            None => Err(ApiError::unauthorized(
+                "The room was not found on this server".to_string(),
+            ))?,
+        };
 
-        match room_membership {
-            Some(mut entry) => entry.update(connection, domain, options),
-            None => Self::create(connection, domain, options),
+        let options = RoomMembershipOptions {
+            room_id,
+            user_id: user_id.clone(),
+            sender: user_id.clone(),
+            membership: "join".to_string(),
+            is_direct: None,
+        };
+
+        match Self::find(connection, &room.id, user_id)? {
+            Some(mut entry) => match entry.membership.as_str() {
+                "ban" => Err(ApiError::unauthorized(
+                    "User is banned from the room".to_string(),
+                )),
+                "join" | "invite" => entry.update(connection, homeserver_domain, options),
+                _ => {
+                    let join_rules_event =
+                        Event::find_room_join_rules_by_room_id(connection, room.id.clone())?;
+
+                    if join_rules_event.content.join_rule == JoinRule::Invite
+                        && *user_id != room.user_id
+                    {
+                        return Err(ApiError::unauthorized(
+                            "You are not invited to this room".to_string(),
+                        ));
+                    }
+
+                    entry.update(connection, homeserver_domain, options)
+                }
+            },
+            None => Self::create(connection, homeserver_domain, options),
         }
     }
 
@@ -248,7 +311,8 @@ impl RoomMembership {
     ) -> Result<Self, ApiError> {
         let profile = Profile::find_by_uid(connection, &options.user_id)?;
 
-        let event = Self::create_new_room_member_event(homeserver_domain, &options, profile)?;
+        let mut event = Self::create_new_room_member_event(homeserver_domain, &options, profile)?;
+        event.prev_content = Event::find(connection, &self.event_id)?.map(|event| event.content);
 
         self.membership = options.membership.clone();
         self.sender = options.sender.clone();
@@ -291,8 +355,7 @@ impl RoomMembership {
             content: MemberEventContent {
                 avatar_url,
                 displayname,
-                // TODO: This needs to be set based on options provided by new APIs that use it.
-                is_direct: None,
+                is_direct: options.is_direct,
                 membership,
                 third_party_invite: None,
             },
@@ -302,8 +365,8 @@ impl RoomMembership {
             origin_server_ts: 0,
             prev_content: None,
             room_id: Some(options.room_id.clone()),
-            sender: options.user_id.clone(),
-            state_key: format!("@{}:{}", options.user_id.clone(), &homeserver_domain),
+            sender: options.sender.clone(),
+            state_key: options.user_id.to_string(),
             unsigned: None,
         }
         .try_into()?;
@@ -312,64 +375,123 @@ impl RoomMembership {
     }
 
     /// Given a list of invited users create the appropriate membership entries and `m.room.member` events.
+    ///
+    /// Users that cannot be invited, because they are unknown or belong to another homeserver, are
+    /// skipped and returned.
     pub fn create_memberships(
         connection: &PgConnection,
         room: &Room,
         invite_list: &[UserId],
+        is_direct: Option<bool>,
         homeserver_domain: &str,
-    ) -> Result<(), ApiError> {
-        for invitee in invite_list {
-            if invitee.hostname().to_string() != homeserver_domain {
-                return Err(ApiError::unimplemented(
-                    "Federation is not yet supported.".to_string(),
-                ));
-            }
-        }
-        let missing_user_ids = User::find_missing_users(connection, invite_list)?;
-        if !missing_user_ids.is_empty() {
-            return Err(ApiError::bad_json(format!(
-                "Unknown users in invite list: {}",
-                &missing_user_ids
-                    .iter()
-                    .map(|user_id| user_id.to_string())
-                    .collect::<Vec<String>>()
-                    .join(", ")
-            )));
-        }
+    ) -> Result<Vec<UserId>, ApiError> {
+        let (local_user_ids, mut failed_user_ids): (Vec<UserId>, Vec<UserId>) = invite_list
+            .iter()
+            .cloned()
+            .partition(|invitee| invitee.hostname().to_string() == homeserver_domain);
+
+        let missing_user_ids = User::find_missing_users(connection, &local_user_ids)?;
 
-        let options = invite_list
+        let options = local_user_ids
             .iter()
+            .filter(|user_id| !missing_user_ids.contains(user_id))
             .map(|user_id| RoomMembershipOptions {
                 room_id: room.id.clone(),
                 user_id: user_id.clone(),
                 sender: room.user_id.clone(),
                 membership: "invite".to_string(),
+                is_direct,
             })
             .collect::<Vec<RoomMembershipOptions>>();
 
-        Self::create_many(connection, homeserver_domain, options)?;
+        if !options.is_empty() {
+            Self::create_many(connection, homeserver_domain, options)?;
+        }
 
-        Ok(())
+        failed_user_ids.extend(missing_user_ids);
+
+        Ok(failed_user_ids)
+    }
+
+    /// Return the user's membership in the room if its state is one of `allowed`.
+    ///
+    /// Fails with `M_FORBIDDEN` if the user has no membership in the room or a membership in a
+    /// state that is not allowed.
+    pub fn require_membership(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        user_id: &UserId,
+        allowed: &[&str],
+    ) -> Result<Self, ApiError> {
+        match Self::find(connection, room_id, user_id)? {
+            Some(membership) => {
+                if allowed.contains(&membership.membership.as_str()) {
+                    Ok(membership)
+                } else if membership.membership == "join" {
+                    Err(ApiError::unauthorized(format!(
+                        "The user {} has already joined the room",
+                        user_id
+                    )))
+                } else {
+                    Err(ApiError::unauthorized(format!(
+                        "The user {} has not joined the room",
+                        user_id
+                    )))
+                }
+            }
+            None => Err(ApiError::unauthorized(format!(
+                "The user {} is not a member of the room",
+                user_id
+            ))),
+        }
     }
 
     /// Return member events for a given `RoomId`.
+    ///
+    /// The events are ordered by their room ordering key. If a page of `options.limit` events was
+    /// returned, the ordering key to pass as `options.from` for the next page is returned as well.
     pub fn get_events_by_room(
         connection: &PgConnection,
         room_id: RoomId,
-    ) -> Result<Vec<MemberEvent>, ApiError> {
-        let event_ids = room_memberships::table
-            .filter(room_memberships::room_id.eq(room_id))
-            .select(room_memberships::event_id);
+        options: &MemberEventsOptions,
+    ) -> Result<(Option<i64>, Vec<MemberEvent>), ApiError> {
+        let member_event_type = EventType::RoomMember.to_string();
+
+        let orderings = events::table
+            .select(max(events::ordering))
+            .filter(events::room_id.eq(&room_id))
+            .filter(events::event_type.eq(&member_event_type))
+            .filter(events::ordering.le(options.at.unwrap_or(std::i64::MAX)))
+            .group_by(events::state_key);
+
+        let mut query = events::table
+            .filter(events::ordering.nullable().eq(any(orderings)))
+            .filter(events::ordering.gt(options.from.unwrap_or(0)))
+            .order(events::ordering.asc())
+            .limit(options.limit.unwrap_or(std::i64::MAX))
+            .into_boxed();
+
+        if let Some(ref membership) = options.membership {
+            query =
+                query.filter(sql::<Text>("events.content::jsonb ->> 'membership'").eq(membership));
+        }
 
-        let events: Vec<Event> = events::table
-            .filter(events::id.eq(any(event_ids)))
-            .get_results(connection)
-            .map_err(|err| match err {
-                DieselError::NotFound => ApiError::not_found(None),
-                _ => ApiError::from(err),
-            })?;
+        let mut events: Vec<Event> =
+            DB::instrument(query, |query| query.get_results(connection)).map_err(ApiError::from)?;
 
-        events.into_iter().map(TryInto::try_into).collect()
+        Event::apply_redactions(connection, &mut events)?;
+
+        let next_from = match (options.limit, events.last()) {
+            (Some(limit), Some(event)) if events.len() as i64 == limit => Some(event.ordering),
+            _ => None,
+        };
+
+        let events = events
+            .into_iter()
+            .map(TryInto::try_into)
+            .collect::<Result<Vec<MemberEvent>, ApiError>>()?;
+
+        Ok((next_from, events))
     }
 
     /// Return all `RoomMembership`'s for given `UserId`.
@@ -416,6 +538,50 @@ impl RoomMembership {
             .map_err(ApiError::from)
     }
 
+    /// Count the rooms in which the given `UserId` has the given membership state.
+    pub fn count_by_uid_and_state(
+        connection: &PgConnection,
+        user_id: &UserId,
+        membership: &str,
+    ) -> Result<i64, ApiError> {
+        room_memberships::table
+            .filter(room_memberships::user_id.eq(user_id))
+            .filter(room_memberships::membership.eq(membership))
+            .count()
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Return the `UserId`'s of the members of a room with the given membership state, sorted by
+    /// `UserId`.
+    pub fn find_user_ids_by_room_and_state(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        membership: &str,
+    ) -> Result<Vec<UserId>, ApiError> {
+        room_memberships::table
+            .filter(room_memberships::room_id.eq(room_id))
+            .filter(room_memberships::membership.eq(membership))
+            .order(room_memberships::user_id)
+            .select(room_memberships::user_id)
+            .get_results(connection)
+            .map_err(ApiError::from)
+    }
+
+    /// Count the members of a room with the given membership state.
+    pub fn count_by_room_and_state(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        membership: &str,
+    ) -> Result<i64, ApiError> {
+        room_memberships::table
+            .filter(room_memberships::room_id.eq(room_id))
+            .filter(room_memberships::membership.eq(membership))
+            .count()
+            .get_result(connection)
+            .map_err(ApiError::from)
+    }
+
     /// Return `RoomId`'s for given `UserId`'s.
     pub fn find_common_rooms(
         connection: &PgConnection,
@@ -455,3 +621,126 @@ impl RoomMembership {
             .map_err(ApiError::from)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::pg::PgConnection;
+    use diesel::prelude::*;
+    use ruma_identifiers::{EventId, RoomId, UserId};
+
+    use serde_json::Map;
+
+    use super::{NewRoomMembership, RoomMembership, RoomMembershipOptions};
+    use crate::models::room::{CreationOptions, NewRoom, Room, RoomPreset};
+    use crate::schema::room_memberships;
+    use crate::test::Test;
+
+    /// Set up a connection with a membership of the given state for `@carl:ruma.test`.
+    fn setup(membership: &str) -> (PgConnection, RoomId, UserId) {
+        let connection = Test::connection();
+
+        let room_id = RoomId::try_from("!room:ruma.test").unwrap();
+        let user_id = UserId::try_from("@carl:ruma.test").unwrap();
+
+        diesel::insert_into(room_memberships::table)
+            .values(&NewRoomMembership {
+                event_id: EventId::new("ruma.test").unwrap(),
+                room_id: room_id.clone(),
+                user_id: user_id.clone(),
+                sender: user_id.clone(),
+                membership: membership.to_string(),
+            })
+            .execute(&connection)
+            .unwrap();
+
+        (connection, room_id, user_id)
+    }
+
+    #[test]
+    fn require_membership_allows_join() {
+        let (connection, room_id, user_id) = setup("join");
+
+        let membership =
+            RoomMembership::require_membership(&connection, &room_id, &user_id, &["join"]).unwrap();
+
+        assert_eq!(membership.membership, "join");
+    }
+
+    #[test]
+    fn require_membership_rejects_leave() {
+        let (connection, room_id, user_id) = setup("leave");
+
+        let result = RoomMembership::require_membership(&connection, &room_id, &user_id, &["join"]);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn require_membership_allows_any_listed_state() {
+        let (connection, room_id, user_id) = setup("leave");
+
+        let result =
+            RoomMembership::require_membership(&connection, &room_id, &user_id, &["join", "leave"]);
+
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn require_membership_rejects_non_members() {
+        let (connection, room_id, _) = setup("join");
+        let other_user_id = UserId::try_from("@dan:ruma.test").unwrap();
+
+        let result =
+            RoomMembership::require_membership(&connection, &room_id, &other_user_id, &["join"]);
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn create_twice_keeps_a_single_membership() {
+        let connection = Test::connection();
+        let creator_id = UserId::try_from("@carl:ruma.test").unwrap();
+        let user_id = UserId::try_from("@mark:ruma.test").unwrap();
+
+        let new_room = NewRoom {
+            id: RoomId::new("ruma.test").unwrap(),
+            user_id: creator_id,
+            public: true,
+        };
+        let creation_options = CreationOptions {
+            alias: None,
+            creation_content: Map::new(),
+            federate: Some(true),
+            initial_state: None,
+            invite_list: None,
+            is_direct: None,
+            name: None,
+            preset: RoomPreset::PublicChat,
+            topic: None,
+        };
+        let (room, _) =
+            Room::create(&connection, &new_room, "ruma.test", &creation_options).unwrap();
+
+        for _ in 0..2 {
+            let options = RoomMembershipOptions {
+                room_id: room.id.clone(),
+                user_id: user_id.clone(),
+                sender: user_id.clone(),
+                membership: "join".to_string(),
+                is_direct: None,
+            };
+
+            RoomMembership::create(&connection, "ruma.test", options).unwrap();
+        }
+
+        let memberships: i64 = room_memberships::table
+            .filter(room_memberships::room_id.eq(&room.id))
+            .filter(room_memberships::user_id.eq(&user_id))
+            .count()
+            .get_result(&connection)
+            .unwrap();
+        assert_eq!(memberships, 1);
+    }
+}
@@ -0,0 +1,73 @@
+//! The current state of Matrix rooms.
+
+use std::collections::HashMap;
+
+use diesel::dsl::{any, max};
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use ruma_events::EventType;
+use ruma_identifiers::RoomId;
+
+use crate::db::DB;
+use crate::error::ApiError;
+use crate::models::event::{Event, STATE_EVENTS};
+use crate::schema::events;
+
+/// The current state events of a room.
+#[derive(Clone, Debug)]
+pub struct RoomState {
+    /// The room's ID.
+    pub room_id: RoomId,
+    /// The most recent event of each state event type in the room.
+    pub events: Vec<Event>,
+}
+
+impl RoomState {
+    /// Return the current state for each of the given rooms.
+    ///
+    /// The state of all rooms is loaded with a single query and grouped by `RoomId` afterwards.
+    /// Rooms without any state events are not included in the result.
+    pub fn find_for_rooms(
+        connection: &PgConnection,
+        room_ids: &[RoomId],
+    ) -> Result<HashMap<RoomId, Self>, ApiError> {
+        if room_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let state_events: Vec<String> = STATE_EVENTS.iter().map(EventType::to_string).collect();
+
+        let ordering = events::table
+            .select(max(events::ordering))
+            .filter(events::room_id.eq(any(room_ids)))
+            .filter(events::event_type.eq(any(state_events)))
+            .group_by((events::room_id, events::event_type));
+
+        let query = events::table
+            .filter(events::ordering.nullable().eq(any(ordering)))
+            .order(events::ordering.asc());
+
+        let events: Vec<Event> =
+            DB::instrument(query, |query| query.get_results(connection)).map_err(ApiError::from)?;
+
+        let mut room_states: HashMap<RoomId, Self> = HashMap::new();
+
+        for event in events {
+            let room_id = match event.room_id {
+                Some(ref room_id) => room_id.clone(),
+                None => continue,
+            };
+
+            room_states
+                .entry(room_id.clone())
+                .or_insert_with(|| Self {
+                    room_id,
+                    events: Vec::new(),
+                })
+                .events
+                .push(event);
+        }
+
+        Ok(room_states)
+    }
+}
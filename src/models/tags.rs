@@ -1,17 +1,65 @@
 //! Matrix room tags.
-use std::collections::HashMap;
+use std::cmp::Ordering;
 
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::result::Error as DieselError;
-use ruma_events::tag::TagInfo;
 use ruma_identifiers::{RoomId, UserId};
 use serde_json::de::from_str;
+use serde_json::Value;
 
 use crate::error::ApiError;
 use crate::models::room::Room;
 use crate::schema::{room_tags, rooms};
 
+/// Information about a tag.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TagInfo {
+    /// The position of the room among the rooms with the same tag, between 0 and 1.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order: Option<f64>,
+}
+
+impl TagInfo {
+    /// Extract the `TagInfo` from the JSON content of a tag.
+    ///
+    /// An `order` that is not a number is ignored.
+    fn from_content(content: &str) -> Self {
+        let order = from_str::<Value>(content)
+            .ok()
+            .and_then(|content| content.get("order").and_then(Value::as_f64));
+
+        Self { order }
+    }
+}
+
+/// Check that the `order` in the JSON content of a tag, if any, is a number between 0 and 1.
+pub fn validate_order(content: &Value) -> Result<(), ApiError> {
+    match content.get("order") {
+        None | Some(Value::Null) => Ok(()),
+        Some(order) => match order.as_f64() {
+            Some(order) if (0.0..=1.0).contains(&order) => Ok(()),
+            _ => Err(ApiError::bad_json(
+                "The order of a tag must be a number between 0 and 1.".to_string(),
+            )),
+        },
+    }
+}
+
+/// Compare tags by their order, placing tags without an order last.
+///
+/// Tags with the same order are sorted by name.
+fn compare_tags(a: &(String, TagInfo), b: &(String, TagInfo)) -> Ordering {
+    let by_order = match (a.1.order, b.1.order) {
+        (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    };
+
+    by_order.then_with(|| a.0.cmp(&b.0))
+}
+
 /// A new Matrix room tag, not yet saved.
 #[derive(Debug, Clone, Insertable)]
 #[table_name = "room_tags"]
@@ -43,12 +91,12 @@ pub struct RoomTag {
 }
 
 impl RoomTag {
-    /// Return `RoomTag`'s for given `UserId` and `RoomId`.
+    /// Return the tags for given `UserId` and `RoomId`, sorted by their order.
     pub fn find(
         connection: &PgConnection,
         user_id: UserId,
         room_id: RoomId,
-    ) -> Result<HashMap<String, TagInfo>, ApiError> {
+    ) -> Result<Vec<(String, TagInfo)>, ApiError> {
         rooms::table
             .find(room_id.to_string())
             .first::<Room>(&*connection)
@@ -69,13 +117,16 @@ impl RoomTag {
                 _ => ApiError::from(err),
             })?;
 
-        let mut map: HashMap<String, TagInfo> = HashMap::new();
-        for tag in tags {
-            let info = from_str(&tag.content).map_err(ApiError::from)?;
-            map.insert(tag.tag, info);
-        }
+        let mut tags: Vec<(String, TagInfo)> = tags
+            .into_iter()
+            .map(|tag| {
+                let info = TagInfo::from_content(&tag.content);
+                (tag.tag, info)
+            })
+            .collect();
+        tags.sort_by(compare_tags);
 
-        Ok(map)
+        Ok(tags)
     }
 
     /// Return `RoomTag` for given `UserId`, `RoomId` and `tag`.
@@ -155,32 +206,30 @@ impl RoomTag {
     }
 
     /// Delete a `RoomTag`.
+    ///
+    /// Deleting a tag that doesn't exist succeeds, as long as the room exists.
     pub fn delete(
         connection: &PgConnection,
         user_id: UserId,
         room_id: RoomId,
         tag: String,
     ) -> Result<(), ApiError> {
-        let tag = room_tags::table
-            .filter(room_tags::room_id.eq(room_id))
-            .filter(room_tags::user_id.eq(user_id))
-            .filter(room_tags::tag.eq(tag));
-        tag.clone()
-            .first::<Self>(connection)
+        rooms::table
+            .find(room_id.to_string())
+            .first::<Room>(&*connection)
             .map_err(|err| match err {
                 DieselError::NotFound => ApiError::not_found(
-                    "The given room_id does not correspond to a tag".to_string(),
+                    "The given room_id does not correspond to a room".to_string(),
                 ),
                 _ => ApiError::from(err),
             })?;
+        let tag = room_tags::table
+            .filter(room_tags::room_id.eq(room_id))
+            .filter(room_tags::user_id.eq(user_id))
+            .filter(room_tags::tag.eq(tag));
         diesel::delete(tag)
             .execute(connection)
-            .map_err(|err| match err {
-                DieselError::NotFound => ApiError::not_found(
-                    "The given user_id and room_id does not correspond to a tag".to_string(),
-                ),
-                _ => ApiError::from(err),
-            })?;
+            .map_err(ApiError::from)?;
         Ok(())
     }
 }
@@ -0,0 +1,166 @@
+//! Storage and querying of typing notifications.
+
+use diesel::pg::data_types::PgTimestamp;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use ruma_identifiers::{RoomId, UserId};
+
+use crate::error::ApiError;
+use crate::models::presence_status::get_now;
+use crate::schema::typing;
+
+/// A user who is currently typing in a room.
+#[derive(Debug, Clone, Insertable, Queryable)]
+#[table_name = "typing"]
+pub struct Typing {
+    /// The room the user is typing in.
+    pub room_id: RoomId,
+    /// The typing user.
+    pub user_id: UserId,
+    /// The point in time after which the user is no longer considered to be typing.
+    pub expires_at: PgTimestamp,
+    /// The point in time the user last started or stopped typing.
+    pub updated_at: PgTimestamp,
+}
+
+impl Typing {
+    /// Mark a user as typing in a room for the next `timeout` milliseconds.
+    ///
+    /// Entries of the room that already expired are removed along the way.
+    pub fn start(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        user_id: &UserId,
+        timeout: i64,
+    ) -> Result<(), ApiError> {
+        let now = get_now();
+        let new_typing = Self {
+            room_id: room_id.clone(),
+            user_id: user_id.clone(),
+            expires_at: PgTimestamp(now + timeout),
+            updated_at: PgTimestamp(now),
+        };
+
+        connection
+            .transaction::<(), ApiError, _>(|| {
+                diesel::delete(
+                    typing::table
+                        .filter(typing::room_id.eq(room_id))
+                        .filter(typing::expires_at.le(PgTimestamp(now))),
+                )
+                .execute(connection)
+                .map_err(ApiError::from)?;
+
+                diesel::insert_into(typing::table)
+                    .values(&new_typing)
+                    .on_conflict((typing::room_id, typing::user_id))
+                    .do_update()
+                    .set((
+                        typing::expires_at.eq(new_typing.expires_at),
+                        typing::updated_at.eq(new_typing.updated_at),
+                    ))
+                    .execute(connection)
+                    .map_err(ApiError::from)?;
+
+                Ok(())
+            })
+            .map_err(ApiError::from)
+    }
+
+    /// Mark a user as no longer typing in a room.
+    ///
+    /// The entry is kept until it is removed along with the other expired ones, so that syncs can
+    /// tell that the typing users changed.
+    pub fn stop(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        user_id: &UserId,
+    ) -> Result<(), ApiError> {
+        let now = PgTimestamp(get_now());
+
+        diesel::update(
+            typing::table
+                .filter(typing::room_id.eq(room_id))
+                .filter(typing::user_id.eq(user_id))
+                .filter(typing::expires_at.gt(now)),
+        )
+        .set((typing::expires_at.eq(now), typing::updated_at.eq(now)))
+        .execute(connection)
+        .map_err(ApiError::from)?;
+
+        Ok(())
+    }
+
+    /// Return the users typing in a room at `now`, along with the latest point in time after
+    /// `since` and up to `now` at which the typing users of the room changed, if any.
+    pub fn find_by_room(
+        connection: &PgConnection,
+        room_id: &RoomId,
+        since: Option<i64>,
+        now: i64,
+    ) -> Result<(Vec<UserId>, Option<i64>), ApiError> {
+        let entries: Vec<Self> = typing::table
+            .filter(typing::room_id.eq(room_id))
+            .order(typing::user_id)
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        let since = since.unwrap_or(i64::min_value());
+
+        // Users start typing when the entry is updated and stop when it expires.
+        let changed_at = entries
+            .iter()
+            .flat_map(|entry| vec![entry.updated_at.0, entry.expires_at.0])
+            .filter(|&time| time > since && time <= now)
+            .max();
+
+        let user_ids = entries
+            .into_iter()
+            .filter(|entry| entry.expires_at.0 > now)
+            .map(|entry| entry.user_id)
+            .collect();
+
+        Ok((user_ids, changed_at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use diesel::prelude::*;
+    use ruma_identifiers::{RoomId, UserId};
+
+    use super::Typing;
+    use crate::models::presence_status::get_now;
+    use crate::schema::typing;
+    use crate::test::Test;
+
+    #[test]
+    fn expired_entries_are_ignored_and_removed() {
+        let connection = Test::connection();
+
+        let room_id = RoomId::try_from("!typing:ruma.test").unwrap();
+        let alice = UserId::try_from("@alice:ruma.test").unwrap();
+        let bob = UserId::try_from("@bob:ruma.test").unwrap();
+
+        Typing::start(&connection, &room_id, &alice, -1_000).unwrap();
+        let (user_ids, _) = Typing::find_by_room(&connection, &room_id, None, get_now()).unwrap();
+        assert!(user_ids.is_empty());
+
+        Typing::start(&connection, &room_id, &bob, 30_000).unwrap();
+        let (user_ids, _) = Typing::find_by_room(&connection, &room_id, None, get_now()).unwrap();
+        assert_eq!(user_ids, vec![bob.clone()]);
+
+        // The expired entry was removed when the next user started typing.
+        let entries: i64 = typing::table.count().get_result(&connection).unwrap();
+        assert_eq!(entries, 1);
+
+        let before_stop = get_now() - 1;
+        Typing::stop(&connection, &room_id, &bob).unwrap();
+        let (user_ids, changed_at) =
+            Typing::find_by_room(&connection, &room_id, Some(before_stop), get_now()).unwrap();
+        assert!(user_ids.is_empty());
+        assert!(changed_at.is_some());
+    }
+}
@@ -1,13 +1,17 @@
 //! Matrix users.
 
 use std::collections::HashSet;
+use std::convert::TryFrom;
 
+use chrono::NaiveDate;
 use diesel::dsl::any;
 use diesel::pg::data_types::PgTimestamp;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
 use diesel::result::Error as DieselError;
+use diesel::sql_types::Text;
 use iron::typemap::Key;
+use iron::Request;
 use ruma_identifiers::UserId;
 
 use crate::crypto::verify_password;
@@ -15,6 +19,8 @@ use crate::error::ApiError;
 use crate::models::access_token::AccessToken;
 use crate::schema::users;
 
+sql_function!(fn lower(x: Text) -> Text);
+
 /// A Matrix user.
 #[derive(AsChangeset, Debug, Clone, Identifiable, Queryable)]
 #[table_name = "users"]
@@ -55,7 +61,8 @@ impl User {
                     .get_result(connection)
                     .map_err(ApiError::from)?;
 
-                let access_token = AccessToken::create(connection, &user.id, macaroon_secret_key)?;
+                let access_token =
+                    AccessToken::create(connection, &user.id, None, macaroon_secret_key)?;
 
                 Ok((user, access_token))
             })
@@ -63,6 +70,9 @@ impl User {
     }
 
     /// Verify that a `User` with the given `UserId` and plaintext password exists.
+    ///
+    /// A missing user and a wrong password fail alike, so that callers can pass the error on
+    /// without revealing which accounts exist.
     pub fn verify(
         connection: &PgConnection,
         id: &UserId,
@@ -70,19 +80,31 @@ impl User {
     ) -> Result<Self, ApiError> {
         match Self::find_active_user(connection, id)? {
             Some(user) => {
-                if !verify_password(user.password_hash.as_bytes(), plaintext_password)? {
+                if !user.verify_password(plaintext_password)? {
                     return Err(ApiError::unauthorized("Invalid credentials".to_string()));
                 }
 
                 Ok(user)
             }
-            None => Err(ApiError::not_found(format!(
-                "The user {} was not found on this server",
-                id
-            ))),
+            None => Err(ApiError::unauthorized("Invalid credentials".to_string())),
         }
     }
 
+    /// Whether the given plaintext password matches the user's password hash.
+    ///
+    /// The hashes are compared in constant time. A malformed stored hash is an error rather than
+    /// a mismatch, so that it isn't mistaken for a wrong password.
+    pub fn verify_password(&self, candidate: &str) -> Result<bool, ApiError> {
+        verify_password(self.password_hash.as_bytes(), candidate)
+    }
+
+    /// The time the user was created, in milliseconds since the Unix epoch.
+    pub fn created_at_millis(&self) -> i64 {
+        // PostgreSQL timestamps count microseconds since 2000-01-01.
+        let postgres_epoch = NaiveDate::from_ymd(2000, 1, 1).and_hms(0, 0, 0);
+        postgres_epoch.timestamp_millis() + self.created_at.0 / 1000
+    }
+
     /// Look up a registered `User` using the given `UserId`.
     pub fn find_registered_user(
         connection: &PgConnection,
@@ -97,6 +119,29 @@ impl User {
         }
     }
 
+    /// Resolve the `UserId` someone logs in with to the ID of a registered user.
+    ///
+    /// An exact match wins. Otherwise the ID is compared ignoring case, so that accounts
+    /// registered with uppercase characters in their localpart can also log in with the lowercase
+    /// spelling. If that isn't unambiguous, the given ID is returned unchanged.
+    pub fn find_login_id(connection: &PgConnection, id: &UserId) -> Result<UserId, ApiError> {
+        if Self::find_registered_user(connection, id)?.is_some() {
+            return Ok(id.clone());
+        }
+
+        let mut matches: Vec<UserId> = users::table
+            .filter(lower(users::id).eq(id.to_string().to_lowercase()))
+            .select(users::id)
+            .limit(2)
+            .get_results(connection)
+            .map_err(ApiError::from)?;
+
+        match matches.len() {
+            1 => Ok(matches.remove(0)),
+            _ => Ok(id.clone()),
+        }
+    }
+
     /// Look up an active `User` using the given `UserId`.
     ///
     /// A user stops being active when he deactivates his account.
@@ -148,3 +193,141 @@ impl User {
 impl Key for User {
     type Value = Self;
 }
+
+impl User {
+    /// Return the user that `AccessTokenAuth` authenticated for the request.
+    ///
+    /// Fails with an internal server error instead of panicking if the middleware did not run,
+    /// e.g. because it is missing from the handler's chain.
+    pub fn from_request(request: &Request<'_, '_>) -> Result<Self, ApiError> {
+        request
+            .extensions
+            .get::<Self>()
+            .cloned()
+            .ok_or_else(|| ApiError::unknown("No authenticated user was found.".to_string()))
+    }
+}
+
+/// Build the `UserId` of a local user from its localpart and the homeserver's domain.
+///
+/// The localpart may only contain the characters `a-z`, `0-9`, `.`, `_`, `=`, `-` and `/`.
+pub fn make_user_id(localpart: &str, domain: &str) -> Result<UserId, ApiError> {
+    if localpart.is_empty() {
+        return Err(ApiError::invalid_username("must not be empty"));
+    }
+
+    let is_valid_char = |c: char| match c {
+        'a'..='z' | '0'..='9' | '.' | '_' | '=' | '-' | '/' => true,
+        _ => false,
+    };
+
+    if !localpart.chars().all(is_valid_char) {
+        return Err(ApiError::invalid_username(
+            "may only contain the characters a-z, 0-9, '.', '_', '=', '-' and '/'",
+        ));
+    }
+
+    local_user_id(localpart, domain)
+}
+
+/// Build the `UserId` of an existing local user from its localpart and the homeserver's domain.
+///
+/// Unlike `make_user_id`, this accepts any localpart a `UserId` may have, such as ones with
+/// uppercase characters. Accounts registered before the localpart was restricted must still be
+/// able to log in.
+pub fn local_user_id(localpart: &str, domain: &str) -> Result<UserId, ApiError> {
+    UserId::try_from(format!("@{}:{}", localpart, domain).as_ref()).map_err(ApiError::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use iron::headers::Headers;
+    use iron::status::Status;
+    use iron::{IronResult, Request};
+    use iron_test::request;
+    use ruma_identifiers::UserId;
+    use serde_json::to_value;
+
+    use super::{local_user_id, make_user_id, NewUser, User};
+    use crate::crypto::hash_password;
+    use crate::test::{Response, Test};
+
+    #[test]
+    fn make_user_id_from_valid_localpart() {
+        let user_id = make_user_id("carl.o_=-/1", "ruma.test").unwrap();
+
+        assert_eq!(user_id.to_string(), "@carl.o_=-/1:ruma.test");
+    }
+
+    #[test]
+    fn make_user_id_rejects_invalid_localparts() {
+        for localpart in &["", "Carl", "carl:ruma.test", "@carl", "car l", "cärl"] {
+            assert!(make_user_id(localpart, "ruma.test").is_err());
+        }
+    }
+
+    #[test]
+    fn make_user_id_rejects_invalid_domain() {
+        assert!(make_user_id("carl", "").is_err());
+    }
+
+    #[test]
+    fn local_user_id_accepts_existing_mixed_case_localparts() {
+        let user_id = local_user_id("Carl", "ruma.test").unwrap();
+
+        assert_eq!(user_id.to_string(), "@Carl:ruma.test");
+    }
+
+    #[test]
+    fn verify_password() {
+        let connection = Test::connection();
+
+        let new_user = NewUser {
+            id: UserId::try_from("@carl:ruma.test").unwrap(),
+            password_hash: hash_password("secret", 3).unwrap(),
+        };
+        let (user, _) = User::create(&connection, &new_user, &[0; 32]).unwrap();
+
+        assert!(user.verify_password("secret").unwrap());
+        assert!(!user.verify_password("Secret").unwrap());
+        assert!(!user.verify_password("").unwrap());
+    }
+
+    #[test]
+    fn verify_password_with_malformed_hash() {
+        let connection = Test::connection();
+
+        let new_user = NewUser {
+            id: UserId::try_from("@carl:ruma.test").unwrap(),
+            password_hash: "not a hash".to_string(),
+        };
+        let (user, _) = User::create(&connection, &new_user, &[0; 32]).unwrap();
+
+        assert!(user.verify_password("secret").is_err());
+
+        // Logging in fails with a server error, not as if the password were wrong.
+        let error = User::verify(&connection, &user.id, "secret").unwrap_err();
+        assert_eq!(to_value(&error).unwrap()["errcode"], "M_UNKNOWN");
+    }
+
+    #[test]
+    fn from_request_without_authentication_is_an_error_instead_of_a_panic() {
+        let handler = |request: &mut Request<'_, '_>| -> IronResult<iron::Response> {
+            User::from_request(request)?;
+
+            Ok(iron::Response::with(Status::Ok))
+        };
+
+        let error = request::get("http://localhost/", Headers::new(), &handler)
+            .expect_err("the handler should fail without AccessTokenAuth");
+        let response = Response::from_iron_response(error.response);
+
+        assert_eq!(response.status, Status::InternalServerError);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_UNKNOWN"
+        );
+    }
+}
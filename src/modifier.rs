@@ -35,3 +35,10 @@ impl Modifier<Response> for EmptyResponse {
         response.status = Some(self.0);
     }
 }
+
+/// Create a `200 OK` response with an empty JSON object as its body.
+///
+/// API endpoints that have nothing to return should always respond with this.
+pub fn empty_response() -> Response {
+    Response::with(EmptyResponse(Status::Ok))
+}
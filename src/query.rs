@@ -15,14 +15,19 @@ use ruma_events::presence::PresenceState;
 use ruma_events::stripped::StrippedState;
 use ruma_events::EventType;
 use ruma_identifiers::RoomId;
-use serde_json::Value;
+use serde_json::{from_str, json, Value};
 
 use crate::error::ApiError;
+use crate::models::access_token::AccessToken;
+use crate::models::account_data::AccountData;
 use crate::models::event::Event;
 use crate::models::filter::{ContentFilter, RoomEventFilter, RoomFilter};
 use crate::models::presence_list::PresenceList;
-use crate::models::presence_status::PresenceStatus;
+use crate::models::presence_status::{get_now, PresenceStatus};
+use crate::models::receipt::Receipt;
 use crate::models::room_membership::RoomMembership;
+use crate::models::room_state::RoomState;
+use crate::models::typing::Typing;
 use crate::models::user::User;
 
 /// Counts of unread notifications for a room.
@@ -42,6 +47,10 @@ struct Timeline {
     /// True if the number of events returned was limited by the limit on the filter.
     limited: bool,
     /// A token that can be supplied to to the from parameter of the `rooms/{roomId}/messages` endpoint.
+    ///
+    /// Empty unless the timeline is limited. Ruma does not implement `/messages` yet, so the token
+    /// is not accepted anywhere so far. It is a `Batch` with the ordering of the first event in
+    /// the timeline as its room key.
     prev_batch: String,
 }
 
@@ -52,6 +61,16 @@ struct Events<T> {
     events: Vec<T>,
 }
 
+/// Account data of a user that is not associated with a room.
+#[derive(Debug, Clone, Serialize)]
+struct AccountDataEvent {
+    /// The type of the account data.
+    #[serde(rename = "type")]
+    event_type: String,
+    /// The account data itself.
+    content: Value,
+}
+
 /// Information about rooms the user has left or been banned from.
 #[derive(Debug, Clone, Serialize)]
 struct LeftRoom {
@@ -82,7 +101,7 @@ struct JoinedRoom {
     /// The private data that this user has attached to this room.
     account_data: Events<Value>,
     /// The ephemeral events in the room that aren't recorded in the timeline or
-    /// state of the room. e.g. typing.
+    /// state of the room, e.g. typing and receipts.
     ephemeral: Events<Value>,
 }
 
@@ -104,6 +123,8 @@ pub struct Sync {
     next_batch: String,
     /// The updates to the presence status of other users.
     presence: Events<PresenceEvent>,
+    /// The global private data created by this user.
+    account_data: Events<AccountDataEvent>,
     /// Updates to rooms.
     rooms: Rooms,
 }
@@ -113,7 +134,8 @@ pub struct Sync {
 pub struct Batch {
     /// The room ordering key.
     pub room_key: i64,
-    /// The presence ordering key.
+    /// The point in time in milliseconds up to which presence, typing and receipt updates were
+    /// returned.
     pub presence_key: i64,
 }
 
@@ -178,13 +200,48 @@ pub enum Context<'a> {
 }
 
 impl Sync {
+    /// Whether the response contains no updates at all.
+    pub fn is_empty(&self) -> bool {
+        self.presence.events.is_empty()
+            && self.account_data.events.is_empty()
+            && self.rooms.invite.is_empty()
+            && self.rooms.join.is_empty()
+            && self.rooms.leave.is_empty()
+    }
+
+    /// Mark the user as `set_presence`, or online if not given, for syncing.
+    ///
+    /// This is separate from `sync` so that the presence is only updated once per request, not
+    /// on every check while long-polling. Nothing is written unless the presence changed or the
+    /// last activity is stale, see `PresenceStatus::set_active`.
+    pub fn update_presence(
+        connection: &PgConnection,
+        homeserver_domain: &str,
+        user: &User,
+        set_presence: Option<PresenceState>,
+    ) -> Result<(), ApiError> {
+        let set_presence = match set_presence {
+            Some(set_presence) => set_presence,
+            None => PresenceState::Online,
+        };
+
+        PresenceStatus::set_active(connection, homeserver_domain, &user.id, set_presence)
+    }
+
     /// Query sync.
+    ///
+    /// This only reads from the database. If presence is disabled, no presence events are
+    /// returned.
     pub fn sync(
         connection: &PgConnection,
-        homeserver_domain: &str,
+        presence_enabled: bool,
         user: &User,
+        access_token: &AccessToken,
         options: SyncOptions,
     ) -> Result<Self, ApiError> {
+        // Typing and receipt updates are only returned up to this point, so that later ones are
+        // included in the next sync.
+        let now = get_now();
         let mut context = Context::Initial;
 
         if let Some(ref batch) = options.since {
@@ -195,51 +252,66 @@ impl Sync {
             }
         }
 
-        let filter_room = match options.filter {
-            Some(filter) => filter.room,
-            None => None,
+        let (filter_room, filter_presence, filter_account_data) = match options.filter {
+            Some(filter) => (filter.room, filter.presence, filter.account_data),
+            None => (None, None, None),
+        };
+
+        let (presence_key, mut presence) = if presence_enabled {
+            Self::get_presence_events(connection, user, &context)?
+        } else {
+            let presence_key = match context {
+                Context::Incremental(batch) | Context::FullState(batch) => batch.presence_key,
+                Context::Initial => 0,
+            };
+
+            (presence_key, Vec::new())
         };
 
-        let (presence_key, presence) = Self::get_presence_events(
-            connection,
-            homeserver_domain,
-            user,
-            options.set_presence,
-            &context,
-        )?;
+        // Clients track the user's own presence separately, so it is only included when the
+        // presence filter asks for it by listing the user in `senders`.
+        let own_presence_requested = filter_presence
+            .as_ref()
+            .map_or(false, |filter| filter.senders.contains(&user.id));
+
+        if !own_presence_requested {
+            presence.retain(|event| event.sender != user.id);
+        }
+
+        if let Some(filter) = filter_presence {
+            presence = filter.apply(presence, |event| {
+                (event.event_type.to_string(), Some(event.sender.clone()))
+            });
+        }
 
-        let (room_key, rooms) = Self::get_rooms_events(connection, user, filter_room, &context)?;
+        let mut account_data = Self::get_account_data_events(connection, user, &context)?;
+
+        if let Some(filter) = filter_account_data {
+            account_data = filter.apply(account_data, |event| (event.event_type.clone(), None));
+        }
+
+        let (room_key, ephemeral_key, rooms) =
+            Self::get_rooms_events(connection, user, access_token, filter_room, &context, now)?;
+        let presence_key = cmp::max(presence_key, ephemeral_key.unwrap_or(presence_key));
         let batch = Batch::new(room_key, presence_key);
         let state = Self {
             next_batch: batch.to_string(),
             presence: Events { events: presence },
+            account_data: Events {
+                events: account_data,
+            },
             rooms,
         };
 
         Ok(state)
     }
 
-    /// Return presence events for sync from database and options.
+    /// Return the presence events of the users on the presence list since the batch.
     fn get_presence_events(
         connection: &PgConnection,
-        homeserver_domain: &str,
         user: &User,
-        set_presence: Option<PresenceState>,
         context: &Context<'_>,
     ) -> Result<(i64, Vec<PresenceEvent>), ApiError> {
-        let set_presence = match set_presence {
-            Some(set_presence) => set_presence,
-            None => PresenceState::Online,
-        };
-
-        PresenceStatus::upsert(
-            connection,
-            homeserver_domain,
-            &user.id,
-            Some(set_presence),
-            None,
-        )?;
-
         let since = match *context {
             Context::Incremental(batch) | Context::FullState(batch) => Some(batch.presence_key),
             Context::Initial => None,
@@ -248,18 +320,52 @@ impl Sync {
         PresenceList::find_events_by_uid(connection, &user.id, since)
     }
 
+    /// Return the global account data for sync.
+    ///
+    /// Account data is not ordered, so it is only included in initial and full state syncs.
+    fn get_account_data_events(
+        connection: &PgConnection,
+        user: &User,
+        context: &Context<'_>,
+    ) -> Result<Vec<AccountDataEvent>, ApiError> {
+        if let Context::Incremental(_) = *context {
+            return Ok(Vec::new());
+        }
+
+        AccountData::get_by_uid(connection, &user.id)?
+            .into_iter()
+            .map(|account_data| {
+                Ok(AccountDataEvent {
+                    event_type: account_data.data_type,
+                    content: from_str(&account_data.content).map_err(ApiError::from)?,
+                })
+            })
+            .collect()
+    }
+
     /// Return rooms for sync from database and options.
     fn get_rooms_events(
         connection: &PgConnection,
         user: &User,
+        access_token: &AccessToken,
         room_filter: Option<RoomFilter>,
         context: &Context<'_>,
-    ) -> Result<(i64, Rooms), ApiError> {
+        now: i64,
+    ) -> Result<(i64, Option<i64>, Rooms), ApiError> {
         let mut join = HashMap::new();
         let mut invite = HashMap::new();
         let mut leave = HashMap::new();
 
-        let room_memberships = RoomMembership::find_all_by_uid(connection, &user.id)?;
+        // Only the rooms selected by the filter are processed any further.
+        let room_memberships: Vec<RoomMembership> =
+            RoomMembership::find_all_by_uid(connection, &user.id)?
+                .into_iter()
+                .filter(|room_membership| {
+                    room_filter.as_ref().map_or(true, |filter| {
+                        filter.includes_room(&room_membership.room_id)
+                    })
+                })
+                .collect();
 
         let mut room_ordering = match *context {
             Context::Incremental(batch) | Context::FullState(batch) => batch.room_key,
@@ -272,11 +378,33 @@ impl Sync {
             Context::Initial => (false, -1),
         };
 
+        // Only incremental syncs are limited to the typing and receipt updates since the last one.
+        let ephemeral_since = match *context {
+            Context::Incremental(batch) => Some(batch.presence_key),
+            Context::FullState(_) | Context::Initial => None,
+        };
+        let mut ephemeral_key = None;
+
         let (timeline_filter, include_leave) = match room_filter {
             Some(filter) => (filter.timeline, filter.include_leave),
             None => (None, false),
         };
 
+        // Load the full state of every room that needs it up front, rather than once per room.
+        let full_state_room_ids: Vec<RoomId> = room_memberships
+            .iter()
+            .filter(
+                |room_membership| match room_membership.membership.as_str() {
+                    "join" => is_full_state,
+                    "invite" => true,
+                    _ => false,
+                },
+            )
+            .map(|room_membership| room_membership.room_id.clone())
+            .collect();
+
+        let mut room_states = RoomState::find_for_rooms(connection, &full_state_room_ids)?;
+
         for room_membership in room_memberships {
             match room_membership.membership.as_str() {
                 "join" => {
@@ -284,7 +412,9 @@ impl Sync {
                         Event::find_room_events(connection, &room_membership.room_id, since)?;
 
                     let room_state_events: Vec<Event> = if is_full_state {
-                        Event::get_room_full_state(connection, &room_membership.room_id)?
+                        room_states
+                            .remove(&room_membership.room_id)
+                            .map_or_else(Vec::new, |room_state| room_state.events)
                     } else {
                         Event::get_room_state_events_since(
                             connection,
@@ -293,19 +423,62 @@ impl Sync {
                         )?
                     };
 
-                    if events.is_empty() && room_state_events.is_empty() {
+                    let (typing_user_ids, typing_changed_at) = Typing::find_by_room(
+                        connection,
+                        &room_membership.room_id,
+                        ephemeral_since,
+                        now,
+                    )?;
+                    ephemeral_key = cmp::max(ephemeral_key, typing_changed_at);
+
+                    // Incremental syncs include the typing users whenever they changed, even if
+                    // nobody is typing anymore. Otherwise, they are only included if somebody is.
+                    let include_typing = match ephemeral_since {
+                        Some(_) => typing_changed_at.is_some(),
+                        None => !typing_user_ids.is_empty(),
+                    };
+
+                    let (receipts, receipts_changed_at) = Receipt::find_by_room(
+                        connection,
+                        &room_membership.room_id,
+                        ephemeral_since,
+                        now,
+                    )?;
+                    ephemeral_key = cmp::max(ephemeral_key, receipts_changed_at);
+
+                    if events.is_empty()
+                        && room_state_events.is_empty()
+                        && !include_typing
+                        && receipts.is_empty()
+                    {
                         continue;
                     }
 
-                    let (ordering, timeline) =
-                        Self::convert_events_to_timeline(events, &timeline_filter)?;
+                    let (ordering, timeline) = Self::convert_events_to_timeline(
+                        connection,
+                        access_token,
+                        events,
+                        &timeline_filter,
+                    )?;
                     room_ordering = cmp::max(ordering, room_ordering);
 
-                    let state_events: Vec<StateEvent> = room_state_events
-                        .iter()
-                        .cloned()
-                        .map(|e| e.try_into())
-                        .collect::<Result<Vec<StateEvent>, ApiError>>()?;
+                    let state_events = Event::into_state_events(connection, room_state_events)?;
+
+                    let mut ephemeral_events = Vec::new();
+
+                    if include_typing {
+                        ephemeral_events.push(json!({
+                            "type": EventType::Typing.to_string(),
+                            "content": { "user_ids": typing_user_ids },
+                        }));
+                    }
+
+                    if !receipts.is_empty() {
+                        ephemeral_events.push(json!({
+                            "type": EventType::Receipt.to_string(),
+                            "content": Receipt::event_content(&receipts),
+                        }));
+                    }
 
                     join.insert(
                         room_membership.room_id,
@@ -319,13 +492,29 @@ impl Sync {
                                 events: state_events,
                             },
                             account_data: Events { events: Vec::new() },
-                            ephemeral: Events { events: Vec::new() },
+                            ephemeral: Events {
+                                events: ephemeral_events,
+                            },
                         },
                     );
                 }
                 "invite" => {
-                    let room_state_events =
-                        Event::get_room_full_state(connection, &room_membership.room_id)?;
+                    let mut room_state_events = room_states
+                        .remove(&room_membership.room_id)
+                        .map_or_else(Vec::new, |room_state| room_state.events);
+
+                    // The room state only holds the latest member event of the room, which is not
+                    // necessarily the invite itself.
+                    if !room_state_events
+                        .iter()
+                        .any(|event| event.id == room_membership.event_id)
+                    {
+                        if let Some(invite_event) =
+                            Event::find(connection, &room_membership.event_id)?
+                        {
+                            room_state_events.push(invite_event);
+                        }
+                    }
 
                     let state_events: Vec<StrippedState> = room_state_events
                         .iter()
@@ -356,8 +545,12 @@ impl Sync {
                         last_event.ordering,
                     )?;
 
-                    let (ordering, timeline) =
-                        Self::convert_events_to_timeline(events, &timeline_filter)?;
+                    let (ordering, timeline) = Self::convert_events_to_timeline(
+                        connection,
+                        access_token,
+                        events,
+                        &timeline_filter,
+                    )?;
                     room_ordering = cmp::max(ordering, room_ordering);
 
                     let room_state_events = Event::get_room_state_events_until(
@@ -365,11 +558,7 @@ impl Sync {
                         &room_membership.room_id,
                         &last_event,
                     )?;
-                    let state_events: Vec<StateEvent> = room_state_events
-                        .iter()
-                        .cloned()
-                        .map(|e| e.try_into())
-                        .collect::<Result<Vec<StateEvent>, ApiError>>()?;
+                    let state_events = Event::into_state_events(connection, room_state_events)?;
 
                     leave.insert(
                         room_membership.room_id,
@@ -387,6 +576,7 @@ impl Sync {
 
         Ok((
             room_ordering,
+            ephemeral_key,
             Rooms {
                 join,
                 leave,
@@ -397,9 +587,13 @@ impl Sync {
 
     /// Converting events in the correct format for timeline.
     ///
+    /// Transaction IDs are only included for events sent with the given access token.
+    ///
     /// Also returns the max ordering from the given events that will be used
     /// as the `next_batch` token.
     fn convert_events_to_timeline(
+        connection: &PgConnection,
+        access_token: &AccessToken,
         events: Vec<Event>,
         timeline_filter: &Option<RoomEventFilter>,
     ) -> Result<(i64, Timeline), ApiError> {
@@ -424,8 +618,26 @@ impl Sync {
             },
         };
 
-        for event in events.into_iter().skip(count) {
+        let mut events: Vec<Event> = events.into_iter().skip(count).collect();
+
+        // Pagination backwards from this token continues with the events older than the first one
+        // returned. The presence key is irrelevant for room pagination. Without any omitted events
+        // there is nothing to paginate, so no token is given.
+        let prev_batch = match events.first() {
+            Some(event) if limited => Batch::new(event.ordering, 0).to_string(),
+            _ => String::new(),
+        };
+
+        let mut redactions = Event::apply_redactions(connection, &mut events)?;
+
+        for mut event in events {
             room_ordering = cmp::max(room_ordering, event.ordering);
+            event.hide_transaction_id_from_others(access_token);
+
+            if let Some(redaction) = redactions.remove(&event.id) {
+                timeline_events.push(event.into_redacted_room_event(redaction)?);
+                continue;
+            }
 
             let value = match EventType::from(event.event_type.as_ref()) {
                 EventType::CallAnswer => RoomEvent::CallAnswer(event.try_into()?),
@@ -445,6 +657,7 @@ impl Sync {
                 EventType::RoomMessage => RoomEvent::RoomMessage(event.try_into()?),
                 EventType::RoomName => RoomEvent::RoomName(event.try_into()?),
                 EventType::RoomPowerLevels => RoomEvent::RoomPowerLevels(event.try_into()?),
+                EventType::RoomRedaction => RoomEvent::RoomRedaction(event.try_into()?),
                 EventType::RoomThirdPartyInvite => {
                     RoomEvent::RoomThirdPartyInvite(event.try_into()?)
                 }
@@ -463,7 +676,7 @@ impl Sync {
             Timeline {
                 events: timeline_events,
                 limited,
-                prev_batch: String::from(""),
+                prev_batch,
             },
         ))
     }
@@ -12,6 +12,7 @@ table! {
         revoked -> Bool,
         created_at -> Timestamp,
         updated_at -> Timestamp,
+        device_id -> Nullable<Text>,
     }
 }
 
@@ -34,6 +35,10 @@ table! {
         state_key -> Nullable<Text>,
         content -> Text,
         created_at -> Timestamp,
+        redacts -> Nullable<Text>,
+        transaction_id -> Nullable<Text>,
+        access_token_id -> Nullable<BigInt>,
+        prev_content -> Nullable<Text>,
     }
 }
 
@@ -153,6 +158,25 @@ table! {
     }
 }
 
+table! {
+    receipts(room_id, user_id, receipt_type) {
+        room_id -> Text,
+        user_id -> Text,
+        receipt_type -> Text,
+        event_id -> Text,
+        updated_at -> Timestamp,
+    }
+}
+
+table! {
+    typing(room_id, user_id) {
+        room_id -> Text,
+        user_id -> Text,
+        expires_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
 // Diesel macros needed to enable queries with multiple tables involving foreign key relationships.
 
 allow_tables_to_appear_in_same_query!(events, room_memberships);
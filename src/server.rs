@@ -1,29 +1,38 @@
 //! Iron web server that serves the API.
 
 use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::time::Duration;
 
+use bodyparser::MaxBodyLength;
 use diesel::pg::PgConnection;
-use diesel::r2d2::{Builder, ConnectionManager, Pool};
+use diesel::r2d2::{ConnectionManager, Pool};
 use diesel_migrations::setup_database;
 use iron::error::HttpResult;
 use iron::{Chain, Iron, IronError, IronResult, Listening, Request, Response};
 use mount::Mount;
 use persistent::{Read, Write};
 use router::Router;
+use ruma_identifiers::RoomIdOrAliasId;
 
 use crate::api::r0::{
-    AccountPassword, CreateRoom, DeactivateAccount, DeleteRoomAlias, DeleteTag, GetAvatarUrl,
-    GetDisplayName, GetFilter, GetPresenceList, GetPresenceStatus, GetPushers, GetRoomAlias,
-    GetTags, InviteToRoom, JoinRoom, JoinRoomWithIdOrAlias, KickFromRoom, LeaveRoom, Login, Logout,
-    Members, PostFilter, PostPresenceList, Profile, PutAccountData, PutAvatarUrl, PutDisplayName,
-    PutPresenceStatus, PutRoomAccountData, PutRoomAlias, PutTag, Register, RoomState,
-    SendMessageEvent, SetPushers, StateMessageEvent, Sync, Versions,
+    AccountPassword, Capabilities, CreateRoom, DeactivateAccount, DeleteFilter, DeleteRoomAlias,
+    DeleteTag, GetAccountData, GetAvatarUrl, GetDisplayName, GetFilter, GetPresenceList,
+    GetPresenceStatus, GetPushers, GetRoomAccountData, GetRoomAlias, GetStateEvent, GetTags,
+    GetUserRooms, InviteToRoom, JoinRoom, JoinRoomWithIdOrAlias, JoinedMembers, KickFromRoom,
+    LeaveRoom, ListFilters, Login, Logout, LogoutAll, Members, PostFilter, PostPresenceList,
+    PostReceipt, Profile, PutAccountData, PutAvatarUrl, PutDisplayName, PutPresenceStatus,
+    PutRoomAccountData, PutRoomAlias, PutTag, PutTyping, RedactEvent, Register, RoomState,
+    SendMessageEvent, SetPushers, StateMessageEvent, Sync, Versions, WhoIs, Whoami,
 };
+use crate::api::ruma::{Health, PostProfiles};
+use crate::api::well_known::ClientDiscovery;
 use crate::config::Config;
 use crate::db::DB;
 use crate::embedded_migrations::run as run_pending_migrations;
 use crate::error::{ApiError, CliError};
-use crate::middleware::{MiddlewareChain, ResponseHeaders};
+use crate::middleware::{HeadRequest, MiddlewareChain, RateLimit, ResponseHeaders};
+use crate::models::room::Room;
+use crate::models::room_alias::RoomAlias;
 use crate::swagger::Swagger;
 
 /// Ruma's web server.
@@ -45,28 +54,36 @@ impl<'a> Server<'a> {
 
     /// Mount all APIs.
     pub fn mount_all(self) -> Result<Self, CliError> {
-        self.mount_extra().mount_client()
+        self.mount_extra().mount_federation().mount_client()
     }
 
     /// Mount all APIs with some extra options.
     pub fn mount_all_with_options(
         self,
-        r2d2_pool_builder: Builder<ConnectionManager<PgConnection>>,
+        connection_pool: Pool<ConnectionManager<PgConnection>>,
         set_up_db: bool,
     ) -> Result<Self, CliError> {
         self.mount_extra()
-            .mount_client_with_options(r2d2_pool_builder, set_up_db)
+            .mount_federation()
+            .mount_client_with_options(connection_pool, set_up_db)
     }
 
     /// Mount the client APIs.
     pub fn mount_client(self) -> Result<Self, CliError> {
-        self.mount_client_with_options(Pool::builder(), true)
+        debug!("Connecting to PostgreSQL.");
+        let connection_pool = DB::create_connection_pool(
+            Pool::builder(),
+            &self.config.postgres_url,
+            Duration::from_secs(self.config.postgres_connection_timeout),
+        )?;
+
+        self.mount_client_with_options(connection_pool, true)
     }
 
     /// Mount the client APIs with some extra options.
     pub fn mount_client_with_options(
         mut self,
-        r2d2_pool_builder: Builder<ConnectionManager<PgConnection>>,
+        connection_pool: Pool<ConnectionManager<PgConnection>>,
         set_up_db: bool,
     ) -> Result<Self, CliError> {
         let mut r0_router = Router::new();
@@ -81,6 +98,14 @@ impl<'a> Server<'a> {
             DeactivateAccount::chain(),
             "deactivate_account",
         );
+        r0_router.get("/account/whoami", Whoami::chain(), "whoami");
+        r0_router.get("/capabilities", Capabilities::chain(), "capabilities");
+        r0_router.get(
+            "/admin/users/:user_id/rooms",
+            GetUserRooms::chain(),
+            "get_user_rooms",
+        );
+        r0_router.get("/admin/whois/:user_id", WhoIs::chain(), "whois");
         r0_router.post("/createRoom", CreateRoom::chain(), "create_room");
         r0_router.get(
             "/directory/room/:room_alias",
@@ -99,8 +124,19 @@ impl<'a> Server<'a> {
         );
         r0_router.post("/login", Login::chain(), "login");
         r0_router.post("/logout", Logout::chain(), "logout");
+        r0_router.post("/logout/all", LogoutAll::chain(), "logout_all");
         r0_router.post("/register", Register::chain(), "register");
         r0_router.post("/tokenrefresh", deprecated, "token_refresh");
+        r0_router.get(
+            "/user/:user_id/account_data/:type",
+            GetAccountData::chain(),
+            "get_account_data",
+        );
+        r0_router.get(
+            "/user/:user_id/rooms/:room_id/account_data/:type",
+            GetRoomAccountData::chain(),
+            "get_room_account_data",
+        );
         r0_router.put(
             "/user/:user_id/account_data/:type",
             PutAccountData::chain(),
@@ -111,6 +147,11 @@ impl<'a> Server<'a> {
             PutRoomAccountData::chain(),
             "put_room_account_data",
         );
+        r0_router.put(
+            "/rooms/:room_id/redact/:event_id/:transaction_id",
+            RedactEvent::chain(),
+            "redact_event",
+        );
         r0_router.put(
             "/rooms/:room_id/send/:event_type/:transaction_id",
             SendMessageEvent::chain(),
@@ -126,7 +167,14 @@ impl<'a> Server<'a> {
             StateMessageEvent::chain(),
             "state_message_event_with_key",
         );
-        r0_router.post("/rooms/:room_id/join", JoinRoom::chain(), "join_room");
+        // Both join endpoints share one limit, so that alternating between them doesn't help.
+        let join_rate_limit = RateLimit::new(|config| config.room_join_rate_limit);
+        let mut join_room = JoinRoom::chain();
+        join_room.link_before(join_rate_limit.clone());
+        let mut join_room_with_id_or_alias = JoinRoomWithIdOrAlias::chain();
+        join_room_with_id_or_alias.link_before(join_rate_limit);
+
+        r0_router.post("/rooms/:room_id/join", join_room, "join_room");
         r0_router.post(
             "/rooms/:room_id/invite",
             InviteToRoom::chain(),
@@ -134,7 +182,7 @@ impl<'a> Server<'a> {
         );
         r0_router.post(
             "/join/:room_id_or_alias",
-            JoinRoomWithIdOrAlias::chain(),
+            join_room_with_id_or_alias,
             "join_room_with_alias",
         );
         r0_router.post(
@@ -144,11 +192,36 @@ impl<'a> Server<'a> {
         );
         r0_router.post("rooms/:room_id/leave", LeaveRoom::chain(), "leave_room");
         r0_router.get("/rooms/:room_id/members", Members::chain(), "members");
+        r0_router.get(
+            "/rooms/:room_id/joined_members",
+            JoinedMembers::chain(),
+            "joined_members",
+        );
+        r0_router.post(
+            "/rooms/:room_id/receipt/:receipt_type/:event_id",
+            PostReceipt::chain(),
+            "post_receipt",
+        );
+        r0_router.put(
+            "/rooms/:room_id/typing/:user_id",
+            PutTyping::chain(),
+            "put_typing",
+        );
         r0_router.get(
             "/rooms/:room_id/state",
             RoomState::chain(),
             "get_room_state",
         );
+        r0_router.get(
+            "/rooms/:room_id/state/:event_type",
+            GetStateEvent::chain(),
+            "get_state_event",
+        );
+        r0_router.get(
+            "/rooms/:room_id/state/:event_type/:state_key",
+            GetStateEvent::chain(),
+            "get_state_event_with_key",
+        );
         r0_router.get("/profile/:user_id", Profile::chain(), "profile");
         r0_router.get(
             "/profile/:user_id/avatar_url",
@@ -190,7 +263,17 @@ impl<'a> Server<'a> {
             GetFilter::chain(),
             "get_filter",
         );
+        r0_router.delete(
+            "/user/:user_id/filter/:filter_id",
+            DeleteFilter::chain(),
+            "delete_filter",
+        );
         r0_router.post("/user/:user_id/filter", PostFilter::chain(), "post_filter");
+        r0_router.get(
+            "/user/:user_id/filters",
+            ListFilters::chain(),
+            "list_filters",
+        );
         r0_router.get("/sync", Sync::chain(), "sync");
         r0_router.get(
             "/presence/:user_id/status",
@@ -217,9 +300,8 @@ impl<'a> Server<'a> {
 
         let mut r0 = Chain::new(r0_router);
 
-        debug!("Connecting to PostgreSQL.");
-        let connection_pool =
-            DB::create_connection_pool(r2d2_pool_builder, &self.config.postgres_url)?;
+        DB::set_log_sql(self.config.log_instrumented_sql);
+
         let connection = connection_pool.get()?;
 
         if set_up_db {
@@ -230,30 +312,115 @@ impl<'a> Server<'a> {
             run_pending_migrations(&*connection).map_err(CliError::from)?;
         }
 
+        self.warn_about_missing_auto_join_rooms(&*connection)?;
+
+        let mut ruma_router = Router::new();
+
+        ruma_router.get("/health", Health, "health");
+        ruma_router.post("/profiles", PostProfiles::chain(), "post_profiles");
+
+        let mut ruma = Chain::new(ruma_router);
+
+        ruma.link_before(Read::<Config>::one(self.config.clone()));
+        ruma.link_before(Read::<MaxBodyLength>::one(self.config.max_body_bytes));
+        ruma.link_before(Write::<DB>::one(connection_pool.clone()));
+        ruma.link_after(self.response_headers());
+
+        r0.link_before(HeadRequest);
         r0.link_before(Read::<Config>::one(self.config.clone()));
+        r0.link_before(Read::<MaxBodyLength>::one(self.config.max_body_bytes));
         r0.link_before(Write::<DB>::one(connection_pool));
-        r0.link_after(ResponseHeaders);
+        r0.link_after(self.response_headers());
+        r0.link_after(HeadRequest);
 
         let mut versions_router = Router::new();
 
-        versions_router.get("/versions", Versions::supported(), "versions");
+        versions_router.get(
+            "/versions",
+            Versions::supported(self.config.unstable_features.clone()),
+            "versions",
+        );
 
         let mut versions = Chain::new(versions_router);
-        versions.link_after(ResponseHeaders);
+        versions.link_before(HeadRequest);
+        versions.link_after(self.response_headers());
+        versions.link_after(HeadRequest);
 
+        let mut well_known_router = Router::new();
+
+        well_known_router.get("/client", ClientDiscovery, "client_discovery");
+
+        let mut well_known = Chain::new(well_known_router);
+        well_known.link_before(Read::<Config>::one(self.config.clone()));
+        well_known.link_after(self.response_headers());
+
+        self.mount.mount("/.well-known/matrix/", well_known);
         self.mount.mount("/_matrix/client/", versions);
         self.mount.mount("/_matrix/client/r0/", r0);
+        self.mount.mount("/ruma/", ruma);
 
         Ok(self)
     }
 
+    /// Mount the federation APIs.
+    ///
+    /// Ruma does not implement federation yet, so nothing is mounted while federation is enabled.
+    /// When it is disabled, the federation and key APIs explicitly respond with `M_NOT_FOUND`.
+    pub fn mount_federation(mut self) -> Self {
+        if !self.config.federation_enabled {
+            let mut key = Chain::new(federation_disabled);
+            key.link_after(self.response_headers());
+
+            let mut federation = Chain::new(federation_disabled);
+            federation.link_after(self.response_headers());
+
+            self.mount.mount("/_matrix/key/", key);
+            self.mount.mount("/_matrix/federation/", federation);
+        }
+
+        self
+    }
+
     /// Mount the extra APIs.
     pub fn mount_extra(mut self) -> Self {
-        self.mount.mount("/ruma/swagger.json", Swagger::chain());
+        let mut swagger = Chain::new(Swagger);
+        swagger.link_after(self.response_headers());
+
+        self.mount.mount("/ruma/swagger.json", swagger);
 
         self
     }
 
+    /// Log a warning for every room in `auto_join_rooms` that doesn't exist, since new users
+    /// can't be joined to it until it is created.
+    fn warn_about_missing_auto_join_rooms(
+        &self,
+        connection: &PgConnection,
+    ) -> Result<(), CliError> {
+        for room_id_or_alias in &self.config.auto_join_rooms {
+            let exists = match room_id_or_alias {
+                RoomIdOrAliasId::RoomId(room_id) => Room::find(connection, room_id)?.is_some(),
+                RoomIdOrAliasId::RoomAliasId(alias) => {
+                    RoomAlias::find(connection, alias)?.is_some()
+                }
+            };
+
+            if !exists {
+                warn!(
+                    "The room {} in auto_join_rooms doesn't exist, new users won't join it.",
+                    room_id_or_alias
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Create the `ResponseHeaders` middleware for the configured CORS origins.
+    fn response_headers(&self) -> ResponseHeaders {
+        ResponseHeaders::new(self.config.cors_allowed_origins.clone())
+    }
+
     /// Run the server and block the current thread until stopped or interrupted.
     pub fn run(self) -> HttpResult<Listening> {
         let address = format!("{}:{}", self.config.bind_address, self.config.bind_port);
@@ -287,3 +454,51 @@ fn deprecated(_: &mut Request<'_, '_>) -> IronResult<Response> {
         "tokenrefresh is no longer supported".to_string(),
     )))
 }
+
+/// A simple Iron handler used for the federation APIs when federation is disabled.
+fn federation_disabled(_: &mut Request<'_, '_>) -> IronResult<Response> {
+    Err(IronError::from(ApiError::not_found(
+        "Federation is disabled on this homeserver.".to_string(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test::Test;
+    use iron::status::Status;
+
+    #[test]
+    fn federation_api_not_implemented_with_federation_enabled() {
+        let test = Test::new();
+
+        let response = test.get("/_matrix/federation/v1/version");
+
+        assert_eq!(response.status, Status::NotFound);
+    }
+
+    #[test]
+    fn federation_api_not_found_with_federation_disabled() {
+        let test = Test::with_config(|config| config.federation_enabled = false);
+
+        let response = test.get("/_matrix/federation/v1/version");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_FOUND"
+        );
+    }
+
+    #[test]
+    fn key_api_not_found_with_federation_disabled() {
+        let test = Test::with_config(|config| config.federation_enabled = false);
+
+        let response = test.get("/_matrix/key/v2/server");
+
+        assert_eq!(response.status, Status::NotFound);
+        assert_eq!(
+            response.json().get("errcode").unwrap().as_str().unwrap(),
+            "M_NOT_FOUND"
+        );
+    }
+}
@@ -2,11 +2,9 @@
 
 use iron::headers::ContentType;
 use iron::modifiers::Header;
-use iron::{status, Chain, Handler, IronResult, Request, Response};
+use iron::{status, Handler, IronResult, Request, Response};
 
-use crate::middleware::{MiddlewareChain, ResponseHeaders};
-
-/// Mounts the Swagger endpoint onto the given `Mount`.
+/// Serves the Swagger specification of the Ruma APIs.
 #[derive(Clone, Copy, Debug)]
 pub struct Swagger;
 
@@ -21,14 +19,3 @@ impl Handler for Swagger {
         )))
     }
 }
-
-impl MiddlewareChain for Swagger {
-    /// Create a `Swagger` with all necessary middleware.
-    fn chain() -> Chain {
-        let mut chain = Chain::new(Swagger);
-
-        chain.link_after(ResponseHeaders);
-
-        chain
-    }
-}